@@ -0,0 +1,54 @@
+use std::time::Instant;
+use typed_index_collections::TiVec;
+
+use common::contraction_hierarchy::build_contraction_hierarchy;
+use common::read_file_funcs::{
+    contraction_hierarchy_filename, read_graph_walk_cycling_car_for_period, read_link_is_major_road, read_time_periods,
+    read_turn_cost_profiles, write_bincoded_file,
+};
+use common::structs::{Cost, CostUnits, NodeID, NodeWalkCyclingCar};
+use common::turn_costs::MajorRoads;
+
+// Builds a contraction hierarchy for the car graph of each time period, and for the cycling graph, using
+// each mode's profile from turn_cost_profiles.json. Run after do_serialisation, and again whenever the
+// graphs or turn cost profiles change. Pass modes to only build some, eg: `build_contraction_hierarchies car`
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let modes: Vec<String> = if args.is_empty() {
+        vec!["car".to_string(), "cycling".to_string()]
+    } else {
+        args
+    };
+
+    let time_periods = read_time_periods();
+    let turn_cost_profiles = read_turn_cost_profiles();
+
+    for mode in modes.iter() {
+        let turn_cost_profile = turn_cost_profiles.get(mode);
+        let link_is_major_road = (turn_cost_profile.major_road_crossing_seconds > Cost(0))
+            .then(|| match read_link_is_major_road(mode) {
+                Some(link_is_major_road) => link_is_major_road,
+                None => panic!("The {} turn cost profile has major_road_crossing_seconds but there are no major roads", mode),
+            });
+
+        for file_suffix in time_periods.unique_file_suffixes(mode) {
+            let now = Instant::now();
+            let graph: TiVec<NodeID, NodeWalkCyclingCar> =
+                TiVec::from(read_graph_walk_cycling_car_for_period(mode, &file_suffix));
+            let major_roads = link_is_major_road
+                .clone()
+                .map(|link_is_major_road| MajorRoads::new(link_is_major_road, &graph));
+
+            let contraction_hierarchy = build_contraction_hierarchy(&graph, &turn_cost_profile, major_roads.as_ref());
+
+            let outpath = contraction_hierarchy_filename(mode, &file_suffix);
+            write_bincoded_file(&outpath, &contraction_hierarchy, CostUnits::Seconds);
+            println!("Serialised {} in {:?}", outpath, now.elapsed());
+
+            // Only car graphs vary by time period
+            if mode != "car" {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use typed_index_collections::TiVec;
+
+use crate::structs::{
+    AccessibilityMetrics, AccessibilityMetricsRequest, Cost, NodeID, Score, SubpurposeScore,
+    SubpurposeSmallMediumLargeCount,
+};
+
+// Builds the DfT Journey Time Statistics style measures for one floodfill, one node reached at a time.
+// Opportunities are the node values (eg: jobs) without any decay applied; destinations are the small,
+// medium and large destination counts added together
+pub struct AccessibilityMetricsAccumulator<'a> {
+    request: &'a AccessibilityMetricsRequest,
+    cumulative_opportunities: Vec<Vec<Score>>,
+    opportunity_seconds_sum: Vec<f64>,
+    opportunities_sum: Vec<f64>,
+    destinations_reached: Vec<Vec<(Cost, f64)>>,  // seconds to reach and number of destinations, for each subpurpose
+}
+
+impl<'a> AccessibilityMetricsAccumulator<'a> {
+    pub fn new(request: &'a AccessibilityMetricsRequest, subpurposes_count: usize) -> Self {
+        AccessibilityMetricsAccumulator {
+            request,
+            cumulative_opportunities: vec![vec![Score(0.0); subpurposes_count]; request.thresholds.len()],
+            opportunity_seconds_sum: vec![0.0; subpurposes_count],
+            opportunities_sum: vec![0.0; subpurposes_count],
+            destinations_reached: vec![Vec::new(); subpurposes_count],
+        }
+    }
+
+    // seconds_so_far is the actual travel time: nodes needn't be reached in order of it, as PT floodfills
+    // are ordered by rail adjusted cost
+    pub fn add_node_reached(
+        &mut self,
+        node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>,
+        small_medium_large_subpurpose_destinations: &TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>>,
+        seconds_so_far: Cost,
+        node_id: NodeID,
+    ) {
+        for SubpurposeScore {
+            subpurpose_ix,
+            subpurpose_score,
+        } in node_values_2d[node_id].iter()
+        {
+            for (threshold_ix, threshold) in self.request.thresholds.iter().enumerate() {
+                if seconds_so_far <= *threshold {
+                    self.cumulative_opportunities[threshold_ix][*subpurpose_ix] += *subpurpose_score;
+                }
+            }
+            self.opportunity_seconds_sum[*subpurpose_ix] += subpurpose_score.0 * seconds_so_far.0 as f64;
+            self.opportunities_sum[*subpurpose_ix] += subpurpose_score.0;
+        }
+
+        if self.request.nearest_destinations_count > 0 {
+            for destination in small_medium_large_subpurpose_destinations[node_id].iter() {
+                let destinations_count = destination.small_destinations_count.0
+                    + destination.medium_destinations_count.0
+                    + destination.large_destinations_count.0;
+                if destinations_count > 0.0 {
+                    self.destinations_reached[destination.subpurpose_ix].push((seconds_so_far, destinations_count));
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> AccessibilityMetrics {
+        let nearest_destinations_count = self.request.nearest_destinations_count;
+
+        // Seconds at which the running total of destinations first reaches 1, 2, .. nearest_destinations_count
+        let mut seconds_to_nearest_destinations = Vec::new();
+        for destinations in self.destinations_reached.iter_mut() {
+            destinations.sort_by_key(|(seconds, _)| *seconds);
+            let mut seconds_to_nearest: Vec<Option<Cost>> = vec![None; nearest_destinations_count];
+            let mut destinations_so_far = 0.0;
+            let mut nth_destination = 0;
+            for (seconds, destinations_count) in destinations.iter() {
+                destinations_so_far += destinations_count;
+                while nth_destination < nearest_destinations_count && destinations_so_far >= (nth_destination + 1) as f64 {
+                    seconds_to_nearest[nth_destination] = Some(*seconds);
+                    nth_destination += 1;
+                }
+                if nth_destination == nearest_destinations_count {
+                    break;
+                }
+            }
+            seconds_to_nearest_destinations.push(seconds_to_nearest);
+        }
+
+        let weighted_mean_seconds = self
+            .opportunity_seconds_sum
+            .iter()
+            .zip(self.opportunities_sum.iter())
+            .map(|(opportunity_seconds, opportunities)| {
+                if *opportunities > 0.0 {
+                    Some(opportunity_seconds / opportunities)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        AccessibilityMetrics {
+            thresholds: self.request.thresholds.clone(),
+            cumulative_opportunities: self.cumulative_opportunities,
+            seconds_to_nearest_destinations,
+            weighted_mean_seconds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floodfill_walk_cycling_car::floodfill_walk_cycling_car;
+    use crate::search_workspace::SearchWorkspace;
+    use crate::structs::{
+        Angle, EdgeWalkCyclingCar, LinkID, Multiplier, NodeWalkCyclingCar, PurposeTaxonomy, SecondsPastMidnight,
+        Subpurpose, TimePeriods,
+    };
+    use crate::turn_costs::TurnCostProfile;
+    use smallvec::smallvec;
+    use std::collections::HashMap;
+
+    fn destinations(subpurpose_ix: usize, small: f64, medium: f64, large: f64) -> SubpurposeSmallMediumLargeCount {
+        SubpurposeSmallMediumLargeCount {
+            subpurpose_ix,
+            small_destinations_count: Score(small),
+            medium_destinations_count: Score(medium),
+            large_destinations_count: Score(large),
+        }
+    }
+
+    fn opportunities(subpurpose_ix: usize, opportunities: f64) -> SubpurposeScore {
+        SubpurposeScore { subpurpose_ix, subpurpose_score: Score(opportunities) }
+    }
+
+    #[test]
+    fn thresholds_and_nearest_destinations_on_a_small_graph() {
+        // A street 0-1-2-3, with nodes reached at 0, 100, 300 and 600 seconds walking from node 0
+        let edge = |to: usize, cost: usize, link: u32| EdgeWalkCyclingCar {
+            to: NodeID(to),
+            cost: Cost(cost),
+            angle_leaving_node_from: Angle(90),
+            angle_arrived_from: Angle(90),
+            link_arrived_from: LinkID(link),
+            distance_metres: None,
+        };
+        let graph: TiVec<NodeID, NodeWalkCyclingCar> = vec![
+            NodeWalkCyclingCar { edges: smallvec![edge(1, 100, 0)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(2, 200, 1)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(3, 300, 2)] },
+            NodeWalkCyclingCar { edges: smallvec![] },
+        ]
+        .into();
+        let node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>> =
+            vec![vec![], vec![opportunities(0, 5.0)], vec![opportunities(1, 2.0)], vec![opportunities(0, 10.0)]].into();
+        let small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>> = vec![
+            vec![],
+            vec![destinations(0, 1.0, 0.0, 0.0)],
+            vec![destinations(0, 0.0, 2.0, 0.0)],
+            vec![destinations(0, 0.0, 0.0, 1.0)],
+        ]
+        .into();
+        let purpose_taxonomy = PurposeTaxonomy {
+            version: 1,
+            purposes: vec!["Business".to_string()],
+            subpurposes: vec![
+                Subpurpose { name: "Jobs".to_string(), purpose_ix: 0 },
+                Subpurpose { name: "Offices".to_string(), purpose_ix: 0 },
+            ],
+            score_multipliers: HashMap::from([("walk".to_string(), vec![Multiplier(1.0), Multiplier(1.0)])]),
+            size_bands: vec!["small".to_string(), "medium".to_string(), "large".to_string()],
+            subpurpose_purpose_lookup: vec![0, 0],
+        };
+        // 100 seconds is on a threshold, so node 1 is within it
+        let request = AccessibilityMetricsRequest { thresholds: vec![Cost(100), Cost(400)], nearest_destinations_count: 5 };
+
+        let floodfill_output = floodfill_walk_cycling_car(
+            &[Multiplier(1.0)],
+            &purpose_taxonomy,
+            &node_values_2d,
+            &graph,
+            &mut SearchWorkspace::new(graph.len()),
+            &TurnCostProfile::make_default_instance("walk"),
+            None,
+            NodeID(0),
+            Cost(0),
+            &[],
+            Cost(3600),
+            "walk",
+            false,
+            0,
+            NodeID(graph.len()),
+            &vec![false; graph.len()].into(),
+            &small_medium_large_subpurpose_destinations,
+            false,
+            &vec![],
+            Some(&request),
+            false,
+            &[],
+            false,
+            false,
+            None,
+            SecondsPastMidnight(28800),
+            &TimePeriods::make_default_instance(),
+            "weekday",
+        );
+        let metrics = floodfill_output.accessibility_metrics.unwrap();
+
+        assert_eq!(metrics.thresholds, vec![Cost(100), Cost(400)]);
+        assert_eq!(metrics.cumulative_opportunities, vec![vec![Score(5.0), Score(0.0)], vec![Score(5.0), Score(2.0)]]);
+        // Node 2's two destinations are the 2nd and 3rd nearest; only 4 are ever reached
+        assert_eq!(
+            metrics.seconds_to_nearest_destinations,
+            vec![
+                vec![Some(Cost(100)), Some(Cost(300)), Some(Cost(300)), Some(Cost(600)), None],
+                vec![None; 5],
+            ]
+        );
+        assert_eq!(metrics.weighted_mean_seconds[0], Some((5.0 * 100.0 + 10.0 * 600.0) / 15.0));
+        assert_eq!(metrics.weighted_mean_seconds[1], Some(300.0));
+    }
+
+    #[test]
+    fn nodes_can_be_reached_out_of_order() {
+        // As in PT floodfills, which are ordered by rail adjusted cost rather than seconds
+        let node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>> = vec![vec![opportunities(0, 1.0)]; 3].into();
+        let small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>> =
+            vec![vec![destinations(0, 0.5, 0.0, 0.0)]; 3].into();
+        let request = AccessibilityMetricsRequest { thresholds: vec![Cost(250)], nearest_destinations_count: 1 };
+
+        let mut accumulator = AccessibilityMetricsAccumulator::new(&request, 1);
+        for (seconds, node) in [(300, 0), (100, 1), (200, 2)] {
+            accumulator.add_node_reached(&node_values_2d, &small_medium_large_subpurpose_destinations, Cost(seconds), NodeID(node));
+        }
+        let metrics = accumulator.finish();
+
+        assert_eq!(metrics.cumulative_opportunities, vec![vec![Score(2.0)]]);
+        // Half a destination at each node, so the first whole one is reached at the second nearest node
+        assert_eq!(metrics.seconds_to_nearest_destinations, vec![vec![Some(Cost(200))]]);
+        assert_eq!(metrics.weighted_mean_seconds, vec![Some(200.0)]);
+    }
+}
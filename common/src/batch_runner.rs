@@ -0,0 +1,206 @@
+use actix_web::body::MessageBody;
+use actix_web::HttpResponse;
+use fs_err::File;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::time::Instant;
+
+pub const DEFAULT_REQUESTS_PER_SHARD: usize = 100;
+const RUN_SETTINGS_FILENAME: &str = "run_settings.json";
+
+// Written to the output directory by the first run, so a resumed run can check it's carrying on the same work
+#[derive(Serialize, Deserialize)]
+struct ShardedRunSettings {
+    requests_path: String,
+    requests_count: usize,
+    requests_per_shard: usize,
+}
+
+// The body of a response built for an endpoint, eg its JSON
+pub fn response_body(response: HttpResponse) -> String {
+    let bytes = response.into_body().try_into_bytes().unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+// Panics in one request, eg from a node that isn't in the graph, become its error rather than ending the run
+pub fn run_catching_panics<T>(run: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    match catch_unwind(AssertUnwindSafe(run)) {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = if let Some(message) = panic.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = panic.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            Err(format!("Panicked: {}", message))
+        }
+    }
+}
+
+// Reads `<requests.jsonl> <output_dir> [requests_per_shard]` from the command line arguments after
+// `run_requests`, and runs the requests
+pub fn run_requests_command(args: &[String], run_request: impl Fn(&str) -> Result<String, String>) {
+    if args.len() < 2 {
+        panic!("Usage: run_requests <requests.jsonl> <output_dir> [requests_per_shard]");
+    }
+    let requests_per_shard = match args.get(2) {
+        Some(requests_per_shard) => requests_per_shard.parse().unwrap(),
+        None => DEFAULT_REQUESTS_PER_SHARD,
+    };
+    run_requests_file(&args[0], &args[1], requests_per_shard, run_request);
+}
+
+pub fn shard_path(output_dir: &str, shard_ix: usize) -> String {
+    format!("{}/shard_{:05}.jsonl", output_dir, shard_ix)
+}
+
+// Runs each request in a JSONL file, one request per line, through run_request, which returns the JSON
+// of the result. Results are written in order to shards of requests_per_shard lines, each line
+// {"request_ix": 0, "result": ...} or {"request_ix": 0, "error": "..."}: failed requests don't stop the
+// run. Shards are written under a temporary name and renamed once complete, so any shard that exists is
+// finished, and rerunning with the same arguments carries on from the shards that don't
+pub fn run_requests_file(
+    requests_path: &str,
+    output_dir: &str,
+    requests_per_shard: usize,
+    run_request: impl Fn(&str) -> Result<String, String>,
+) {
+    assert!(requests_per_shard > 0, "requests_per_shard must be at least 1");
+    fs_err::create_dir_all(output_dir).unwrap();
+
+    let read_request_lines = || {
+        BufReader::new(File::open(requests_path).unwrap())
+            .lines()
+            .map(|line| line.unwrap())
+            .filter(|line| !line.trim().is_empty())
+    };
+    let requests_count = read_request_lines().count();
+    check_or_write_run_settings(
+        output_dir,
+        ShardedRunSettings {
+            requests_path: requests_path.to_string(),
+            requests_count,
+            requests_per_shard,
+        },
+    );
+
+    let shards_count = requests_count.div_ceil(requests_per_shard);
+    let mut request_lines = read_request_lines();
+    for shard_ix in 0..shards_count {
+        let shard_lines: Vec<String> = request_lines.by_ref().take(requests_per_shard).collect();
+        let path = shard_path(output_dir, shard_ix);
+        if Path::new(&path).exists() {
+            println!("Shard {} of {} already done", shard_ix + 1, shards_count);
+            continue;
+        }
+
+        let now = Instant::now();
+        let partial_path = format!("{}.partial", path);
+        let mut file = BufWriter::new(File::create(&partial_path).unwrap());
+        for (line_ix, line) in shard_lines.iter().enumerate() {
+            let request_ix = shard_ix * requests_per_shard + line_ix;
+            match run_catching_panics(|| run_request(line)) {
+                Ok(result) => writeln!(file, "{{\"request_ix\":{},\"result\":{}}}", request_ix, result).unwrap(),
+                Err(message) => {
+                    println!("Request {} failed: {}", request_ix, message);
+                    let message = serde_json::to_string(&message).unwrap();
+                    writeln!(file, "{{\"request_ix\":{},\"error\":{}}}", request_ix, message).unwrap();
+                }
+            }
+        }
+        file.into_inner().unwrap().sync_all().unwrap();
+        fs_err::rename(&partial_path, &path).unwrap();
+        println!("Shard {} of {} done in {:?}", shard_ix + 1, shards_count, now.elapsed());
+    }
+}
+
+// Shards are only meaningful for the requests and shard size they were written with
+fn check_or_write_run_settings(output_dir: &str, settings: ShardedRunSettings) {
+    let path = format!("{}/{}", output_dir, RUN_SETTINGS_FILENAME);
+    if Path::new(&path).exists() {
+        let previous: ShardedRunSettings = serde_json::from_reader(BufReader::new(File::open(&path).unwrap())).unwrap();
+        if previous.requests_count != settings.requests_count || previous.requests_per_shard != settings.requests_per_shard {
+            panic!(
+                "{} holds a run of {} requests from {} in shards of {}: resume with the same requests and shard size, or use another output directory",
+                output_dir, previous.requests_count, previous.requests_path, previous.requests_per_shard
+            );
+        }
+        println!("Resuming run in {}", output_dir);
+    } else {
+        serde_json::to_writer_pretty(BufWriter::new(File::create(&path).unwrap()), &settings).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // A fresh directory under the system temp directory, holding requests.jsonl with these lines
+    fn test_dir_with_requests(name: &str, request_lines: &[&str]) -> String {
+        let dir = std::env::temp_dir().join(format!("batch_runner_{}_{}", name, std::process::id()));
+        let _ = fs_err::remove_dir_all(&dir);
+        fs_err::create_dir_all(&dir).unwrap();
+        fs_err::write(dir.join("requests.jsonl"), request_lines.join("\n")).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn resumed_runs_skip_finished_shards() {
+        let dir = test_dir_with_requests("resume", &["1", "2", "", "fail", "4", "5"]);
+        let requests_path = format!("{}/requests.jsonl", dir);
+        let output_dir = format!("{}/output", dir);
+        let run_request = |line: &str| match line {
+            "fail" => Err("bad request".to_string()),
+            _ => Ok(format!("[{}]", line)),
+        };
+
+        run_requests_file(&requests_path, &output_dir, 2, run_request);
+        let shards: Vec<String> = (0..3).map(|shard_ix| fs_err::read_to_string(shard_path(&output_dir, shard_ix)).unwrap()).collect();
+        assert_eq!(shards[0], "{\"request_ix\":0,\"result\":[1]}\n{\"request_ix\":1,\"result\":[2]}\n");
+        assert_eq!(shards[1], "{\"request_ix\":2,\"error\":\"bad request\"}\n{\"request_ix\":3,\"result\":[4]}\n");
+        assert_eq!(shards[2], "{\"request_ix\":4,\"result\":[5]}\n");
+        assert!(!Path::new(&shard_path(&output_dir, 3)).exists());
+
+        // As if the run had stopped during the second shard
+        fs_err::remove_file(shard_path(&output_dir, 1)).unwrap();
+        let requests_run = RefCell::new(Vec::new());
+        run_requests_file(&requests_path, &output_dir, 2, |line| {
+            requests_run.borrow_mut().push(line.to_string());
+            run_request(line)
+        });
+        assert_eq!(requests_run.into_inner(), vec!["fail", "4"]);
+        assert_eq!(fs_err::read_to_string(shard_path(&output_dir, 1)).unwrap(), shards[1]);
+
+        fs_err::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn panicking_requests_become_errors() {
+        let dir = test_dir_with_requests("panics", &["1", "panic"]);
+        let output_dir = format!("{}/output", dir);
+        run_requests_file(&format!("{}/requests.jsonl", dir), &output_dir, 10, |line| match line {
+            "panic" => panic!("node 99 isn't in the graph"),
+            _ => Ok(line.to_string()),
+        });
+        assert_eq!(
+            fs_err::read_to_string(shard_path(&output_dir, 0)).unwrap(),
+            "{\"request_ix\":0,\"result\":1}\n{\"request_ix\":1,\"error\":\"Panicked: node 99 isn't in the graph\"}\n"
+        );
+        fs_err::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "resume with the same requests and shard size")]
+    fn resuming_with_another_shard_size_panics() {
+        let dir = test_dir_with_requests("mismatch", &["1", "2", "3"]);
+        let requests_path = format!("{}/requests.jsonl", dir);
+        let output_dir = format!("{}/output", dir);
+        run_requests_file(&requests_path, &output_dir, 2, |line| Ok(line.to_string()));
+        run_requests_file(&requests_path, &output_dir, 3, |line| Ok(line.to_string()));
+    }
+}
@@ -0,0 +1,361 @@
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use arrow::array::{ArrayRef, Float64Array, ListBuilder, StringArray, StringBuilder, UInt32Array, UInt32Builder, UInt8Array};
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::od_matrix::OdMatrixOutput;
+use crate::structs::{
+    Cost, FloodfillOutputOriginDestinationPair, FloodfillOutputOriginDestinationPairWalkCyclingCar, NodeID,
+    PlanningToolOutput, Score, ZoneScores,
+};
+
+pub const ARROW_IPC_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+pub const PARQUET_CONTENT_TYPE: &str = "application/vnd.apache.parquet";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResponseFormat {
+    Json,
+    ArrowIpc,
+    Parquet,
+}
+
+impl ResponseFormat {
+    // The first media type in the Accept header that we can send, ignoring q values. JSON if there's no
+    // Accept header or nothing in it matches, as before
+    pub fn from_request(request: &HttpRequest) -> ResponseFormat {
+        let accept = match request.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok()) {
+            Some(accept) => accept,
+            None => return ResponseFormat::Json,
+        };
+        for media_type in accept.split(',') {
+            match media_type.split(';').next().unwrap().trim() {
+                ARROW_IPC_CONTENT_TYPE => return ResponseFormat::ArrowIpc,
+                PARQUET_CONTENT_TYPE | "application/x-parquet" => return ResponseFormat::Parquet,
+                "application/json" => return ResponseFormat::Json,
+                _ => {}
+            }
+        }
+        ResponseFormat::Json
+    }
+
+    pub fn is_columnar(&self) -> bool {
+        *self != ResponseFormat::Json
+    }
+}
+
+// JSON, and error messages in any format, go back as the plain text endpoints have always returned
+pub fn text_response(body: String) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(body)
+}
+
+// Sends value as JSON, or as the table to_record_batch makes from it in a columnar format
+pub fn formatted_response<T: Serialize>(
+    value: &T,
+    format: ResponseFormat,
+    to_record_batch: impl FnOnce(&T) -> RecordBatch,
+) -> HttpResponse {
+    if format.is_columnar() {
+        columnar_response(to_record_batch(value), format)
+    } else {
+        text_response(serde_json::to_string(value).unwrap())
+    }
+}
+
+pub fn columnar_response(record_batch: RecordBatch, format: ResponseFormat) -> HttpResponse {
+    let mut buffer: Vec<u8> = Vec::new();
+    match format {
+        ResponseFormat::ArrowIpc => {
+            let mut writer = StreamWriter::try_new(&mut buffer, &record_batch.schema()).unwrap();
+            writer.write(&record_batch).unwrap();
+            writer.finish().unwrap();
+        }
+        ResponseFormat::Parquet => {
+            let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+            let mut writer = ArrowWriter::try_new(&mut buffer, record_batch.schema(), Some(properties)).unwrap();
+            writer.write(&record_batch).unwrap();
+            writer.close().unwrap();
+        }
+        ResponseFormat::Json => panic!("JSON responses aren't columnar"),
+    }
+    let content_type = if format == ResponseFormat::ArrowIpc { ARROW_IPC_CONTENT_TYPE } else { PARQUET_CONTENT_TYPE };
+    HttpResponse::Ok().content_type(content_type).body(buffer)
+}
+
+// The parts of a floodfill output that go in the columnar results: fields not here are only in JSON
+pub struct FloodfillResultRow<'a> {
+    pub start_node_id: NodeID,
+    pub seconds_walk_to_start_node: Cost,
+    pub purpose_scores: &'a [Score],
+    pub competition_adjusted_purpose_scores: Option<&'a [Score]>,
+    pub od_pairs_found: &'a [[usize; 2]],   // [cost, node]
+}
+
+impl<'a> From<&'a FloodfillOutputOriginDestinationPair> for FloodfillResultRow<'a> {
+    fn from(result: &'a FloodfillOutputOriginDestinationPair) -> Self {
+        FloodfillResultRow {
+            start_node_id: result.start_node_id,
+            seconds_walk_to_start_node: result.seconds_walk_to_start_node,
+            purpose_scores: &result.purpose_scores,
+            competition_adjusted_purpose_scores: result.competition_adjusted_scores.as_ref().map(|scores| scores.purpose_scores.as_slice()),
+            od_pairs_found: &result.od_pairs_found,
+        }
+    }
+}
+
+impl<'a> From<&'a FloodfillOutputOriginDestinationPairWalkCyclingCar> for FloodfillResultRow<'a> {
+    fn from(result: &'a FloodfillOutputOriginDestinationPairWalkCyclingCar) -> Self {
+        FloodfillResultRow {
+            start_node_id: result.start_node_id,
+            seconds_walk_to_start_node: result.seconds_walk_to_start_node,
+            purpose_scores: &result.purpose_scores,
+            competition_adjusted_purpose_scores: result.competition_adjusted_scores.as_ref().map(|scores| scores.purpose_scores.as_slice()),
+            od_pairs_found: &result.od_pairs_found,
+        }
+    }
+}
+
+fn u32_column(values: impl Iterator<Item = usize>) -> ArrayRef {
+    Arc::new(UInt32Array::from_iter_values(values.map(|value| value as u32)))
+}
+
+// One Float64 column per purpose, named by the purpose taxonomy. The suffix tells apart sets of scores
+// in the same table
+fn purpose_score_columns<'a>(
+    purposes: &[String],
+    suffix: &str,
+    purpose_scores_by_row: impl Iterator<Item = &'a [Score]> + Clone,
+) -> Vec<(String, ArrayRef)> {
+    purposes
+        .iter()
+        .enumerate()
+        .map(|(purpose_ix, purpose)| {
+            let column: ArrayRef = Arc::new(Float64Array::from_iter_values(
+                purpose_scores_by_row.clone().map(|purpose_scores| purpose_scores[purpose_ix].0),
+            ));
+            (format!("{purpose}{suffix}"), column)
+        })
+        .collect()
+}
+
+// One row per start node, in request order. The OD pairs found are in list columns destination_nodes
+// and costs, which line up element by element
+pub fn floodfill_results_record_batch(rows: &[FloodfillResultRow], purposes: &[String]) -> RecordBatch {
+    let mut destination_nodes = ListBuilder::new(UInt32Builder::new());
+    let mut costs = ListBuilder::new(UInt32Builder::new());
+    for row in rows.iter() {
+        for [cost, node] in row.od_pairs_found.iter() {
+            destination_nodes.values().append_value(*node as u32);
+            costs.values().append_value(*cost as u32);
+        }
+        destination_nodes.append(true);
+        costs.append(true);
+    }
+
+    let mut columns: Vec<(String, ArrayRef)> = vec![
+        ("origin_ix".to_string(), u32_column(0..rows.len())),
+        ("start_node".to_string(), u32_column(rows.iter().map(|row| row.start_node_id.0))),
+        ("seconds_walk_to_start_node".to_string(), u32_column(rows.iter().map(|row| row.seconds_walk_to_start_node.0))),
+    ];
+    columns.extend(purpose_score_columns(purposes, "", rows.iter().map(|row| row.purpose_scores)));
+    if rows.iter().all(|row| row.competition_adjusted_purpose_scores.is_some()) && !rows.is_empty() {
+        columns.extend(purpose_score_columns(
+            purposes,
+            "_competition_adjusted",
+            rows.iter().map(|row| row.competition_adjusted_purpose_scores.unwrap()),
+        ));
+    }
+    columns.push(("destination_nodes".to_string(), Arc::new(destination_nodes.finish())));
+    columns.push(("costs".to_string(), Arc::new(costs.finish())));
+    RecordBatch::try_from_iter(columns).unwrap()
+}
+
+// One row per zone, in request order
+pub fn zone_scores_record_batch(zone_scores: &[ZoneScores], purposes: &[String]) -> RecordBatch {
+    let mut columns: Vec<(String, ArrayRef)> = vec![
+        (
+            "zone_id".to_string(),
+            Arc::new(StringArray::from_iter_values(zone_scores.iter().map(|zone| zone.zone_id.as_str()))),
+        ),
+        (
+            "total_weight".to_string(),
+            Arc::new(Float64Array::from_iter_values(zone_scores.iter().map(|zone| zone.total_weight))),
+        ),
+    ];
+    columns.extend(purpose_score_columns(purposes, "", zone_scores.iter().map(|zone| zone.purpose_scores.as_slice())));
+    if zone_scores.iter().all(|zone| zone.competition_adjusted_purpose_scores.is_some()) && !zone_scores.is_empty() {
+        columns.extend(purpose_score_columns(
+            purposes,
+            "_competition_adjusted",
+            zone_scores.iter().map(|zone| zone.competition_adjusted_purpose_scores.as_deref().unwrap()),
+        ));
+    }
+    RecordBatch::try_from_iter(columns).unwrap()
+}
+
+// One row per OD pair: every pair for a dense matrix, with null costs where unreachable, or only the
+// reachable pairs for a sparse one
+pub fn od_matrix_record_batch(od_matrix: &OdMatrixOutput) -> RecordBatch {
+    let mut origin_ixs: Vec<usize> = Vec::new();
+    let mut destination_ixs: Vec<usize> = Vec::new();
+    let mut costs: Vec<Option<u32>> = Vec::new();
+    if let Some(matrix) = &od_matrix.matrix {
+        for (origin_ix, row) in matrix.iter().enumerate() {
+            for (destination_ix, cost) in row.iter().enumerate() {
+                origin_ixs.push(origin_ix);
+                destination_ixs.push(destination_ix);
+                costs.push(cost.map(|cost| cost.0 as u32));
+            }
+        }
+    }
+    if let Some(entries) = &od_matrix.entries {
+        for [origin_ix, destination_ix, cost] in entries.iter() {
+            origin_ixs.push(*origin_ix);
+            destination_ixs.push(*destination_ix);
+            costs.push(Some(*cost as u32));
+        }
+    }
+
+    // cost is nullable even when every pair was reached, so the schema is the same for every response
+    let columns: Vec<(&str, ArrayRef, bool)> = vec![
+        ("origin_ix", u32_column(origin_ixs.iter().copied()), false),
+        ("start_node", u32_column(origin_ixs.iter().map(|origin_ix| od_matrix.origins[*origin_ix].0)), false),
+        ("destination_ix", u32_column(destination_ixs.iter().copied()), false),
+        (
+            "destination_node",
+            u32_column(destination_ixs.iter().map(|destination_ix| od_matrix.destinations[*destination_ix].0)),
+            false,
+        ),
+        ("cost", Arc::new(UInt32Array::from(costs)), true),
+    ];
+    RecordBatch::try_from_iter_with_nullable(columns).unwrap()
+}
+
+// One row per link. The fields about the whole floodfill go in the schema metadata, as JSON
+pub fn planning_tool_record_batch(output: &PlanningToolOutput, purposes: &[String]) -> RecordBatch {
+    let mut link_coordinates = ListBuilder::new(StringBuilder::new());
+    for coordinates in output.link_coordinates.iter() {
+        for coordinate in coordinates.iter() {
+            link_coordinates.values().append_value(coordinate);
+        }
+        link_coordinates.append(true);
+    }
+
+    let mut columns: Vec<(String, ArrayRef)> = vec![
+        ("link_ix".to_string(), u32_column(0..output.link_is_pt.len())),
+        ("link_coordinates".to_string(), Arc::new(link_coordinates.finish())),
+        ("link_is_pt".to_string(), Arc::new(UInt8Array::from(output.link_is_pt.clone()))),
+        (
+            "link_route_details".to_string(),
+            Arc::new(StringArray::from_iter_values(
+                output.link_route_details.iter().map(|route_details| serde_json::to_string(route_details).unwrap()),
+            )),
+        ),
+    ];
+    columns.extend(purpose_score_columns(
+        purposes,
+        "",
+        output.per_link_score_per_purpose.iter().map(|scores| scores.as_slice()),
+    ));
+    let record_batch = RecordBatch::try_from_iter(columns).unwrap();
+
+    let metadata: HashMap<String, String> = HashMap::from([
+        ("start_node".to_string(), output.start_node.0.to_string()),
+        ("init_travel_time".to_string(), output.init_travel_time.0.to_string()),
+        ("num_iterations".to_string(), output.num_iterations.to_string()),
+        (
+            "key_destinations_per_purpose".to_string(),
+            serde_json::to_string(&output.key_destinations_per_purpose).unwrap(),
+        ),
+    ]);
+    let schema = record_batch.schema().as_ref().clone().with_metadata(metadata);
+    record_batch.with_schema(Arc::new(schema)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use arrow::array::{Array, ListArray};
+    use arrow::ipc::reader::StreamReader;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    fn read_back(record_batch: &RecordBatch, format: ResponseFormat) -> RecordBatch {
+        let body = columnar_response(record_batch.clone(), format).into_body().try_into_bytes().unwrap();
+        let mut record_batches: Vec<RecordBatch> = match format {
+            ResponseFormat::ArrowIpc => StreamReader::try_new(body.as_ref(), None).unwrap().map(|batch| batch.unwrap()).collect(),
+            ResponseFormat::Parquet => ParquetRecordBatchReaderBuilder::try_new(body)
+                .unwrap()
+                .build()
+                .unwrap()
+                .map(|batch| batch.unwrap())
+                .collect(),
+            ResponseFormat::Json => unreachable!(),
+        };
+        assert_eq!(record_batches.len(), 1);
+        record_batches.remove(0)
+    }
+
+    fn u32_values(record_batch: &RecordBatch, name: &str) -> Vec<u32> {
+        let column = record_batch.column_by_name(name).unwrap();
+        column.as_any().downcast_ref::<UInt32Array>().unwrap().values().to_vec()
+    }
+
+    fn u32_lists(record_batch: &RecordBatch, name: &str) -> Vec<Vec<u32>> {
+        let column = record_batch.column_by_name(name).unwrap();
+        let lists = column.as_any().downcast_ref::<ListArray>().unwrap();
+        (0..lists.len())
+            .map(|ix| lists.value(ix).as_any().downcast_ref::<UInt32Array>().unwrap().values().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn floodfill_results_round_trip() {
+        let purposes = vec!["Business".to_string(), "Shopping".to_string()];
+        let scores = [[Score(1.5), Score(0.0)], [Score(2.25), Score(3.0)], [Score(0.0), Score(0.0)]];
+        let competition_adjusted_scores = [[Score(0.5), Score(0.0)], [Score(0.75), Score(1.0)], [Score(0.0), Score(0.0)]];
+        let od_pairs_found: [Vec<[usize; 2]>; 3] = [vec![[120, 7], [300, 9]], vec![], vec![[0, 4]]];
+        let rows: Vec<FloodfillResultRow> = (0..3)
+            .map(|ix| FloodfillResultRow {
+                start_node_id: NodeID(ix * 100 + 4),
+                seconds_walk_to_start_node: Cost(ix * 10),
+                purpose_scores: &scores[ix],
+                competition_adjusted_purpose_scores: Some(&competition_adjusted_scores[ix]),
+                od_pairs_found: &od_pairs_found[ix],
+            })
+            .collect();
+        let record_batch = floodfill_results_record_batch(&rows, &purposes);
+
+        let column_names: Vec<String> = record_batch.schema().fields().iter().map(|field| field.name().clone()).collect();
+        assert_eq!(
+            column_names,
+            vec![
+                "origin_ix", "start_node", "seconds_walk_to_start_node", "Business", "Shopping",
+                "Business_competition_adjusted", "Shopping_competition_adjusted", "destination_nodes", "costs",
+            ]
+        );
+
+        for format in [ResponseFormat::ArrowIpc, ResponseFormat::Parquet] {
+            let read = read_back(&record_batch, format);
+            assert_eq!(read, record_batch, "{:?}", format);
+            assert_eq!(u32_values(&read, "origin_ix"), vec![0, 1, 2]);
+            assert_eq!(u32_values(&read, "start_node"), vec![4, 104, 204]);
+            assert_eq!(u32_values(&read, "seconds_walk_to_start_node"), vec![0, 10, 20]);
+            let shopping = read.column_by_name("Shopping_competition_adjusted").unwrap();
+            assert_eq!(shopping.as_any().downcast_ref::<Float64Array>().unwrap().values().to_vec(), vec![0.0, 1.0, 0.0]);
+            assert_eq!(u32_lists(&read, "destination_nodes"), vec![vec![7, 9], vec![], vec![4]]);
+            assert_eq!(u32_lists(&read, "costs"), vec![vec![120, 300], vec![], vec![0]]);
+        }
+
+        // Without competition for every row, there are no competition adjusted columns
+        let mut rows = rows;
+        rows[1].competition_adjusted_purpose_scores = None;
+        assert_eq!(floodfill_results_record_batch(&rows, &purposes).num_columns(), 7);
+    }
+}
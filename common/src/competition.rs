@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use typed_index_collections::TiVec;
+
+use crate::floodfill_funcs::get_travel_time_multiplier;
+use crate::structs::{
+    CompetitionAdjustedScores, Multiplier, NodeID, PurposeTaxonomy, Score, SubpurposeScore, ZoneStartNode,
+};
+
+// Two step floating catchment (2SFCA). With a cumulative_cutoff decay function this is the classic
+// version; any other decay function gives enhanced 2SFCA.
+// Step 1: demand at each destination is the population of every origin which reaches it, weighted by
+// the decay function of the travel time. Step 2: each origin's score is the sum of supply / demand of
+// every destination it reaches, weighted in the same way.
+// Both steps use OD pairs from floodfills with the destinations set to nodes_with_opportunities, with the
+// same seconds the floodfill's scores are weighted by: on PT, that's od_pairs_found_rail_adjusted
+
+pub fn nodes_with_opportunities(node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>) -> Vec<NodeID> {
+    node_values_2d
+        .iter_enumerated()
+        .filter(|(_, subpurpose_scores)| !subpurpose_scores.is_empty())
+        .map(|(node_id, _)| node_id)
+        .collect()
+}
+
+// Step 1 for one origin: demand by purpose at each destination node it reaches
+pub fn demand_from_one_origin(
+    od_pairs_found: &[[usize; 2]],
+    population: f64,
+    travel_time_relationships: &[Multiplier],
+    purposes_count: usize,
+) -> HashMap<NodeID, Vec<f64>> {
+    let seconds_per_purpose = travel_time_relationships.len() / purposes_count;
+    let mut demand: HashMap<NodeID, Vec<f64>> = HashMap::new();
+    for [seconds, node] in od_pairs_found.iter() {
+        let demand_this_node = demand.entry(NodeID(*node)).or_insert_with(|| vec![0.0; purposes_count]);
+        for (purpose_ix, purpose_demand) in demand_this_node.iter_mut().enumerate() {
+            *purpose_demand += population * get_travel_time_multiplier(travel_time_relationships, seconds_per_purpose, purpose_ix, *seconds).0;
+        }
+    }
+    demand
+}
+
+// To sum the demand from each origin, eg in a rayon reduce()
+pub fn merge_demand(
+    mut demand: HashMap<NodeID, Vec<f64>>,
+    other_demand: HashMap<NodeID, Vec<f64>>,
+) -> HashMap<NodeID, Vec<f64>> {
+    for (node, other_demand_this_node) in other_demand {
+        match demand.get_mut(&node) {
+            Some(demand_this_node) => {
+                for (purpose_demand, other_purpose_demand) in demand_this_node.iter_mut().zip(other_demand_this_node) {
+                    *purpose_demand += other_purpose_demand;
+                }
+            }
+            None => {
+                demand.insert(node, other_demand_this_node);
+            }
+        }
+    }
+    demand
+}
+
+// Step 2 for one origin
+pub fn calculate_competition_adjusted_scores(
+    od_pairs_found: &[[usize; 2]],
+    demand: &HashMap<NodeID, Vec<f64>>,
+    node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>,
+    travel_time_relationships: &[Multiplier],
+    purpose_taxonomy: &PurposeTaxonomy,
+) -> CompetitionAdjustedScores {
+    let seconds_per_purpose = travel_time_relationships.len() / purpose_taxonomy.purposes_count();
+    let mut subpurpose_scores = vec![Score(0.0); purpose_taxonomy.subpurposes_count()];
+    for [seconds, node] in od_pairs_found.iter() {
+        let node = NodeID(*node);
+        let demand_this_node = match demand.get(&node) {
+            Some(demand_this_node) => demand_this_node,
+            None => continue,
+        };
+        for SubpurposeScore {
+            subpurpose_ix,
+            subpurpose_score,
+        } in node_values_2d[node].iter()
+        {
+            let purpose_ix = purpose_taxonomy.subpurpose_purpose_lookup[*subpurpose_ix];
+            // zero demand means zero population reached this destination: skip it rather than divide by zero
+            if demand_this_node[purpose_ix] > 0.0 {
+                let supply_to_demand_ratio = subpurpose_score.0 / demand_this_node[purpose_ix];
+                subpurpose_scores[*subpurpose_ix] += Score(
+                    supply_to_demand_ratio * get_travel_time_multiplier(travel_time_relationships, seconds_per_purpose, purpose_ix, *seconds).0,
+                );
+            }
+        }
+    }
+
+    let mut purpose_scores = vec![Score(0.0); purpose_taxonomy.purposes_count()];
+    for (subpurpose_ix, subpurpose_score) in subpurpose_scores.iter().enumerate() {
+        purpose_scores[purpose_taxonomy.subpurpose_purpose_lookup[subpurpose_ix]] += *subpurpose_score;
+    }
+    CompetitionAdjustedScores {
+        subpurpose_scores,
+        purpose_scores,
+    }
+}
+
+// populations are given per start node, or per zone if zone_ids are given. Each zone's population is
+// then split between its start nodes in proportion to their weights
+pub fn populations_by_start_node(
+    populations: &[f64],
+    start_nodes_count: usize,
+    zone_ids: &[String],
+    zone_start_nodes: &[(usize, ZoneStartNode)],
+) -> Result<Vec<f64>, String> {
+    if zone_ids.is_empty() {
+        if populations.len() != start_nodes_count {
+            return Err(format!(
+                "{} populations given for {} start nodes",
+                populations.len(),
+                start_nodes_count
+            ));
+        }
+        return Ok(populations.to_vec());
+    }
+
+    if populations.len() != zone_ids.len() {
+        return Err(format!("{} populations given for {} zones", populations.len(), zone_ids.len()));
+    }
+    let mut zone_total_weights = vec![0.0; zone_ids.len()];
+    for (zone_ix, start_node) in zone_start_nodes.iter() {
+        zone_total_weights[*zone_ix] += start_node.weight;
+    }
+    Ok(zone_start_nodes
+        .iter()
+        .map(|(zone_ix, start_node)| {
+            if zone_total_weights[*zone_ix] > 0.0 {
+                populations[*zone_ix] * start_node.weight / zone_total_weights[*zone_ix]
+            } else {
+                0.0
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{Cost, Subpurpose};
+
+    fn one_purpose_taxonomy() -> PurposeTaxonomy {
+        PurposeTaxonomy {
+            version: 1,
+            purposes: vec!["Education".to_string()],
+            subpurposes: vec![Subpurpose {
+                name: "Primary".to_string(),
+                purpose_ix: 0,
+            }],
+            score_multipliers: HashMap::new(),
+            size_bands: vec!["small".to_string(), "medium".to_string(), "large".to_string()],
+            subpurpose_purpose_lookup: vec![0],
+        }
+    }
+
+    #[test]
+    fn two_pass_ratio_weights_supply_by_decayed_demand() {
+        let purpose_taxonomy = one_purpose_taxonomy();
+        // Full weight up to 5 seconds, then half
+        let travel_time_relationships: Vec<Multiplier> =
+            (0..=10).map(|seconds| Multiplier(if seconds <= 5 { 1.0 } else { 0.5 })).collect();
+        // Node 1 has 10 places; nodes 0 and 2 have none
+        let node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>> = TiVec::from(vec![
+            vec![],
+            vec![SubpurposeScore {
+                subpurpose_ix: 0,
+                subpurpose_score: Score(10.0),
+            }],
+            vec![],
+        ]);
+        assert_eq!(nodes_with_opportunities(&node_values_2d), vec![NodeID(1)]);
+
+        // Origin A, population 100, reaches node 1 in 2 seconds; origin B, population 300, in 8
+        let od_pairs_a = [[2, 1]];
+        let od_pairs_b = [[8, 1]];
+        let demand = merge_demand(
+            demand_from_one_origin(&od_pairs_a, 100.0, &travel_time_relationships, 1),
+            demand_from_one_origin(&od_pairs_b, 300.0, &travel_time_relationships, 1),
+        );
+        // 100 * 1 + 300 * 0.5
+        assert_eq!(demand[&NodeID(1)], vec![250.0]);
+
+        let scores_a = calculate_competition_adjusted_scores(
+            &od_pairs_a,
+            &demand,
+            &node_values_2d,
+            &travel_time_relationships,
+            &purpose_taxonomy,
+        );
+        let scores_b = calculate_competition_adjusted_scores(
+            &od_pairs_b,
+            &demand,
+            &node_values_2d,
+            &travel_time_relationships,
+            &purpose_taxonomy,
+        );
+        assert!((scores_a.purpose_scores[0].0 - 10.0 / 250.0).abs() < 1e-12);
+        assert!((scores_b.purpose_scores[0].0 - 10.0 / 250.0 * 0.5).abs() < 1e-12);
+        // Every place is shared out between the population: 100 * 0.04 + 300 * 0.02 = 10
+        let places_allocated = 100.0 * scores_a.purpose_scores[0].0 + 300.0 * scores_b.purpose_scores[0].0;
+        assert!((places_allocated - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn zone_populations_are_split_by_start_node_weight() {
+        let start_node = |weight: f64| ZoneStartNode { node: NodeID(0), seconds_walk_to_start_node: Cost(0), weight };
+        let zone_ids = vec!["E01000001".to_string(), "E01000002".to_string(), "E01000003".to_string()];
+        // Zone 0 has start nodes weighted 1:3, zone 1 one start node, and zone 2 only a weightless one
+        let zone_start_nodes = [(0, start_node(1.0)), (0, start_node(3.0)), (1, start_node(0.2)), (2, start_node(0.0))];
+        assert_eq!(
+            populations_by_start_node(&[400.0, 50.0, 70.0], 4, &zone_ids, &zone_start_nodes),
+            Ok(vec![100.0, 300.0, 50.0, 0.0])
+        );
+        assert_eq!(
+            populations_by_start_node(&[400.0, 50.0], 4, &zone_ids, &zone_start_nodes),
+            Err("2 populations given for 3 zones".to_string())
+        );
+
+        assert_eq!(populations_by_start_node(&[1.0, 2.0], 2, &[], &[]), Ok(vec![1.0, 2.0]));
+        assert_eq!(
+            populations_by_start_node(&[1.0, 2.0], 3, &[], &[]),
+            Err("2 populations given for 3 start nodes".to_string())
+        );
+    }
+}
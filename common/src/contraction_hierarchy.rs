@@ -0,0 +1,490 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use typed_index_collections::TiVec;
+
+use crate::structs::{Angle, Cost, EdgeWalkCyclingCar, LinkID, NodeID, NodeWalkCyclingCar, START_LINK_ID};
+use crate::turn_costs::{MajorRoads, TurnCostProfile};
+
+// Witness searches give up after settling this many states, and add a shortcut to be safe. Estimating
+// priorities only needs a rough count of shortcuts, so can stop sooner
+const WITNESS_SEARCH_SETTLED_LIMIT: usize = 1000;
+const PRIORITY_WITNESS_SEARCH_SETTLED_LIMIT: usize = 100;
+
+// Use with `BinaryHeap`. Since it's a max-heap, reverse the comparison to get the smallest key first
+#[derive(PartialEq, Eq)]
+struct QueueItem<K> {
+    key: K,
+    state: u32,
+}
+
+impl<K: Ord> PartialOrd for QueueItem<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> Ord for QueueItem<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ord = other.key.cmp(&self.key);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        self.state.cmp(&other.state)
+    }
+}
+
+// Contraction hierarchy over the directed edges of a walk, cycling or car graph, rather than its nodes,
+// so turn costs are exact: each state is one edge, and moving from one state to the next costs the turn
+// between them plus the next edge. States are numbered in the order of the graph's nodes, then edges.
+// Built for one turn cost profile: requests with another can't use it
+#[derive(Serialize, Deserialize)]
+pub struct ContractionHierarchy {
+    pub turn_cost_profile: TurnCostProfile,
+    pub nodes_count: usize,
+    node_first_state: Vec<u32>,   // states leaving node n are node_first_state[n]..node_first_state[n + 1]
+    state_start_cost: Vec<Cost>,   // cost of a state when a route starts on it: the turn from Angle(0) plus the edge
+    node_first_arriving: Vec<u32>,   // indexes arriving_states in the same way, for states ending at each node
+    arriving_states: Vec<u32>,
+    // Edges to higher ranked states, as compressed rows: those leaving state s are
+    // forward_targets[forward_first[s]..forward_first[s + 1]]
+    forward_first: Vec<u32>,
+    forward_targets: Vec<u32>,
+    forward_costs: Vec<Cost>,
+    // Edges from higher ranked states, for searching backwards from the destination
+    backward_first: Vec<u32>,
+    backward_sources: Vec<u32>,
+    backward_costs: Vec<Cost>,
+}
+
+// The state graph while it's being contracted. Only edges between uncontracted states are kept
+struct ContractionGraph {
+    out_edges: Vec<Vec<(u32, Cost)>>,
+    in_edges: Vec<Vec<(u32, Cost)>>,
+}
+
+impl ContractionGraph {
+    // Keeps the cheaper of parallel edges
+    fn add_edge(&mut self, from: u32, to: u32, cost: Cost) {
+        match self.out_edges[from as usize].iter_mut().find(|(target, _)| *target == to) {
+            Some(edge) => {
+                if cost >= edge.1 {
+                    return;
+                }
+                edge.1 = cost;
+                let in_edge = self.in_edges[to as usize].iter_mut().find(|(source, _)| *source == from).unwrap();
+                in_edge.1 = cost;
+            }
+            None => {
+                self.out_edges[from as usize].push((to, cost));
+                self.in_edges[to as usize].push((from, cost));
+            }
+        }
+    }
+
+    // Dijkstra from start which skips the state being contracted, stopping beyond max_cost
+    fn witness_search(&self, start: u32, skip: u32, max_cost: Cost, settled_limit: usize) -> HashMap<u32, Cost> {
+        let mut costs: HashMap<u32, Cost> = HashMap::new();
+        let mut settled: HashMap<u32, Cost> = HashMap::new();
+        let mut queue: BinaryHeap<QueueItem<Cost>> = BinaryHeap::new();
+        costs.insert(start, Cost(0));
+        queue.push(QueueItem { key: Cost(0), state: start });
+
+        while let Some(current) = queue.pop() {
+            if settled.contains_key(&current.state) {
+                continue;
+            }
+            if current.key > max_cost || settled.len() >= settled_limit {
+                break;
+            }
+            settled.insert(current.state, current.key);
+            for (target, edge_cost) in self.out_edges[current.state as usize].iter() {
+                if *target == skip {
+                    continue;
+                }
+                let new_cost = current.key + *edge_cost;
+                if costs.get(target).is_none_or(|cost| new_cost < *cost) {
+                    costs.insert(*target, new_cost);
+                    queue.push(QueueItem { key: new_cost, state: *target });
+                }
+            }
+        }
+        settled
+    }
+
+    // Shortcuts needed to contract state, where no witness path is at least as cheap
+    fn find_shortcuts(&self, state: u32, settled_limit: usize) -> Vec<(u32, u32, Cost)> {
+        let mut shortcuts = Vec::new();
+        let max_out_cost = match self.out_edges[state as usize].iter().map(|(_, cost)| *cost).max() {
+            Some(max_out_cost) => max_out_cost,
+            None => return shortcuts,
+        };
+        for (source, in_cost) in self.in_edges[state as usize].iter() {
+            let witness_costs = self.witness_search(*source, state, *in_cost + max_out_cost, settled_limit);
+            for (target, out_cost) in self.out_edges[state as usize].iter() {
+                if target == source {
+                    continue;
+                }
+                let via_cost = *in_cost + *out_cost;
+                if witness_costs.get(target).is_none_or(|witness_cost| *witness_cost > via_cost) {
+                    shortcuts.push((*source, *target, via_cost));
+                }
+            }
+        }
+        shortcuts
+    }
+
+    // Edge difference, plus the number of neighbours already contracted to spread contraction evenly
+    fn priority(&self, state: u32, contracted_neighbours: u32) -> i64 {
+        let shortcuts_count = self.find_shortcuts(state, PRIORITY_WITNESS_SEARCH_SETTLED_LIMIT).len() as i64;
+        let edges_removed = (self.out_edges[state as usize].len() + self.in_edges[state as usize].len()) as i64;
+        shortcuts_count - edges_removed + contracted_neighbours as i64
+    }
+}
+
+// Rows of a compressed adjacency list, from one Vec of edges per state
+fn to_compressed_rows(edges_by_state: Vec<Vec<(u32, Cost)>>) -> (Vec<u32>, Vec<u32>, Vec<Cost>) {
+    let mut first = Vec::with_capacity(edges_by_state.len() + 1);
+    let mut states = Vec::new();
+    let mut costs = Vec::new();
+    for edges in edges_by_state {
+        first.push(states.len() as u32);
+        for (state, cost) in edges {
+            states.push(state);
+            costs.push(cost);
+        }
+    }
+    first.push(states.len() as u32);
+    (first, states, costs)
+}
+
+pub fn build_contraction_hierarchy(
+    graph: &TiVec<NodeID, NodeWalkCyclingCar>,
+    turn_cost_profile: &TurnCostProfile,
+    major_roads: Option<&MajorRoads>,   // only needed if the profile has a major road crossing penalty
+) -> ContractionHierarchy {
+    let turn_seconds = |angle_arrived_from: Angle, link_arrived_from: LinkID, node: NodeID, edge: &EdgeWalkCyclingCar| {
+        let mut seconds = turn_cost_profile.cost_of_turn(edge.angle_leaving_node_from, angle_arrived_from);
+        if let Some(major_roads) = major_roads {
+            if major_roads.is_crossing(link_arrived_from, node, edge.link_arrived_from) {
+                seconds = seconds + turn_cost_profile.major_road_crossing_seconds;
+            }
+        }
+        seconds
+    };
+
+    // One state per directed edge
+    let mut node_first_state: Vec<u32> = Vec::with_capacity(graph.len() + 1);
+    let mut state_start_cost: Vec<Cost> = Vec::new();
+    let mut states_arriving_at_node: TiVec<NodeID, Vec<u32>> = vec![Vec::new(); graph.len()].into();
+    for (node_id, node) in graph.iter_enumerated() {
+        node_first_state.push(state_start_cost.len() as u32);
+        for edge in node.edges.iter() {
+            states_arriving_at_node[edge.to].push(state_start_cost.len() as u32);
+            state_start_cost.push(turn_seconds(Angle(0), START_LINK_ID, node_id, edge) + edge.cost);
+        }
+    }
+    node_first_state.push(state_start_cost.len() as u32);
+    let states_count = state_start_cost.len();
+    println!("{} nodes and {} states to contract", graph.len(), states_count);
+
+    let mut contraction_graph = ContractionGraph {
+        out_edges: vec![Vec::new(); states_count],
+        in_edges: vec![Vec::new(); states_count],
+    };
+    for (node_id, node) in graph.iter_enumerated() {
+        for (edge_ix, edge) in node.edges.iter().enumerate() {
+            let state = node_first_state[node_id.0] + edge_ix as u32;
+            let next_node_first_state = node_first_state[edge.to.0];
+            for (next_edge_ix, next_edge) in graph[edge.to].edges.iter().enumerate() {
+                let next_state = next_node_first_state + next_edge_ix as u32;
+                let cost = turn_seconds(edge.angle_arrived_from, edge.link_arrived_from, edge.to, next_edge) + next_edge.cost;
+                contraction_graph.add_edge(state, next_state, cost);
+            }
+        }
+    }
+
+    let mut contracted_neighbours = vec![0u32; states_count];
+    let mut queue: BinaryHeap<QueueItem<i64>> = (0..states_count as u32)
+        .into_par_iter()
+        .map(|state| QueueItem {
+            key: contraction_graph.priority(state, 0),
+            state,
+        })
+        .collect::<Vec<_>>()
+        .into();
+
+    let mut forward_edges: Vec<Vec<(u32, Cost)>> = vec![Vec::new(); states_count];
+    let mut backward_edges: Vec<Vec<(u32, Cost)>> = vec![Vec::new(); states_count];
+    let mut contracted_count = 0;
+
+    while let Some(current) = queue.pop() {
+        let state = current.state;
+
+        // Priorities go stale as neighbours are contracted: only contract if it's still the lowest
+        let priority = contraction_graph.priority(state, contracted_neighbours[state as usize]);
+        if let Some(next) = queue.peek() {
+            if priority > next.key {
+                queue.push(QueueItem { key: priority, state });
+                continue;
+            }
+        }
+
+        let shortcuts = contraction_graph.find_shortcuts(state, WITNESS_SEARCH_SETTLED_LIMIT);
+
+        // Every remaining neighbour is contracted later, so ranks above this state
+        let out_edges = std::mem::take(&mut contraction_graph.out_edges[state as usize]);
+        let in_edges = std::mem::take(&mut contraction_graph.in_edges[state as usize]);
+        for (target, _) in out_edges.iter() {
+            contraction_graph.in_edges[*target as usize].retain(|(source, _)| *source != state);
+            contracted_neighbours[*target as usize] += 1;
+        }
+        for (source, _) in in_edges.iter() {
+            contraction_graph.out_edges[*source as usize].retain(|(target, _)| *target != state);
+            contracted_neighbours[*source as usize] += 1;
+        }
+        forward_edges[state as usize] = out_edges;
+        backward_edges[state as usize] = in_edges;
+
+        for (source, target, cost) in shortcuts {
+            contraction_graph.add_edge(source, target, cost);
+        }
+
+        contracted_count += 1;
+        if contracted_count % 1_000_000 == 0 {
+            println!("Contracted {} of {} states", contracted_count, states_count);
+        }
+    }
+
+    let mut node_first_arriving: Vec<u32> = Vec::with_capacity(graph.len() + 1);
+    let mut arriving_states: Vec<u32> = Vec::new();
+    for states in states_arriving_at_node.into_iter() {
+        node_first_arriving.push(arriving_states.len() as u32);
+        arriving_states.extend(states);
+    }
+    node_first_arriving.push(arriving_states.len() as u32);
+
+    let (forward_first, forward_targets, forward_costs) = to_compressed_rows(forward_edges);
+    let (backward_first, backward_sources, backward_costs) = to_compressed_rows(backward_edges);
+    println!(
+        "Contraction hierarchy has {} edges up and {} down, from {} states",
+        forward_targets.len(),
+        backward_sources.len(),
+        states_count
+    );
+
+    ContractionHierarchy {
+        turn_cost_profile: turn_cost_profile.clone(),
+        nodes_count: graph.len(),
+        node_first_state,
+        state_start_cost,
+        node_first_arriving,
+        arriving_states,
+        forward_first,
+        forward_targets,
+        forward_costs,
+        backward_first,
+        backward_sources,
+        backward_costs,
+    }
+}
+
+impl ContractionHierarchy {
+    // Dijkstra up the hierarchy from the given states, stopping beyond max_cost
+    fn upward_search(
+        &self,
+        start_states: impl Iterator<Item = (u32, Cost)>,
+        first: &[u32],
+        neighbours: &[u32],
+        costs: &[Cost],
+        max_cost: Cost,
+    ) -> HashMap<u32, Cost> {
+        let mut best_costs: HashMap<u32, Cost> = HashMap::new();
+        let mut settled: HashMap<u32, Cost> = HashMap::new();
+        let mut queue: BinaryHeap<QueueItem<Cost>> = BinaryHeap::new();
+        for (state, cost) in start_states {
+            if best_costs.get(&state).is_none_or(|best_cost| cost < *best_cost) {
+                best_costs.insert(state, cost);
+                queue.push(QueueItem { key: cost, state });
+            }
+        }
+
+        while let Some(current) = queue.pop() {
+            if current.key > max_cost {
+                break;
+            }
+            if settled.contains_key(&current.state) {
+                continue;
+            }
+            settled.insert(current.state, current.key);
+            let edges = first[current.state as usize] as usize..first[current.state as usize + 1] as usize;
+            for (neighbour, edge_cost) in neighbours[edges.clone()].iter().zip(costs[edges].iter()) {
+                let new_cost = current.key + *edge_cost;
+                if best_costs.get(neighbour).is_none_or(|best_cost| new_cost < *best_cost) {
+                    best_costs.insert(*neighbour, new_cost);
+                    queue.push(QueueItem { key: new_cost, state: *neighbour });
+                }
+            }
+        }
+        settled
+    }
+
+    fn forward_search(&self, origin: NodeID, max_cost: Cost) -> HashMap<u32, Cost> {
+        let states = self.node_first_state[origin.0]..self.node_first_state[origin.0 + 1];
+        self.upward_search(
+            states.map(|state| (state, self.state_start_cost[state as usize])),
+            &self.forward_first,
+            &self.forward_targets,
+            &self.forward_costs,
+            max_cost,
+        )
+    }
+
+    fn backward_search(&self, destination: NodeID, max_cost: Cost) -> HashMap<u32, Cost> {
+        let states = self.node_first_arriving[destination.0] as usize..self.node_first_arriving[destination.0 + 1] as usize;
+        self.upward_search(
+            self.arriving_states[states].iter().map(|state| (*state, Cost(0))),
+            &self.backward_first,
+            &self.backward_sources,
+            &self.backward_costs,
+            max_cost,
+        )
+    }
+
+    // Seconds from each origin to each destination, indexed [origin][destination], or None where it's
+    // max_cost or more, as in the floodfill. Backward searches from every destination fill buckets at the states they settle;
+    // each forward search then only has to scan the buckets of the states it settles
+    pub fn many_to_many(&self, origins: &[NodeID], destinations: &[NodeID], max_cost: Cost) -> Vec<Vec<Option<Cost>>> {
+        let backward_search_results: Vec<HashMap<u32, Cost>> = destinations
+            .par_iter()
+            .map(|destination| self.backward_search(*destination, max_cost))
+            .collect();
+        let mut buckets: HashMap<u32, Vec<(usize, Cost)>> = HashMap::new();
+        for (destination_ix, settled) in backward_search_results.into_iter().enumerate() {
+            for (state, cost) in settled {
+                buckets.entry(state).or_default().push((destination_ix, cost));
+            }
+        }
+
+        origins
+            .par_iter()
+            .map(|origin| {
+                let mut row: Vec<Option<Cost>> = vec![None; destinations.len()];
+                for (destination_ix, destination) in destinations.iter().enumerate() {
+                    if destination == origin {
+                        row[destination_ix] = Some(Cost(0));
+                    }
+                }
+                for (state, forward_cost) in self.forward_search(*origin, max_cost) {
+                    if let Some(bucket) = buckets.get(&state) {
+                        for (destination_ix, backward_cost) in bucket.iter() {
+                            let cost = forward_cost + *backward_cost;
+                            if cost < max_cost && row[*destination_ix].is_none_or(|best_cost| cost < best_cost) {
+                                row[*destination_ix] = Some(cost);
+                            }
+                        }
+                    }
+                }
+                row
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::floodfill_walk_cycling_car::floodfill_walk_cycling_car;
+    use crate::search_workspace::SearchWorkspace;
+    use crate::structs::{Multiplier, PurposeTaxonomy, SecondsPastMidnight, Subpurpose, TimePeriods};
+    use smallvec::smallvec;
+
+    fn edge(to: usize, cost: usize, bearing: u16, link: u32) -> EdgeWalkCyclingCar {
+        EdgeWalkCyclingCar {
+            to: NodeID(to),
+            cost: Cost(cost),
+            angle_leaving_node_from: Angle(bearing),
+            angle_arrived_from: Angle(bearing),
+            link_arrived_from: LinkID(link),
+            distance_metres: Some(Cost(cost)),
+        }
+    }
+
+    #[test]
+    fn many_to_many_matches_floodfill() {
+        // A minor road 0-1-2 crosses a major road 3-1-4 at node 1, with a detour round it by 0-3-5-2 and a
+        // dead end at 6. Routes start as if arriving heading north, so heading south from 1 is a u-turn
+        let graph: TiVec<NodeID, NodeWalkCyclingCar> = vec![
+            NodeWalkCyclingCar { edges: smallvec![edge(1, 60, 90, 0), edge(3, 100, 45, 1)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(0, 60, 270, 2), edge(2, 60, 90, 3), edge(3, 40, 0, 4), edge(4, 40, 180, 5)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(1, 60, 270, 6), edge(5, 50, 0, 7), edge(6, 30, 90, 8)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(0, 100, 225, 9), edge(1, 40, 180, 10), edge(5, 60, 90, 11)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(1, 40, 0, 12)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(3, 60, 270, 13), edge(2, 50, 180, 14)] },
+            NodeWalkCyclingCar { edges: smallvec![edge(2, 30, 270, 15)] },
+        ]
+        .into();
+        let mut link_is_major_road = vec![false; 16];
+        for link in [4, 5, 10, 12] {
+            link_is_major_road[link] = true;
+        }
+        let major_roads = MajorRoads::new(link_is_major_road, &graph);
+        let mut turn_cost_profile = TurnCostProfile::make_default_instance("car");
+        turn_cost_profile.major_road_crossing_seconds = Cost(30);
+
+        let contraction_hierarchy = build_contraction_hierarchy(&graph, &turn_cost_profile, Some(&major_roads));
+        let nodes: Vec<NodeID> = (0..graph.len()).map(NodeID).collect();
+        let time_limit = Cost(3600);
+        let matrix = contraction_hierarchy.many_to_many(&nodes, &nodes, time_limit);
+
+        let purpose_taxonomy = PurposeTaxonomy {
+            version: 1,
+            purposes: vec!["Shopping".to_string()],
+            subpurposes: vec![Subpurpose { name: "Supermarket".to_string(), purpose_ix: 0 }],
+            score_multipliers: HashMap::from([("car".to_string(), vec![Multiplier(1.0)])]),
+            size_bands: vec!["small".to_string(), "medium".to_string(), "large".to_string()],
+            subpurpose_purpose_lookup: vec![0],
+        };
+        let mut workspace = SearchWorkspace::new(graph.len());
+        for origin in nodes.iter() {
+            let floodfill_output = floodfill_walk_cycling_car(
+                &vec![Multiplier(1.0); time_limit.0],
+                &purpose_taxonomy,
+                &vec![vec![]; graph.len()].into(),
+                &graph,
+                &mut workspace,
+                &turn_cost_profile,
+                Some(&major_roads),
+                *origin,
+                Cost(0),
+                &nodes,
+                time_limit,
+                "car",
+                false,
+                0,
+                NodeID(graph.len()),
+                &vec![false; graph.len()].into(),
+                &vec![vec![]; graph.len()].into(),
+                false,
+                &vec![],
+                None,
+                false,
+                &[],
+                false,
+                false,
+                None,
+                SecondsPastMidnight(28800),
+                &TimePeriods::make_default_instance(),
+                "weekday",
+            );
+            let mut floodfill_row: Vec<Option<Cost>> = vec![None; graph.len()];
+            for [cost, node] in floodfill_output.od_pairs_found {
+                floodfill_row[node] = Some(Cost(cost));
+            }
+            assert_eq!(matrix[origin.0], floodfill_row, "from node {}", origin.0);
+        }
+        // Straight over the major road, with the crossing penalty, beats the detour
+        assert_eq!(matrix[0][2], Some(Cost(165)));
+    }
+}
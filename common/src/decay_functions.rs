@@ -0,0 +1,113 @@
+use serde::Deserialize;
+
+use crate::structs::{Cost, Multiplier, MAX_TRAVEL_TIME_SECONDS};
+
+// How much a destination counts towards scores, given the seconds taken to reach it. LookupTable uses
+// the travel_time_relationships files; the others are parametric forms, so alternative impedance
+// assumptions can be tested without producing new data files
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DecayFunction {
+    LookupTable,
+    NegativeExponential { beta: f64 },                         // exp(-beta * seconds)
+    Logistic { midpoint_seconds: f64, steepness: f64 },        // 1 / (1 + exp(steepness * (seconds - midpoint_seconds)))
+    CumulativeCutoff { cutoff_seconds: usize },                // 1 up to and including cutoff_seconds, then 0
+    Gaussian { sigma_seconds: f64 },                           // exp(-seconds^2 / (2 * sigma_seconds^2))
+}
+
+impl DecayFunction {
+    // Parameters that would make multipliers NaN, or grow with travel time, are rejected
+    fn validate(&self) -> Result<(), String> {
+        let (parameters, is_valid): (Vec<f64>, bool) = match self {
+            DecayFunction::LookupTable | DecayFunction::CumulativeCutoff { .. } => return Ok(()),
+            DecayFunction::NegativeExponential { beta } => (vec![*beta], *beta >= 0.0),
+            DecayFunction::Logistic {
+                midpoint_seconds,
+                steepness,
+            } => (vec![*midpoint_seconds, *steepness], *steepness >= 0.0),
+            DecayFunction::Gaussian { sigma_seconds } => (vec![*sigma_seconds], *sigma_seconds > 0.0),
+        };
+        if !parameters.iter().all(|parameter| parameter.is_finite()) {
+            return Err(format!("Decay function {:?} has a parameter that isn't a finite number", self));
+        }
+        if !is_valid {
+            return Err(format!(
+                "Decay function {:?} doesn't decay: beta and steepness can't be negative, and sigma_seconds must be above 0",
+                self
+            ));
+        }
+        Ok(())
+    }
+
+    fn multiplier(&self, seconds: usize) -> Option<Multiplier> {
+        let seconds_f64 = seconds as f64;
+        match self {
+            DecayFunction::LookupTable => None,
+            DecayFunction::NegativeExponential { beta } => Some(Multiplier((-beta * seconds_f64).exp())),
+            DecayFunction::Logistic {
+                midpoint_seconds,
+                steepness,
+            } => Some(Multiplier(
+                1.0 / (1.0 + (steepness * (seconds_f64 - midpoint_seconds)).exp()),
+            )),
+            DecayFunction::CumulativeCutoff { cutoff_seconds } => {
+                Some(Multiplier(if seconds <= *cutoff_seconds { 1.0 } else { 0.0 }))
+            }
+            DecayFunction::Gaussian { sigma_seconds } => Some(Multiplier(
+                (-seconds_f64.powi(2) / (2.0 * sigma_seconds.powi(2))).exp(),
+            )),
+        }
+    }
+}
+
+// Makes a table laid out like travel_time_relationships (one block of multipliers per purpose, indexed
+// by seconds), with one multiplier for every second up to max_travel_time_seconds. Each purpose's
+// block is taken from its decay function; lookup tables (which are 3601 long, for 0 to 3600 seconds)
+// are extended with their last multiplier. The floodfills then read it exactly as they would a
+// travel_time_relationships file.
+// decay_functions is either empty, to use travel_time_relationships for every purpose, or has one per purpose
+pub fn apply_decay_functions(
+    decay_functions: &[DecayFunction],
+    travel_time_relationships: &[Multiplier],
+    purposes_count: usize,
+    max_travel_time_seconds: Cost,
+) -> Result<Vec<Multiplier>, String> {
+    if !decay_functions.is_empty() && decay_functions.len() != purposes_count {
+        return Err(format!(
+            "{} decay functions given but there are {} purposes",
+            decay_functions.len(),
+            purposes_count
+        ));
+    }
+    for decay_function in decay_functions.iter() {
+        decay_function.validate()?;
+    }
+    if max_travel_time_seconds > MAX_TRAVEL_TIME_SECONDS {
+        return Err(format!(
+            "max_travel_time_seconds is {} but can be at most {}",
+            max_travel_time_seconds.0, MAX_TRAVEL_TIME_SECONDS.0
+        ));
+    }
+
+    let table_seconds_per_purpose = travel_time_relationships.len() / purposes_count;
+    let seconds_per_purpose = max_travel_time_seconds.0 + 1;
+    if decay_functions.is_empty() && seconds_per_purpose == table_seconds_per_purpose {
+        return Ok(travel_time_relationships.to_vec());
+    }
+
+    let mut decayed_travel_time_relationships = Vec::with_capacity(seconds_per_purpose * purposes_count);
+    for purpose_ix in 0..purposes_count {
+        let decay_function = decay_functions.get(purpose_ix).unwrap_or(&DecayFunction::LookupTable);
+        let vec_start_pos_this_purpose = purpose_ix * table_seconds_per_purpose;
+        for seconds in 0..seconds_per_purpose {
+            let multiplier = match decay_function.multiplier(seconds) {
+                Some(multiplier) => multiplier,
+                None => {
+                    travel_time_relationships[vec_start_pos_this_purpose + seconds.min(table_seconds_per_purpose - 1)]
+                }
+            };
+            decayed_travel_time_relationships.push(multiplier);
+        }
+    }
+    Ok(decayed_travel_time_relationships)
+}
@@ -1,78 +1,126 @@
 use typed_index_collections::TiVec;
 
-use crate::read_file_funcs::{read_vec_as_array_multiplier, read_vec_as_array_usize};
-
 use crate::structs::{
-    Cost, DestinationReached, Multiplier, NodeID,Angle,
-    Score, SecondsPastMidnight, SubpurposeScore, PURPOSES_COUNT, SUBPURPOSES_COUNT,
+    Cost, DestinationReached, DestinationCountsAtTimeInterval, LinkCostProfiles, LinkID, Multiplier, NodeID, TimePeriods,
+    Score, SecondsPastMidnight, SubpurposeScore, SubpurposeSmallMediumLargeCount,
 };
 
-pub fn initialise_subpurpose_purpose_lookup() -> [usize; SUBPURPOSES_COUNT] {
-    
-    let subpurpose_purpose_lookup: [usize; SUBPURPOSES_COUNT] =
-        read_vec_as_array_usize("subpurpose_to_purpose_integer");
-    
-    return subpurpose_purpose_lookup;
+// The seconds to cross a link when entering it at seconds_past_midnight: the cost switches to the
+// next period's as the clock passes into it. So that entering later never arrives earlier (eg: just
+// before a slow peak ends), which would break the floodfill, arrival is capped at the arrival from
+// waiting for the start of any later period of the day type
+pub fn get_link_cost_at_time(
+        link_cost_profiles: &LinkCostProfiles,
+        time_periods: &TimePeriods,
+        day_type: &str,
+        link: LinkID,
+        seconds_past_midnight: SecondsPastMidnight,
+    ) -> Cost {
+    let period_ix = time_periods.period_index(day_type, seconds_past_midnight).unwrap();
+    let mut seconds = link_cost_profiles.seconds(link, period_ix) as usize;
+    for (later_period_ix, period) in time_periods.periods.iter().enumerate() {
+        if period.day_type == day_type && period.start_seconds > seconds_past_midnight {
+            let seconds_waiting = period.start_seconds.0 - seconds_past_midnight.0;
+            seconds = seconds.min(seconds_waiting + link_cost_profiles.seconds(link, later_period_ix) as usize);
+        }
+    }
+    Cost(seconds)
 }
 
-
-// update to accept mode name
-pub fn initialise_score_multiplers(mode: &str) -> [Multiplier; SUBPURPOSES_COUNT] {
-    
-    let contents_filename = format!("score_multipliers_{}", mode);
-        
-    let multipliers_this_mode: [Multiplier; SUBPURPOSES_COUNT] = read_vec_as_array_multiplier(&contents_filename);
-        //deserialize_bincoded_file(&contents_filename);
-    
-    return multipliers_this_mode;
+// travel_time_relationships has one block of seconds_per_purpose multipliers per purpose, indexed by
+// seconds. Beyond the end of a block the last multiplier is used
+pub fn get_travel_time_multiplier(
+        travel_time_relationships: &[Multiplier],
+        seconds_per_purpose: usize,
+        purpose_ix: usize,
+        seconds: usize,
+    ) -> Multiplier {
+    travel_time_relationships[purpose_ix * seconds_per_purpose + seconds.min(seconds_per_purpose - 1)]
 }
 
-
-
-pub fn get_time_of_day_index(trip_start_seconds: SecondsPastMidnight) -> usize {
-    let mut time_of_day_ix = 0;
-    if trip_start_seconds > SecondsPastMidnight(3600 * 10) {
-        time_of_day_ix = 1;
+// Time bands are found by the first upper bound a node's seconds are within, so the bounds must go up
+pub fn validate_score_time_bands(score_time_bands: &[Cost]) -> Result<(), String> {
+    if score_time_bands.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(format!(
+            "score_time_bands must be strictly increasing, but are {:?}",
+            score_time_bands.iter().map(|upper_bound| upper_bound.0).collect::<Vec<_>>()
+        ));
     }
-    if trip_start_seconds > SecondsPastMidnight(3600 * 16) {
-        time_of_day_ix = 2;
-    }
-    if trip_start_seconds > SecondsPastMidnight(3600 * 19) {
-        time_of_day_ix = 3;
-    }
-    time_of_day_ix as usize
+    Ok(())
 }
 
-pub fn add_to_subpurpose_scores_for_node_reached(subpurpose_scores: &mut [Score; SUBPURPOSES_COUNT],
+// time_band_upper_bounds and subpurpose_scores_by_time_band are empty unless scores are being split
+// by time band. If they are, subpurpose_scores_by_time_band has one more band than there are
+// bounds, for anything reached after the last bound
+pub fn add_to_subpurpose_scores_for_node_reached(subpurpose_scores: &mut [Score],
                           node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>,
-                          subpurpose_purpose_lookup: &[usize; SUBPURPOSES_COUNT],
+                          subpurpose_purpose_lookup: &[usize],
                           travel_time_relationships: &[Multiplier],
+                          seconds_per_purpose: usize,
                           seconds_so_far: usize,
                           node_id: NodeID,
+                          time_band_upper_bounds: &[Cost],
+                          subpurpose_scores_by_time_band: &mut [Vec<Score>],
                           )  {
+    let time_band_ix = time_band_upper_bounds
+        .iter()
+        .position(|upper_bound| seconds_so_far <= upper_bound.0)
+        .unwrap_or(time_band_upper_bounds.len());
+
     for SubpurposeScore {
             subpurpose_ix,
             subpurpose_score,
     } in node_values_2d[node_id].iter()
     {
-        let vec_start_pos_this_purpose = subpurpose_purpose_lookup[*subpurpose_ix] * 3601;
-        let travel_time_multiplier = travel_time_relationships[vec_start_pos_this_purpose + seconds_so_far];
+        let travel_time_multiplier = get_travel_time_multiplier(
+            travel_time_relationships,
+            seconds_per_purpose,
+            subpurpose_purpose_lookup[*subpurpose_ix],
+            seconds_so_far,
+        );
         let score_to_add = subpurpose_score.multiply(travel_time_multiplier);
         subpurpose_scores[*subpurpose_ix] += score_to_add;
+
+        if !subpurpose_scores_by_time_band.is_empty() {
+            subpurpose_scores_by_time_band[time_band_ix][*subpurpose_ix] += score_to_add;
+        }
     }
 }
 
 
-pub fn calculate_purpose_scores_from_subpurpose_scores(
-        subpurpose_scores: &[Score; SUBPURPOSES_COUNT],
-        subpurpose_purpose_lookup: &[usize; SUBPURPOSES_COUNT],
-        score_multipler: &[Multiplier; SUBPURPOSES_COUNT],
-    ) -> [Score; PURPOSES_COUNT] {
+// Purpose scores are sums of logged subpurpose scores, so can't be split by time band directly. Instead each
+// logged subpurpose score is shared between time bands in proportion to the unlogged score reached in
+// each, so the time bands of each purpose sum to its purpose score
+pub fn split_purpose_scores_by_time_band(
+        subpurpose_scores_by_time_band: &[Vec<Score>],
+        subpurpose_scores: &[Score],
+        logged_subpurpose_scores: &[Score],
+        subpurpose_purpose_lookup: &[usize],
+        purposes_count: usize,
+    ) -> Vec<Vec<Score>> {
 
-    let mut overall_purpose_scores: [Score; PURPOSES_COUNT] = [Score(0.0); PURPOSES_COUNT];
-    for subpurpose_ix in 0..subpurpose_scores.len() {
+    let mut purpose_scores_by_time_band: Vec<Vec<Score>> = vec![vec![Score(0.0); purposes_count]; subpurpose_scores_by_time_band.len()];
+    for (time_band_ix, subpurpose_scores_this_band) in subpurpose_scores_by_time_band.iter().enumerate() {
+        for subpurpose_ix in 0..subpurpose_scores.len() {
+            if subpurpose_scores[subpurpose_ix].0 > 0.0 {
+                let share_this_band = subpurpose_scores_this_band[subpurpose_ix].0 / subpurpose_scores[subpurpose_ix].0;
+                let purpose_ix = subpurpose_purpose_lookup[subpurpose_ix];
+                purpose_scores_by_time_band[time_band_ix][purpose_ix] += Score(logged_subpurpose_scores[subpurpose_ix].0 * share_this_band);
+            }
+        }
+    }
+    purpose_scores_by_time_band
+}
+
+
+// Apply score_multipler and apply logarithm to get subpurpose level scores
+pub fn calculate_logged_subpurpose_scores(
+        subpurpose_scores: &[Score],
+        score_multipler: &[Multiplier],
+    ) -> Vec<Score> {
 
-        // Apply score_multipler and apply logarithm to get subpurpose level scores
+    let mut logged_subpurpose_scores: Vec<Score> = Vec::with_capacity(subpurpose_scores.len());
+    for subpurpose_ix in 0..subpurpose_scores.len() {
         let mut subpurpose_score = subpurpose_scores[subpurpose_ix]
             .multiply(score_multipler[subpurpose_ix])
             .ln();
@@ -81,46 +129,117 @@ pub fn calculate_purpose_scores_from_subpurpose_scores(
         if subpurpose_score < Score(0.0) {
             subpurpose_score = Score(0.0);
         }
+        logged_subpurpose_scores.push(subpurpose_score);
+    }
+    logged_subpurpose_scores
+}
+
+
+pub fn calculate_purpose_scores_from_subpurpose_scores(
+        subpurpose_scores: &[Score],
+        subpurpose_purpose_lookup: &[usize],
+        score_multipler: &[Multiplier],
+        purposes_count: usize,
+    ) -> Vec<Score> {
 
-        // add to purpose level scores
+    let logged_subpurpose_scores = calculate_logged_subpurpose_scores(subpurpose_scores, score_multipler);
+    sum_logged_subpurpose_scores_by_purpose(&logged_subpurpose_scores, subpurpose_purpose_lookup, purposes_count)
+}
+
+
+pub fn sum_logged_subpurpose_scores_by_purpose(
+        logged_subpurpose_scores: &[Score],
+        subpurpose_purpose_lookup: &[usize],
+        purposes_count: usize,
+    ) -> Vec<Score> {
+
+    let mut overall_purpose_scores: Vec<Score> = vec![Score(0.0); purposes_count];
+    for (subpurpose_ix, subpurpose_score) in logged_subpurpose_scores.iter().enumerate() {
         let purpose_ix = subpurpose_purpose_lookup[subpurpose_ix];
-        overall_purpose_scores[purpose_ix] += subpurpose_score;
+        overall_purpose_scores[purpose_ix] += *subpurpose_score;
     }
     overall_purpose_scores
 }
 
 
 
-pub fn get_cost_of_turn(
-    angle_leaving_node_from: Angle,
-    angle_arrived_from: Angle,
-    time_costs_turn: &[Cost; 4], 
-    ) -> Cost {
+// Keeps running totals of the destinations reached, by subpurpose and size band, and snapshots them
+// at each requested time interval. Nodes must be added in the order they are reached, which is in
+// order of cost, unless made with new_for_unordered_costs
+pub struct DestinationCountsAtTimeIntervals {
+    time_intervals_remaining: Vec<Cost>,
+    destination_counts_small_medium_large: Vec<Vec<Score>>,
+    snapshots: Vec<DestinationCountsAtTimeInterval>,
+    nodes_reached_to_sort: Option<Vec<(Cost, Vec<SubpurposeSmallMediumLargeCount>)>>,
+}
 
-    let time_turn_previous_node: Cost;
-    let angle_turn_previous_node: Angle;
+impl DestinationCountsAtTimeIntervals {
+    pub fn new(original_time_intervals_to_store_destination_counts: &[Cost], subpurposes_count: usize) -> Self {
+        let mut time_intervals_remaining = original_time_intervals_to_store_destination_counts.to_vec();
+        time_intervals_remaining.sort();
+        time_intervals_remaining.dedup();
+        let number_of_size_bands = 3; // set to 3 because 3 size bands: small, medium, large
+        DestinationCountsAtTimeIntervals {
+            time_intervals_remaining,
+            destination_counts_small_medium_large: vec![vec![Score(0.0); number_of_size_bands]; subpurposes_count],
+            snapshots: Vec::new(),
+            nodes_reached_to_sort: None,
+        }
+    }
+
+    // For searches which don't reach nodes in order of the cost counted by, eg seconds when finding
+    // shortest paths by distance: nodes are kept until finish, then added in order of cost
+    pub fn new_for_unordered_costs(original_time_intervals_to_store_destination_counts: &[Cost], subpurposes_count: usize) -> Self {
+        let mut destination_counts = Self::new(original_time_intervals_to_store_destination_counts, subpurposes_count);
+        destination_counts.nodes_reached_to_sort = Some(Vec::new());
+        destination_counts
+    }
 
-    if angle_leaving_node_from < angle_arrived_from {
-        angle_turn_previous_node = angle_leaving_node_from + Angle(360) - angle_arrived_from;
-    } else {
-        angle_turn_previous_node = angle_leaving_node_from -  angle_arrived_from;
+    // A snapshot for a time interval holds everything reached within it (cost <= time interval), so take
+    // snapshots for every interval this node is beyond before adding its destinations. One node can be
+    // beyond several intervals, eg after a long PT journey
+    pub fn add_node_reached(&mut self, destinations: &[SubpurposeSmallMediumLargeCount], cost: Cost) {
+        if let Some(nodes_reached_to_sort) = self.nodes_reached_to_sort.as_mut() {
+            nodes_reached_to_sort.push((cost, destinations.to_vec()));
+            return;
+        }
+        self.take_snapshots_up_to(cost);
+        for destination in destinations {
+            self.destination_counts_small_medium_large[destination.subpurpose_ix][0] += destination.small_destinations_count;
+            self.destination_counts_small_medium_large[destination.subpurpose_ix][1] += destination.medium_destinations_count;
+            self.destination_counts_small_medium_large[destination.subpurpose_ix][2] += destination.large_destinations_count;
+        }
     }
 
-    // right turn
-    if Angle(45) <= angle_turn_previous_node && angle_turn_previous_node < Angle(135) {
-        time_turn_previous_node = time_costs_turn[1];
-    // u turn
-    } else if Angle(135) <= angle_turn_previous_node && angle_turn_previous_node < Angle(225) {
-        time_turn_previous_node = time_costs_turn[2];
-    // left turn
-    } else if Angle(225) <= angle_turn_previous_node && angle_turn_previous_node < Angle(315) {
-       time_turn_previous_node = time_costs_turn[3];
-    // no turn
-    } else {
-        time_turn_previous_node = time_costs_turn[0];
+    fn take_snapshots_up_to(&mut self, cost: Cost) {
+        while !self.time_intervals_remaining.is_empty() && self.time_intervals_remaining[0] < cost {
+            let time_interval = self.time_intervals_remaining.remove(0);
+            self.snapshots.push(DestinationCountsAtTimeInterval {
+                time_interval,
+                destination_counts: self.destination_counts_small_medium_large.to_vec(),
+            });
+        }
     }
 
-    time_turn_previous_node
+    // Nothing more is reached after final_cost, so intervals not yet snapshotted get the final counts.
+    // Always includes a snapshot at final_cost (eg: the time limit)
+    pub fn finish(mut self, final_cost: Cost) -> Vec<DestinationCountsAtTimeInterval> {
+        if let Some(mut nodes_reached) = self.nodes_reached_to_sort.take() {
+            nodes_reached.sort_by_key(|(cost, _)| *cost);
+            for (cost, destinations) in nodes_reached {
+                self.add_node_reached(&destinations, cost);
+            }
+        }
+        self.take_snapshots_up_to(Cost(final_cost.0 + 1));
+        if self.snapshots.last().map(|snapshot| snapshot.time_interval) != Some(final_cost) {
+            self.snapshots.push(DestinationCountsAtTimeInterval {
+                time_interval: final_cost,
+                destination_counts: self.destination_counts_small_medium_large.to_vec(),
+            });
+        }
+        self.take_snapshots_up_to(Cost(usize::MAX));
+        self.snapshots
+    }
 }
 
 
@@ -138,3 +257,57 @@ pub fn extract_od_pairs(
     od_pairs
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one_small_destination() -> Vec<SubpurposeSmallMediumLargeCount> {
+        vec![SubpurposeSmallMediumLargeCount {
+            subpurpose_ix: 0,
+            small_destinations_count: Score(1.0),
+            medium_destinations_count: Score(0.0),
+            large_destinations_count: Score(0.0),
+        }]
+    }
+
+    #[test]
+    fn link_costs_are_first_in_first_out_across_periods() {
+        // 600 seconds in the am peak, which ends after 10:00, and 60 seconds after
+        let time_periods = TimePeriods::make_default_instance();
+        let link_cost_profiles = LinkCostProfiles {
+            periods_count: 4,
+            seconds_by_link_and_period: vec![600, 60, 60, 60],
+        };
+        let arrival = |seconds_past_midnight: usize| {
+            let seconds = get_link_cost_at_time(
+                &link_cost_profiles,
+                &time_periods,
+                "weekday",
+                LinkID(0),
+                SecondsPastMidnight(seconds_past_midnight),
+            );
+            seconds_past_midnight + seconds.0
+        };
+        assert_eq!(arrival(35000), 35600);
+        assert_eq!(arrival(35900), 36061);
+        assert_eq!(arrival(36000), 36061);
+        assert_eq!(arrival(36001), 36061);
+        assert!((35000..36100).all(|seconds_past_midnight| arrival(seconds_past_midnight) <= arrival(seconds_past_midnight + 1)));
+    }
+
+    #[test]
+    fn unordered_costs_are_counted_in_their_intervals() {
+        // Seconds when reached in order of distance: a slow short road before a fast long one
+        let mut destination_counts = DestinationCountsAtTimeIntervals::new_for_unordered_costs(&[Cost(600), Cost(1200)], 1);
+        for seconds in [100, 900, 300, 1100] {
+            destination_counts.add_node_reached(&one_small_destination(), Cost(seconds));
+        }
+        let small_counts: Vec<(usize, f64)> = destination_counts
+            .finish(Cost(1800))
+            .iter()
+            .map(|snapshot| (snapshot.time_interval.0, snapshot.destination_counts[0][0].0))
+            .collect();
+        assert_eq!(small_counts, vec![(600, 2.0), (1200, 4.0), (1800, 4.0)]);
+    }
+}
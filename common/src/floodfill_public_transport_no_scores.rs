@@ -3,9 +3,10 @@
 
 use crate::structs::{
     Cost, DestinationReached, FloodfillOutput, NodeID, NodeRoute,
-    NodeWalk, Score, SecondsPastMidnight, PURPOSES_COUNT,
+    NodeWalk, SecondsPastMidnight,
     RAIL_MULTIPLIER,
 };
+use crate::search_workspace::SearchWorkspace;
 use std::collections::{BinaryHeap};
 use typed_index_collections::TiVec;
 use std::cmp::Ordering;
@@ -47,6 +48,7 @@ impl<K: Ord, V: Ord, R: Ord, NT: Ord, IT: Ord, NM: Ord> Ord for PriorityQueueIte
 pub fn floodfill_public_transport_no_scores(
     graph_walk: &TiVec<NodeID, NodeWalk>,
     graph_routes: &TiVec<NodeID, NodeRoute>,
+    workspace: &mut SearchWorkspace,   // reused between calls on the same thread
     start_node_id: NodeID,
     trip_start_seconds: SecondsPastMidnight,
     seconds_walk_to_start_node: Cost,
@@ -70,12 +72,12 @@ pub fn floodfill_public_transport_no_scores(
         arrived_at_node_by_pt: 0,
     });
     
-    let mut nodes_visited: TiVec<NodeID, bool> = vec![false; graph_walk.len()].into();
+    workspace.start_search(graph_walk.len());
     let mut destinations_reached: Vec<DestinationReached> = vec![];
 
     // catch where start node is over an hour from centroid
     if seconds_walk_to_start_node >= time_limit {
-        let purpose_scores = [Score(0.0); PURPOSES_COUNT];
+        let purpose_scores = vec![];
         return FloodfillOutput {
             start_node_id,
             seconds_walk_to_start_node,
@@ -86,10 +88,9 @@ pub fn floodfill_public_transport_no_scores(
 
     while let Some(current) = queue.pop() {
         
-        if nodes_visited[current.node] {
+        if !workspace.visit_node(current.node) {
             continue;
         }
-        nodes_visited[current.node] = true;
 
         // First destination reached is to itself: this is fine as we later ignore first val in destinations_reached
         destinations_reached.push(DestinationReached {
@@ -137,7 +138,8 @@ pub fn floodfill_public_transport_no_scores(
         iters_count += 1;
     }
     
-    let purpose_scores = [Score(0.0); PURPOSES_COUNT];
+    // no scores are calculated here: get_all_scores_links_and_key_destinations() does this from destinations_reached
+    let purpose_scores = vec![];
     FloodfillOutput {
         start_node_id,
         seconds_walk_to_start_node,
@@ -1,10 +1,11 @@
 use crate::structs::{
     Cost, FloodfillOutputOriginDestinationPair, Multiplier, NodeID, NodeRoute,
-    NodeWalk, Score, SecondsPastMidnight, SubpurposeScore, PURPOSES_COUNT, SUBPURPOSES_COUNT,
-    RAIL_MULTIPLIER, SubpurposeSmallMediumLargeCount,
+    NodeWalk, PurposeTaxonomy, Score, SecondsPastMidnight, SubpurposeScore,
+    RAIL_MULTIPLIER, SubpurposeSmallMediumLargeCount, AccessibilityMetricsRequest,
 };
-use crate::floodfill_funcs::{initialise_score_multiplers, initialise_subpurpose_purpose_lookup, calculate_purpose_scores_from_subpurpose_scores, 
-    add_to_subpurpose_scores_for_node_reached};
+use crate::accessibility_metrics::AccessibilityMetricsAccumulator;
+use crate::search_workspace::SearchWorkspace;
+use crate::floodfill_funcs::{DestinationCountsAtTimeIntervals, calculate_logged_subpurpose_scores, sum_logged_subpurpose_scores_by_purpose, split_purpose_scores_by_time_band, add_to_subpurpose_scores_for_node_reached};
 
 use std::collections::{BinaryHeap};
 use typed_index_collections::TiVec;
@@ -42,6 +43,7 @@ impl<K: Ord, V: Ord, R: Ord> Ord for PriorityQueueItem<K, V, R> {
 pub fn floodfill_public_transport_purpose_scores(
     graph_walk: &TiVec<NodeID, NodeWalk>,
     graph_routes: &TiVec<NodeID, NodeRoute>,
+    workspace: &mut SearchWorkspace,   // reused between calls on the same thread
     start_node_id: NodeID,
     trip_start_seconds: SecondsPastMidnight,
     seconds_walk_to_start_node: Cost,
@@ -49,11 +51,15 @@ pub fn floodfill_public_transport_purpose_scores(
     time_limit: Cost,
     node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>,
     travel_time_relationships: &[Multiplier],
+    purpose_taxonomy: &PurposeTaxonomy,
     destination_nodes: &Vec<NodeID>,
     stop_rail_statuses: &TiVec<NodeID, bool>,
     small_medium_large_subpurpose_destinations: &TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>>,
     count_destinations_at_intervals: bool,
     original_time_intervals_to_store_destination_counts: &Vec<Cost>,
+    accessibility_metrics_request: Option<&AccessibilityMetricsRequest>,
+    return_subpurpose_scores: bool,   // also return scores for each subpurpose, both before and after logging
+    score_time_bands: &[Cost],   // upper bounds in seconds of the time bands to split purpose scores by, or empty
 ) -> FloodfillOutputOriginDestinationPair {
     
     let mut iters: usize = 0;
@@ -65,85 +71,95 @@ pub fn floodfill_public_transport_purpose_scores(
         rail_adjusted_cost: seconds_walk_to_start_node,
     });
     
-    let target_destinations = vec![false; graph_walk.len()];
-    let mut target_destinations: TiVec<NodeID, bool> = TiVec::from(target_destinations);
-    for node_id in destination_nodes.into_iter() {
-        target_destinations[*node_id] = true;
-    }
-    // making a copy we can edit            
-    let mut time_intervals_to_store_destination_counts = original_time_intervals_to_store_destination_counts.to_vec();
-    
-    // Make empty vector to store destination counts at the intervals specified in time_intervals_to_store_destination_counts
-    let mut destinations_reached_at_time_intervals = Vec::new();
-    
-    // This stores the total destinations reached, of each subpurpose and size banding
-    let number_of_size_bands = 3; // set to 3 because 3 size bands: small, medium, large
-    let mut destination_counts_small_medium_large: Vec<Vec<Score>> = vec![vec![Score(0.0); number_of_size_bands]; SUBPURPOSES_COUNT];
+    workspace.start_search(graph_walk.len());
+    workspace.mark_destinations(destination_nodes);
+    // Only populated if the API payload requested destination counts. Destinations are counted at their
+    // rail adjusted cost, as scores and the time limit are, which isn't the order nodes are reached in
+    let mut destination_counts_at_time_intervals = count_destinations_at_intervals.then(|| {
+        DestinationCountsAtTimeIntervals::new_for_unordered_costs(
+            original_time_intervals_to_store_destination_counts,
+            purpose_taxonomy.subpurposes_count(),
+        )
+    });
     
-    let mut subpurpose_scores = [Score(0.0); SUBPURPOSES_COUNT];
-    let subpurpose_purpose_lookup = initialise_subpurpose_purpose_lookup();
-    let score_multipliers = initialise_score_multiplers("bus");
-    let mut nodes_visited: TiVec<NodeID, bool> = vec![false; graph_walk.len()].into();
+    let mut subpurpose_scores = vec![Score(0.0); purpose_taxonomy.subpurposes_count()];
+    let mut subpurpose_scores_by_time_band: Vec<Vec<Score>> = if score_time_bands.is_empty() {
+        vec![]
+    } else {
+        vec![vec![Score(0.0); purpose_taxonomy.subpurposes_count()]; score_time_bands.len() + 1]
+    };
+    let subpurpose_purpose_lookup = &purpose_taxonomy.subpurpose_purpose_lookup;
+    let seconds_per_purpose = travel_time_relationships.len() / purpose_taxonomy.purposes_count();
+    let score_multipliers = purpose_taxonomy.score_multipliers("bus");
     let mut od_pairs_found: Vec<[usize;2]> = vec![];
+    let mut od_pairs_found_rail_adjusted: Vec<[usize;2]> = vec![];
     
     let nodes_reached_sequence: Vec<NodeID> = vec![];  // used when tracing PT nodes reached via driving only
     let nodes_reached_time_travelled: Vec<Cost> = vec![];
 
+    // Only populated if the API payload requested accessibility metrics
+    let mut accessibility_metrics_accumulator = accessibility_metrics_request
+        .map(|request| AccessibilityMetricsAccumulator::new(request, purpose_taxonomy.subpurposes_count()));
+
     // catch where start node is over an hour from centroid
     if seconds_walk_to_start_node >= time_limit {
-        let purpose_scores = [Score(0.0); PURPOSES_COUNT];
+        let purpose_scores = vec![Score(0.0); purpose_taxonomy.purposes_count()];
         return FloodfillOutputOriginDestinationPair {
             start_node_id,
             seconds_walk_to_start_node,
             purpose_scores,
+            subpurpose_scores: return_subpurpose_scores.then(|| subpurpose_scores.clone()),
+            subpurpose_scores_log: return_subpurpose_scores.then(|| vec![Score(0.0); purpose_taxonomy.subpurposes_count()]),
+            purpose_scores_by_time_band: (!score_time_bands.is_empty())
+                .then(|| vec![vec![Score(0.0); purpose_taxonomy.purposes_count()]; score_time_bands.len() + 1]),
             od_pairs_found,
+            od_pairs_found_rail_adjusted,
             iters,
             nodes_reached_sequence,
             nodes_reached_time_travelled,
             final_cost: seconds_walk_to_start_node,
-            destinations_reached_at_time_intervals,
+            destinations_reached_at_time_intervals: destination_counts_at_time_intervals
+                .map(|destination_counts| destination_counts.finish(time_limit))
+                .unwrap_or_default(),
+            accessibility_metrics: accessibility_metrics_accumulator.map(|accumulator| accumulator.finish()),
+            competition_adjusted_scores: None,   // added by the caller, as it needs the results from every start node
         };
     }
 
     while let Some(current) = queue.pop() {
         
-        if nodes_visited[current.node] {
+        if !workspace.visit_node(current.node) {
             continue;
         }
-        nodes_visited[current.node] = true;
 
-        if target_destinations[current.node] {
+        if workspace.is_destination(current.node) {
             od_pairs_found.push([current.cost.0,current.node.0]);
+            od_pairs_found_rail_adjusted.push([current.rail_adjusted_cost.0, current.node.0]);
         }
         
         // get scores
         add_to_subpurpose_scores_for_node_reached(
             &mut subpurpose_scores, 
             &node_values_2d,
-            &subpurpose_purpose_lookup,
-            &travel_time_relationships,
+            subpurpose_purpose_lookup,
+            travel_time_relationships,
+            seconds_per_purpose,
             current.rail_adjusted_cost.0,
             current.node,
+            score_time_bands,
+            &mut subpurpose_scores_by_time_band,
         );
+        if let Some(accumulator) = accessibility_metrics_accumulator.as_mut() {
+            accumulator.add_node_reached(
+                node_values_2d,
+                small_medium_large_subpurpose_destinations,
+                current.cost,
+                current.node,
+            );
+        }
         // Only bother counting destinations if the API payload requested it
-        if count_destinations_at_intervals {
-        
-            // add to our destinations counter for each subpurpose        
-            for destination in &small_medium_large_subpurpose_destinations[current.node] {
-                destination_counts_small_medium_large[destination.subpurpose_ix][0] += destination.small_destinations_count;
-                destination_counts_small_medium_large[destination.subpurpose_ix][1] += destination.medium_destinations_count;
-                destination_counts_small_medium_large[destination.subpurpose_ix][2] += destination.large_destinations_count;
-            }
-
-            // Push when a threshold is crossed in distance travelled, based on current.cost
-            // And remove the threshold from time_intervals_to_store_destination_counts, as we have reached it
-            if time_intervals_to_store_destination_counts.len() > 0 {
-                if current.cost >= time_intervals_to_store_destination_counts[0] {
-
-                    destinations_reached_at_time_intervals.push(destination_counts_small_medium_large.to_vec());
-                    time_intervals_to_store_destination_counts.remove(0);
-                }
-            }
+        if let Some(destination_counts) = destination_counts_at_time_intervals.as_mut() {
+            destination_counts.add_node_reached(&small_medium_large_subpurpose_destinations[current.node], current.rail_adjusted_cost);
         }
 
         // Finding adjacent walk nodes
@@ -185,22 +201,40 @@ pub fn floodfill_public_transport_purpose_scores(
     }
     
     // get purpose level scores
-    let purpose_scores = calculate_purpose_scores_from_subpurpose_scores(
-        &subpurpose_scores,
-        &subpurpose_purpose_lookup,
-        &score_multipliers,
+    let logged_subpurpose_scores = calculate_logged_subpurpose_scores(&subpurpose_scores, score_multipliers);
+    let purpose_scores = sum_logged_subpurpose_scores_by_purpose(
+        &logged_subpurpose_scores,
+        subpurpose_purpose_lookup,
+        purpose_taxonomy.purposes_count(),
     );
+    let purpose_scores_by_time_band = (!score_time_bands.is_empty()).then(|| {
+        split_purpose_scores_by_time_band(
+            &subpurpose_scores_by_time_band,
+            &subpurpose_scores,
+            &logged_subpurpose_scores,
+            subpurpose_purpose_lookup,
+            purpose_taxonomy.purposes_count(),
+        )
+    });
     
     FloodfillOutputOriginDestinationPair {
         start_node_id,
         seconds_walk_to_start_node,
         purpose_scores,
+        subpurpose_scores: return_subpurpose_scores.then(|| subpurpose_scores.clone()),
+        subpurpose_scores_log: return_subpurpose_scores.then_some(logged_subpurpose_scores),
+        purpose_scores_by_time_band,
         od_pairs_found,
+        od_pairs_found_rail_adjusted,
         iters,
         nodes_reached_sequence,
         nodes_reached_time_travelled,
         final_cost: time_limit,
-        destinations_reached_at_time_intervals,
+        destinations_reached_at_time_intervals: destination_counts_at_time_intervals
+            .map(|destination_counts| destination_counts.finish(time_limit))
+            .unwrap_or_default(),
+        accessibility_metrics: accessibility_metrics_accumulator.map(|accumulator| accumulator.finish()),
+        competition_adjusted_scores: None,   // added by the caller, as it needs the results from every start node
     }
 }
 
@@ -264,3 +298,74 @@ fn take_next_pt_route(
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{EdgeRoute, EdgeWalk, Subpurpose};
+    use smallvec::smallvec;
+    use std::collections::HashMap;
+
+    #[test]
+    fn destination_counts_use_rail_adjusted_cost() {
+        // Node 0 walks to node 1 in 3000 seconds, and takes a rail service to node 2 which arrives after
+        // 6000 seconds: 3000 rail adjusted, so within the 3600 second limit and counted at 3000 seconds
+        let graph_walk: TiVec<NodeID, NodeWalk> = vec![
+            NodeWalk { has_pt: true, edges: smallvec![EdgeWalk { to: NodeID(1), cost: Cost(3000) }] },
+            NodeWalk { has_pt: false, edges: smallvec![] },
+            NodeWalk { has_pt: false, edges: smallvec![] },
+        ]
+        .into();
+        let mut graph_routes: TiVec<NodeID, NodeRoute> = vec![NodeRoute::make_empty_instance(); 3].into();
+        graph_routes[NodeID(0)] = NodeRoute {
+            next_stop_node: NodeID(2),
+            timetable: smallvec![EdgeRoute { leavetime: SecondsPastMidnight(28800), cost: Cost(6000) }],
+        };
+        let purpose_taxonomy = PurposeTaxonomy {
+            version: 1,
+            purposes: vec!["Shopping".to_string()],
+            subpurposes: vec![Subpurpose { name: "Supermarket".to_string(), purpose_ix: 0 }],
+            score_multipliers: HashMap::from([("bus".to_string(), vec![Multiplier(1.0)])]),
+            size_bands: vec!["small".to_string(), "medium".to_string(), "large".to_string()],
+            subpurpose_purpose_lookup: vec![0],
+        };
+        let one_small_destination = vec![SubpurposeSmallMediumLargeCount {
+            subpurpose_ix: 0,
+            small_destinations_count: Score(1.0),
+            medium_destinations_count: Score(0.0),
+            large_destinations_count: Score(0.0),
+        }];
+        let small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>> =
+            vec![vec![], one_small_destination.clone(), one_small_destination].into();
+
+        let output = floodfill_public_transport_purpose_scores(
+            &graph_walk,
+            &graph_routes,
+            &mut SearchWorkspace::new(3),
+            NodeID(0),
+            SecondsPastMidnight(28800),
+            Cost(0),
+            false,
+            Cost(3600),
+            &vec![vec![]; 3].into(),
+            &vec![Multiplier(1.0); 3600],
+            &purpose_taxonomy,
+            &vec![],
+            &vec![true, false, false].into(),
+            &small_medium_large_subpurpose_destinations,
+            true,
+            &vec![Cost(1800), Cost(3200), Cost(3600), Cost(5400)],
+            None,
+            false,
+            &[],
+        );
+
+        let small_counts: Vec<(usize, f64)> = output
+            .destinations_reached_at_time_intervals
+            .iter()
+            .map(|snapshot| (snapshot.time_interval.0, snapshot.destination_counts[0][0].0))
+            .collect();
+        assert_eq!(small_counts, vec![(1800, 0.0), (3200, 2.0), (3600, 2.0), (5400, 2.0)]);
+    }
+}
@@ -1,10 +1,12 @@
 use std::collections::BinaryHeap;
-use std::collections::{HashSet, HashMap};
 use std::cmp::Ordering;
 use typed_index_collections::TiVec;
 
-use crate::structs::{Cost, NodeID, Angle, LinkID,Score, Multiplier, NodeWalkCyclingCar, FloodfillOutputOriginDestinationPairWalkCyclingCar, SubpurposeScore, PURPOSES_COUNT, SUBPURPOSES_COUNT, PreviousIterAndCurrentNodeId, SubpurposeSmallMediumLargeCount};
-use crate::floodfill_funcs::{initialise_score_multiplers, initialise_subpurpose_purpose_lookup, calculate_purpose_scores_from_subpurpose_scores, add_to_subpurpose_scores_for_node_reached, get_cost_of_turn};
+use crate::structs::{Cost, NodeID, Angle, LinkID, START_LINK_ID, LinkCostProfiles, SecondsPastMidnight, TimePeriods, Score, Multiplier, NodeWalkCyclingCar, FloodfillOutputOriginDestinationPairWalkCyclingCar, PurposeTaxonomy, SubpurposeScore, SubpurposeSmallMediumLargeCount, AccessibilityMetricsRequest};
+use crate::accessibility_metrics::AccessibilityMetricsAccumulator;
+use crate::floodfill_funcs::{DestinationCountsAtTimeIntervals, calculate_logged_subpurpose_scores, sum_logged_subpurpose_scores_by_purpose, split_purpose_scores_by_time_band, add_to_subpurpose_scores_for_node_reached, get_link_cost_at_time};
+use crate::turn_costs::{MajorRoads, TurnCostProfile};
+use crate::search_workspace::{PathIx, SearchWorkspace};
 
 
 // Use with `BinaryHeap`. Since it's a max-heap, reverse the comparison to get the smallest cost
@@ -12,12 +14,13 @@ use crate::floodfill_funcs::{initialise_score_multiplers, initialise_subpurpose_
 #[derive(PartialEq, Eq, Clone)]
 pub struct PriorityQueueItem<K, V, A, L, P, R, N> {
     pub cost: K,
+    pub other_cost: K,   // the metric not being optimised on: seconds when optimising on distance, and vice versa
     pub node: V,
     pub angle_arrived_from: A,
     pub link_arrived_from: L,
     pub previous_node_reached_iter: P,
     pub previous_node_reached: R,
-    pub nodes_visited_in_sequence: N,
+    pub path_so_far: N,   // nodes before this one, in the workspace's path arena
 }
 
 impl<K: Ord, V: Ord, A: Ord, L: Ord, P: Ord, R: Ord, N: Ord> PartialOrd for PriorityQueueItem<K, V, A, L, P, R, N> {
@@ -40,9 +43,12 @@ impl<K: Ord, V: Ord, A: Ord, L: Ord, P: Ord, R: Ord, N: Ord> Ord for PriorityQue
 
 pub fn floodfill_walk_cycling_car(
                 travel_time_relationships: &[Multiplier],
+                purpose_taxonomy: &PurposeTaxonomy,
                 node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>,
                 graph_walk: &TiVec<NodeID, NodeWalkCyclingCar>,
-                time_costs_turn: &[Cost; 4],
+                workspace: &mut SearchWorkspace,   // reused between calls on the same thread
+                turn_cost_profile: &TurnCostProfile,
+                major_roads: Option<&MajorRoads>,   // only needed if the profile has a major road crossing penalty
                 start_node_id: NodeID,
                 seconds_walk_to_start_node: Cost,
                 od_pair_destinations_vector: &[NodeID],  // if you want to find OD pairs, destinations from here
@@ -55,87 +61,123 @@ pub fn floodfill_walk_cycling_car(
                 small_medium_large_subpurpose_destinations: &TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>>,
                 count_destinations_at_intervals: bool,
                 original_time_intervals_to_store_destination_counts: &Vec<Cost>,
+                accessibility_metrics_request: Option<&AccessibilityMetricsRequest>,
+                return_subpurpose_scores: bool,   // also return scores for each subpurpose, both before and after logging
+                score_time_bands: &[Cost],   // upper bounds in seconds of the time bands to split purpose scores by, or empty
+                optimise_on_distance: bool,   // find shortest paths by distance rather than time: the graph must have distances
+                return_other_cost: bool,   // also return od_pairs_found_other_cost
+                link_cost_profiles: Option<&LinkCostProfiles>,   // if given, edge costs are taken from these at the time each edge is entered
+                trip_start_seconds: SecondsPastMidnight,
+                time_periods: &TimePeriods,   // the periods link_cost_profiles are for
+                day_type: &str,
             ) -> FloodfillOutputOriginDestinationPairWalkCyclingCar {
                 
     if target_node == start_node_id {
         println!("target_node is the same as start_node_id: this will generate an error! Change your input")
     }
     
-    // making a copy we can edit            
-    let mut time_intervals_to_store_destination_counts = original_time_intervals_to_store_destination_counts.to_vec();
-    
     // initialise values
-    let mut subpurpose_scores = [Score(0.0); SUBPURPOSES_COUNT];
+    let mut subpurpose_scores = vec![Score(0.0); purpose_taxonomy.subpurposes_count()];
+    let mut subpurpose_scores_by_time_band: Vec<Vec<Score>> = if score_time_bands.is_empty() {
+        vec![]
+    } else {
+        vec![vec![Score(0.0); purpose_taxonomy.subpurposes_count()]; score_time_bands.len() + 1]
+    };
                 
     // lookup between subpurpose idx and purpose (eg: primary school -> education)
-    let subpurpose_purpose_lookup = initialise_subpurpose_purpose_lookup();
+    let subpurpose_purpose_lookup = &purpose_taxonomy.subpurpose_purpose_lookup;
+    let seconds_per_purpose = travel_time_relationships.len() / purpose_taxonomy.purposes_count();
                 
     // multiplier to scale score to account for average size of destination 
-    let score_multipliers = initialise_score_multiplers(&mode);
+    let score_multipliers = purpose_taxonomy.score_multipliers(mode);
     
-    // to debug route creator for optimiser: add set of NodeIDs visited, to ensure no NodeIDs are visited more than once
-    let mut nodes_visited_in_sequence = Vec::new();
-                
-    let mut queue: BinaryHeap<PriorityQueueItem<Cost, NodeID, Angle, LinkID, usize, NodeID, Vec<NodeID>>> = BinaryHeap::new();
+    // Scores, destination counts, accessibility metrics and the time limit all use seconds, whichever
+    // metric is optimised on. The walk to the start node has no distance on the graph
+    let seconds_so_far = |item: &PriorityQueueItem<Cost, NodeID, Angle, LinkID, usize, NodeID, Option<PathIx>>| {
+        if optimise_on_distance { item.other_cost } else { item.cost }
+    };
+    let (start_cost, start_other_cost) = if optimise_on_distance {
+        (Cost(0), seconds_walk_to_start_node)
+    } else {
+        (seconds_walk_to_start_node, Cost(0))
+    };
+
+    let mut queue: BinaryHeap<PriorityQueueItem<Cost, NodeID, Angle, LinkID, usize, NodeID, Option<PathIx>>> = BinaryHeap::new();
     queue.push(PriorityQueueItem {
-        cost: seconds_walk_to_start_node,
+        cost: start_cost,
+        other_cost: start_other_cost,
         node: start_node_id,
         angle_arrived_from: Angle(0),
-        link_arrived_from: LinkID(99_999_999),
+        link_arrived_from: START_LINK_ID,
         previous_node_reached_iter: 0,
         previous_node_reached: start_node_id,
-        nodes_visited_in_sequence: nodes_visited_in_sequence.clone(), // to debug route creator for optimiser: add set of NodeIDs visited, to ensure no NodeIDs are visited more than once
+        path_so_far: None, // to debug route creator for optimiser: used to ensure no NodeIDs are visited more than once
     });
          
     // storing for outputs
     let mut od_pairs_found: Vec<[usize;2]> = Vec::new();
+    let mut od_pairs_found_other_cost: Vec<[usize;2]> = Vec::new();
     
     let mut iters: usize = 0;
-    let mut links_visited = HashSet::new();
-    let mut nodes_visited: TiVec<NodeID, bool> = vec![false; graph_walk.len()].into();
-    
-    // make boolean vec to quickly check if a node is a target node for OD pair finding
-    let mut od_pair_destinations_binary_vec: TiVec<NodeID, bool> = vec![false; graph_walk.len()].into();                
-    for node_id in od_pair_destinations_vector.into_iter() {
-        od_pair_destinations_binary_vec[*node_id] = true;
-    }
+    workspace.start_search(graph_walk.len());
     
-    // Make empty vector to store destination counts at the intervals specified in time_intervals_to_store_destination_counts
-    let mut destinations_reached_at_time_intervals = Vec::new();
+    // mark destinations to quickly check if a node is a target node for OD pair finding
+    workspace.mark_destinations(od_pair_destinations_vector);
     
     // These are only populated if seconds_reclaimed_when_pt_stop_reached is true; otherwise they are returned to the user as empty
     let mut nodes_reached_sequence: Vec<NodeID> = vec![];
     let mut nodes_reached_time_travelled: Vec<Cost> = vec![];
                 
-    // This stores the total destinations reached, of each subpurpose and size banding
-    let number_of_size_bands = 3; // set to 3 because 3 size bands: small, medium, large
-    let mut destination_counts_small_medium_large: Vec<Vec<Score>> = vec![vec![Score(0.0); number_of_size_bands]; SUBPURPOSES_COUNT];
+    // Only populated if the API payload requested destination counts. Counts are by seconds, which
+    // aren't in order when finding shortest paths by distance
+    let mut destination_counts_at_time_intervals = count_destinations_at_intervals.then(|| {
+        if optimise_on_distance {
+            DestinationCountsAtTimeIntervals::new_for_unordered_costs(
+                original_time_intervals_to_store_destination_counts,
+                purpose_taxonomy.subpurposes_count(),
+            )
+        } else {
+            DestinationCountsAtTimeIntervals::new(
+                original_time_intervals_to_store_destination_counts,
+                purpose_taxonomy.subpurposes_count(),
+            )
+        }
+    });
+
+    // Only populated if the API payload requested accessibility metrics
+    let mut accessibility_metrics_accumulator = accessibility_metrics_request
+        .map(|request| AccessibilityMetricsAccumulator::new(request, purpose_taxonomy.subpurposes_count()));
 
     // catch where start node is over an hour from centroid
-    if seconds_walk_to_start_node >= Cost(3600) {
-        let purpose_scores = [Score(0.0); PURPOSES_COUNT];
+    if seconds_walk_to_start_node >= time_limit_seconds {
+        let purpose_scores = vec![Score(0.0); purpose_taxonomy.purposes_count()];
         return
             FloodfillOutputOriginDestinationPairWalkCyclingCar{
                 start_node_id,
                 seconds_walk_to_start_node,
                 purpose_scores,
+                subpurpose_scores: return_subpurpose_scores.then(|| subpurpose_scores.clone()),
+                subpurpose_scores_log: return_subpurpose_scores.then(|| vec![Score(0.0); purpose_taxonomy.subpurposes_count()]),
+                purpose_scores_by_time_band: (!score_time_bands.is_empty())
+                    .then(|| vec![vec![Score(0.0); purpose_taxonomy.purposes_count()]; score_time_bands.len() + 1]),
                 od_pairs_found,
+                od_pairs_found_other_cost: return_other_cost.then_some(od_pairs_found_other_cost),
                 iters,
                 nodes_reached_sequence, 
                 final_cost: seconds_walk_to_start_node,
-                destinations_reached_at_time_intervals,
+                destinations_reached_at_time_intervals: destination_counts_at_time_intervals
+                    .map(|destination_counts| destination_counts.finish(time_limit_seconds))
+                    .unwrap_or_default(),
+                accessibility_metrics: accessibility_metrics_accumulator.map(|accumulator| accumulator.finish()),
+                competition_adjusted_scores: None,   // added by the caller, as it needs the results from every start node
         };
     }
     
-    let mut nodes_reached: HashMap<NodeID, NodeID> = HashMap::new();
-                 
     while let Some(current) = queue.pop() {
         
-        // Optional speed improvement: see if links_visited can be stored as a boolean vector rather than a hashset
-        if links_visited.contains(&current.link_arrived_from) {
+        if !workspace.visit_link(current.link_arrived_from) {
             continue
         }
-        links_visited.insert(current.link_arrived_from);
         
         // 
         if track_pt_nodes_reached {
@@ -147,11 +189,10 @@ pub fn floodfill_walk_cycling_car(
                 // printing for debug
                 // the error may be due to no iters to add to node reached - if start node is the target node
                 //println!("current.previous_node_reached:\t{:?}", current.previous_node_reached.0);
-                //println!("nodes_reached len:\t{:?}", nodes_reached.keys().len());
                 
                 // trace sequence of nodes reached
-                // if target_node == start_node_id, then this will get an error, as nodes_reached will be empty
-                let mut previous_node_id = nodes_reached[&current.previous_node_reached];
+                // if target_node == start_node_id, then this will get an error, as no predecessors will be set
+                let mut previous_node_id = workspace.predecessor(current.previous_node_reached).unwrap();
                 
                 // store final 3 nodes reached. Use an 'if' to ensure no errors if sequence is only 2 nodes long
                 nodes_reached_sequence.push(target_node);
@@ -163,71 +204,118 @@ pub fn floodfill_walk_cycling_car(
                 while previous_node_id != start_node_id {
                     
                     println!("previous_node_id:\t{:?}", previous_node_id.0);
-                    previous_node_id = nodes_reached[&previous_node_id];
+                    previous_node_id = workspace.predecessor(previous_node_id).unwrap();
                     nodes_reached_sequence.push(previous_node_id);
                 }
                 
-                let purpose_scores = calculate_purpose_scores_from_subpurpose_scores(
-                    &subpurpose_scores,
-                    &subpurpose_purpose_lookup,
-                    &score_multipliers,
+                let logged_subpurpose_scores = calculate_logged_subpurpose_scores(&subpurpose_scores, score_multipliers);
+                let purpose_scores = sum_logged_subpurpose_scores_by_purpose(
+                    &logged_subpurpose_scores,
+                    subpurpose_purpose_lookup,
+                    purpose_taxonomy.purposes_count(),
                 );
+                let purpose_scores_by_time_band = (!score_time_bands.is_empty()).then(|| {
+                    split_purpose_scores_by_time_band(
+                        &subpurpose_scores_by_time_band,
+                        &subpurpose_scores,
+                        &logged_subpurpose_scores,
+                        subpurpose_purpose_lookup,
+                        purpose_taxonomy.purposes_count(),
+                    )
+                });
                 
 
                 return FloodfillOutputOriginDestinationPairWalkCyclingCar{
                     start_node_id,
                     seconds_walk_to_start_node,
                     purpose_scores,
+                    subpurpose_scores: return_subpurpose_scores.then(|| subpurpose_scores.clone()),
+                    subpurpose_scores_log: return_subpurpose_scores.then_some(logged_subpurpose_scores),
+                    purpose_scores_by_time_band,
                     od_pairs_found,
+                    od_pairs_found_other_cost: return_other_cost.then_some(od_pairs_found_other_cost),
                     iters,
                     nodes_reached_sequence,
-                    final_cost: current.cost,
-                    destinations_reached_at_time_intervals,
+                    final_cost: seconds_so_far(&current),
+                    destinations_reached_at_time_intervals: destination_counts_at_time_intervals
+                        .map(|destination_counts| destination_counts.finish(seconds_so_far(&current)))
+                        .unwrap_or_default(),
+                    accessibility_metrics: accessibility_metrics_accumulator.map(|accumulator| accumulator.finish()),
+                    competition_adjusted_scores: None,   // added by the caller, as it needs the results from every start node
                 }
             }
         }
         
+        // only extended if track_pt_nodes_reached is true, so the arena stays empty otherwise
+        let mut path_including_current: Option<PathIx> = None;
+        if track_pt_nodes_reached {
+            workspace.set_current_path(current.path_so_far);
+        }
+        
         // so long as this is the first time a link is taken, we add the link; a node can be reached multiple times: once for each link
         for edge in graph_walk[current.node].edges.iter() {
             
-            let time_turn_previous_node = get_cost_of_turn(
+            let mut time_turn_previous_node = turn_cost_profile.cost_of_turn(
                 edge.angle_leaving_node_from,
                 current.angle_arrived_from,
-                time_costs_turn, 
             );
+            if let Some(major_roads) = major_roads {
+                if major_roads.is_crossing(current.link_arrived_from, current.node, edge.link_arrived_from) {
+                    time_turn_previous_node = time_turn_previous_node + turn_cost_profile.major_road_crossing_seconds;
+                }
+            }
             
-            let mut new_cost = current.cost + edge.cost + time_turn_previous_node;
+            let edge_seconds = match link_cost_profiles {
+                Some(link_cost_profiles) => get_link_cost_at_time(
+                    link_cost_profiles,
+                    time_periods,
+                    day_type,
+                    edge.link_arrived_from,
+                    SecondsPastMidnight(trip_start_seconds.0 + seconds_so_far(&current).0),
+                ),
+                None => edge.cost,
+            };
+            let mut new_seconds = seconds_so_far(&current) + edge_seconds + time_turn_previous_node;
             
             if track_pt_nodes_reached {
 
-                // nodes_visited_in_sequence is used to prevent any node from being visited more than once in a given sequence
+                // path_so_far is used to prevent any node from being visited more than once in a given sequence
                 // there is no need to run this unless is track_pt_nodes_reached is true: nodes will only be visited
                 // more than once when new_cost (below) is negative, which can only happen because of the line:
                 // "new_cost -= Cost(seconds_reclaimed_when_pt_stop_reached);"
-                let mut nodes_visited_in_sequence = current.nodes_visited_in_sequence.clone();
-                if nodes_visited_in_sequence.contains(&edge.to) {
+                if workspace.is_on_current_path(edge.to) {
                     continue
-                } {
-                    nodes_visited_in_sequence.push(current.node);
+                }
+                if path_including_current.is_none() {
+                    path_including_current = Some(workspace.extend_path(current.path_so_far, current.node, current.link_arrived_from));
                 }
                 
                 // If is a PT node, take seconds_reclaimed_when_pt_stop_reached from new_cost
                 // Is this out of whack with previous_iters_and_current_node_ids ?
                 if car_nodes_is_closest_to_pt[edge.to] {
-                    new_cost -= Cost(seconds_reclaimed_when_pt_stop_reached);
+                    new_seconds -= Cost(seconds_reclaimed_when_pt_stop_reached);
                 }
             }
             
-            if new_cost < time_limit_seconds {
+            if new_seconds < time_limit_seconds {
                 
+                // distance_metres is only read if it's needed
+                let (new_cost, new_other_cost) = if optimise_on_distance {
+                    (current.cost + edge.distance_metres.unwrap(), new_seconds)
+                } else if return_other_cost {
+                    (new_seconds, current.other_cost + edge.distance_metres.unwrap())
+                } else {
+                    (new_seconds, Cost(0))
+                };
                 queue.push(PriorityQueueItem {
                     cost: new_cost,
+                    other_cost: new_other_cost,
                     node: edge.to,
                     angle_arrived_from: edge.angle_arrived_from,
                     link_arrived_from: edge.link_arrived_from,
                     previous_node_reached_iter: iters,
                     previous_node_reached: current.node, //  use this to store sequence of nodes
-                    nodes_visited_in_sequence: nodes_visited_in_sequence.clone(), // to debug route creator for optimiser: will be empty unless track_pt_nodes_reached is true
+                    path_so_far: path_including_current, // to debug route creator for optimiser: will be None unless track_pt_nodes_reached is true
                 });
                 
                 
@@ -235,68 +323,163 @@ pub fn floodfill_walk_cycling_car(
         }
         
         // we only add scores and od pairs if this node has not been reached before: a node may be reached via multiple links
-        if nodes_visited[current.node] {
+        if !workspace.visit_node(current.node) {
             continue;
         }
-        nodes_visited[current.node] = true;
         
-        nodes_reached.insert(current.node, current.previous_node_reached);
+        workspace.set_predecessor(current.node, current.previous_node_reached);
         iters += 1;
         
         
-        if od_pair_destinations_binary_vec[current.node] {
+        if workspace.is_destination(current.node) {
             od_pairs_found.push([current.cost.0,current.node.0]);
+            if return_other_cost {
+                od_pairs_found_other_cost.push([current.other_cost.0, current.node.0]);
+            }
         }
+        let current_seconds = seconds_so_far(&current);
 
         add_to_subpurpose_scores_for_node_reached(
               &mut subpurpose_scores,
               node_values_2d,
-              &subpurpose_purpose_lookup,
+              subpurpose_purpose_lookup,
               travel_time_relationships,
-              current.cost.0,
+              seconds_per_purpose,
+              current_seconds.0,
               current.node,
+              score_time_bands,
+              &mut subpurpose_scores_by_time_band,
         );
+        if let Some(accumulator) = accessibility_metrics_accumulator.as_mut() {
+            accumulator.add_node_reached(
+                node_values_2d,
+                small_medium_large_subpurpose_destinations,
+                current_seconds,
+                current.node,
+            );
+        }
         
         // Only bother counting destinations if the API payload requested it
-        if count_destinations_at_intervals {
-        
-            // add to our destinations counter for each subpurpose        
-            for destination in &small_medium_large_subpurpose_destinations[current.node] {
-                destination_counts_small_medium_large[destination.subpurpose_ix][0] += destination.small_destinations_count;
-                destination_counts_small_medium_large[destination.subpurpose_ix][1] += destination.medium_destinations_count;
-                destination_counts_small_medium_large[destination.subpurpose_ix][2] += destination.large_destinations_count;
-            }
-
-            // Push when a threshold is crossed in distance travelled, based on current.cost
-            // And remove the threshold from time_intervals_to_store_destination_counts, as we have reached it
-            if time_intervals_to_store_destination_counts.len() > 0 {
-                if current.cost >= time_intervals_to_store_destination_counts[0] {
-
-                    destinations_reached_at_time_intervals.push(destination_counts_small_medium_large.to_vec());
-                    time_intervals_to_store_destination_counts.remove(0);
-                }
-            }
+        if let Some(destination_counts) = destination_counts_at_time_intervals.as_mut() {
+            destination_counts.add_node_reached(&small_medium_large_subpurpose_destinations[current.node], current_seconds);
         }
         
         
         
     }
                 
-    let purpose_scores = calculate_purpose_scores_from_subpurpose_scores(
-        &subpurpose_scores,
-        &subpurpose_purpose_lookup,
-        &score_multipliers,
+    let logged_subpurpose_scores = calculate_logged_subpurpose_scores(&subpurpose_scores, score_multipliers);
+    let purpose_scores = sum_logged_subpurpose_scores_by_purpose(
+        &logged_subpurpose_scores,
+        subpurpose_purpose_lookup,
+        purpose_taxonomy.purposes_count(),
     );
+    let purpose_scores_by_time_band = (!score_time_bands.is_empty()).then(|| {
+        split_purpose_scores_by_time_band(
+            &subpurpose_scores_by_time_band,
+            &subpurpose_scores,
+            &logged_subpurpose_scores,
+            subpurpose_purpose_lookup,
+            purpose_taxonomy.purposes_count(),
+        )
+    });
                     
     FloodfillOutputOriginDestinationPairWalkCyclingCar{
         start_node_id,
         seconds_walk_to_start_node,
         purpose_scores,
+        subpurpose_scores: return_subpurpose_scores.then(|| subpurpose_scores.clone()),
+        subpurpose_scores_log: return_subpurpose_scores.then_some(logged_subpurpose_scores),
+        purpose_scores_by_time_band,
         od_pairs_found,
+        od_pairs_found_other_cost: return_other_cost.then_some(od_pairs_found_other_cost),
         iters,
         nodes_reached_sequence,
         final_cost: time_limit_seconds,
-        destinations_reached_at_time_intervals,
+        destinations_reached_at_time_intervals: destination_counts_at_time_intervals
+            .map(|destination_counts| destination_counts.finish(time_limit_seconds))
+            .unwrap_or_default(),
+        accessibility_metrics: accessibility_metrics_accumulator.map(|accumulator| accumulator.finish()),
+        competition_adjusted_scores: None,   // added by the caller, as it needs the results from every start node
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{EdgeWalkCyclingCar, Subpurpose};
+    use smallvec::smallvec;
+    use std::collections::HashMap;
+
+    fn edge(to: usize, bearing: u16, link: u32) -> EdgeWalkCyclingCar {
+        EdgeWalkCyclingCar {
+            to: NodeID(to),
+            cost: Cost(1),
+            angle_leaving_node_from: Angle(bearing),
+            angle_arrived_from: Angle(bearing),
+            link_arrived_from: LinkID(link),
+            distance_metres: None,
+        }
     }
 
+    #[test]
+    fn tracks_the_path_to_a_target_along_a_long_chain() {
+        // Each cycle check on the way down the chain must not walk back over it, or this takes minutes
+        let nodes_count = 50_000;
+        let graph: TiVec<NodeID, NodeWalkCyclingCar> = (0..nodes_count)
+            .map(|ix| {
+                let mut node = NodeWalkCyclingCar { edges: smallvec![] };
+                if ix > 0 {
+                    node.edges.push(edge(ix - 1, 270, 2 * ix as u32));
+                }
+                if ix + 1 < nodes_count {
+                    node.edges.push(edge(ix + 1, 90, 2 * ix as u32 + 1));
+                }
+                node
+            })
+            .collect();
+        let purpose_taxonomy = PurposeTaxonomy {
+            version: 1,
+            purposes: vec!["Shopping".to_string()],
+            subpurposes: vec![Subpurpose { name: "Supermarket".to_string(), purpose_ix: 0 }],
+            score_multipliers: HashMap::from([("walk".to_string(), vec![Multiplier(1.0)])]),
+            size_bands: vec!["small".to_string(), "medium".to_string(), "large".to_string()],
+            subpurpose_purpose_lookup: vec![0],
+        };
+        let mut workspace = SearchWorkspace::new(graph.len());
+        let floodfill_output = floodfill_walk_cycling_car(
+            &[Multiplier(1.0)],
+            &purpose_taxonomy,
+            &vec![vec![]; graph.len()].into(),
+            &graph,
+            &mut workspace,
+            &TurnCostProfile::make_default_instance("walk"),
+            None,
+            NodeID(0),
+            Cost(0),
+            &[],
+            Cost(nodes_count * 2),
+            "walk",
+            true,
+            0,
+            NodeID(nodes_count - 1),
+            &vec![false; graph.len()].into(),
+            &vec![vec![]; graph.len()].into(),
+            false,
+            &vec![],
+            None,
+            false,
+            &[],
+            false,
+            false,
+            None,
+            SecondsPastMidnight(28800),
+            &TimePeriods::make_default_instance(),
+            "weekday",
+        );
+        let expected_sequence: Vec<NodeID> = (0..nodes_count).rev().map(NodeID).collect();
+        assert_eq!(floodfill_output.nodes_reached_sequence, expected_sequence);
+        assert_eq!(floodfill_output.final_cost, Cost(nodes_count - 1));
+    }
 }
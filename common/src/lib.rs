@@ -2,5 +2,20 @@ pub mod floodfill_funcs;
 pub mod floodfill_public_transport_no_scores;
 pub mod floodfill_walk_cycling_car;
 pub mod read_file_funcs;
+pub mod spatial_index;
 pub mod structs;
-pub mod floodfill_public_transport_purpose_scores;
\ No newline at end of file
+pub mod floodfill_public_transport_purpose_scores;
+pub mod zones;
+pub mod named_output;
+pub mod decay_functions;
+pub mod accessibility_metrics;
+pub mod competition;
+pub mod turn_costs;
+pub mod search_workspace;
+pub mod routing;
+pub mod contraction_hierarchy;
+pub mod od_matrix;
+pub mod ndjson_stream;
+pub mod columnar_output;
+pub mod batch_runner;
+pub mod national_run;
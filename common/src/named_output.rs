@@ -0,0 +1,93 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::structs::{DestinationCountsAtTimeInterval, NamedDestinationCounts, PurposeTaxonomy, Score};
+
+pub fn name_purpose_scores(purpose_taxonomy: &PurposeTaxonomy, purpose_scores: &[Score]) -> BTreeMap<String, Score> {
+    purpose_taxonomy
+        .purposes
+        .iter()
+        .cloned()
+        .zip(purpose_scores.iter().copied())
+        .collect()
+}
+
+pub fn name_destination_counts(
+    purpose_taxonomy: &PurposeTaxonomy,
+    destinations_reached_at_time_intervals: &[DestinationCountsAtTimeInterval],
+) -> Vec<NamedDestinationCounts> {
+    destinations_reached_at_time_intervals
+        .iter()
+        .map(|snapshot| {
+            let destination_counts = purpose_taxonomy
+                .subpurposes
+                .iter()
+                .zip(snapshot.destination_counts.iter())
+                .map(|(subpurpose, size_band_counts)| {
+                    let named_size_band_counts = purpose_taxonomy
+                        .size_bands
+                        .iter()
+                        .cloned()
+                        .zip(size_band_counts.iter().copied())
+                        .collect();
+                    (subpurpose.name.clone(), named_size_band_counts)
+                })
+                .collect();
+            NamedDestinationCounts {
+                time_interval: snapshot.time_interval,
+                destination_counts,
+            }
+        })
+        .collect()
+}
+
+// Swaps the positional purpose_scores and destinations_reached_at_time_intervals of any floodfill
+// output for versions keyed by name. Other fields are left as they are, and fields the output
+// doesn't have aren't added
+pub fn add_names_to_output<T: Serialize>(
+    output: &T,
+    purpose_taxonomy: &PurposeTaxonomy,
+    purpose_scores: &[Score],
+    destinations_reached_at_time_intervals: &[DestinationCountsAtTimeInterval],
+) -> Value {
+    let mut named_output = serde_json::to_value(output).unwrap();
+    if let Some(value) = named_output.get_mut("purpose_scores") {
+        *value = serde_json::to_value(name_purpose_scores(purpose_taxonomy, purpose_scores)).unwrap();
+    }
+    if let Some(value) = named_output.get_mut("destinations_reached_at_time_intervals") {
+        *value = serde_json::to_value(name_destination_counts(purpose_taxonomy, destinations_reached_at_time_intervals))
+            .unwrap();
+    }
+    for key in ["subpurpose_scores", "subpurpose_scores_log"] {
+        if let Some(value) = named_output.get_mut(key) {
+            name_score_array(value, purpose_taxonomy.subpurposes.iter().map(|subpurpose| &subpurpose.name));
+        }
+    }
+    if let Some(Value::Array(time_bands)) = named_output.get_mut("purpose_scores_by_time_band") {
+        for value in time_bands.iter_mut() {
+            name_score_array(value, purpose_taxonomy.purposes.iter());
+        }
+    }
+    // Competition adjusted scores are Vecs of scores like purpose_scores, so are named the same way
+    if let Some(value) = named_output.get_mut("competition_adjusted_purpose_scores") {
+        name_score_array(value, purpose_taxonomy.purposes.iter());
+    }
+    if let Some(competition_adjusted_scores) = named_output.get_mut("competition_adjusted_scores") {
+        if let Some(value) = competition_adjusted_scores.get_mut("purpose_scores") {
+            name_score_array(value, purpose_taxonomy.purposes.iter());
+        }
+        if let Some(value) = competition_adjusted_scores.get_mut("subpurpose_scores") {
+            name_score_array(value, purpose_taxonomy.subpurposes.iter().map(|subpurpose| &subpurpose.name));
+        }
+    }
+    named_output
+}
+
+// Leaves anything which isn't an array (eg: null) as it is
+fn name_score_array<'a>(value: &mut Value, names: impl Iterator<Item = &'a String>) {
+    if let Value::Array(scores) = value {
+        let named_scores: Map<String, Value> = names.cloned().zip(scores.drain(..)).collect();
+        *value = Value::Object(named_scores);
+    }
+}
@@ -0,0 +1,318 @@
+use fs_err::File;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::batch_runner::run_catching_panics;
+
+pub const DEFAULT_ORIGINS_PER_CHUNK: usize = 1000;
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+const NATIONAL_RUN_STATE_FILENAME: &str = "national_run_state.json";
+const NATIONAL_OUTPUT_FILENAME: &str = "national_output.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChunkState {
+    pub first_origin_ix: usize,
+    pub origins_count: usize,
+    pub status: ChunkStatus,
+    pub attempts: usize,   // over every run, not just this one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+// Saved after every chunk, so a run that crashes or is stopped can carry on where it was
+#[derive(Serialize, Deserialize)]
+pub struct NationalRunState {
+    pub origins_count: usize,
+    pub origins_per_chunk: usize,
+    pub chunks: Vec<ChunkState>,
+}
+
+impl NationalRunState {
+    fn new(origins_count: usize, origins_per_chunk: usize) -> Self {
+        let chunks = (0..origins_count)
+            .step_by(origins_per_chunk)
+            .map(|first_origin_ix| ChunkState {
+                first_origin_ix,
+                origins_count: origins_per_chunk.min(origins_count - first_origin_ix),
+                status: ChunkStatus::Pending,
+                attempts: 0,
+                last_error: None,
+            })
+            .collect();
+        NationalRunState {
+            origins_count,
+            origins_per_chunk,
+            chunks,
+        }
+    }
+
+    // Written under a temporary name and renamed, so a crash part way through saving leaves the last state
+    fn save(&self, output_dir: &str) {
+        let path = format!("{}/{}", output_dir, NATIONAL_RUN_STATE_FILENAME);
+        let partial_path = format!("{}.partial", path);
+        let mut file = BufWriter::new(File::create(&partial_path).unwrap());
+        serde_json::to_writer_pretty(&mut file, self).unwrap();
+        file.into_inner().unwrap().sync_all().unwrap();
+        fs_err::rename(&partial_path, &path).unwrap();
+    }
+}
+
+fn chunk_path(output_dir: &str, chunk_ix: usize) -> String {
+    format!("{}/chunk_{:05}.json", output_dir, chunk_ix)
+}
+
+// Reads `<template_request.json> <output_dir> [origins_per_chunk] [max_attempts]` from the command line
+// arguments after `national_run`. The template is a request for the endpoint run_request runs, with every
+// origin in its start node and init travel time fields: each chunk is the template with a slice of them
+pub fn national_run_command(
+    args: &[String],
+    start_nodes_field: &str,
+    init_travel_times_field: &str,
+    run_request: impl Fn(&str) -> Result<String, String>,
+) {
+    if args.len() < 2 {
+        panic!("Usage: national_run <template_request.json> <output_dir> [origins_per_chunk] [max_attempts]");
+    }
+    let origins_per_chunk = match args.get(2) {
+        Some(origins_per_chunk) => origins_per_chunk.parse().unwrap(),
+        None => DEFAULT_ORIGINS_PER_CHUNK,
+    };
+    let max_attempts = match args.get(3) {
+        Some(max_attempts) => max_attempts.parse().unwrap(),
+        None => DEFAULT_MAX_ATTEMPTS,
+    };
+
+    let template: Value = serde_json::from_reader(BufReader::new(File::open(&args[0]).unwrap())).unwrap();
+    // These combine results across origins, so would be wrong for each chunk on its own
+    for field in ["zone_ids", "competition", "od_matrix"] {
+        let is_set = match &template[field] {
+            Value::Null => false,
+            Value::Array(values) => !values.is_empty(),
+            _ => true,
+        };
+        if is_set {
+            panic!("National runs are split into chunks of origins, so can't use {}", field);
+        }
+    }
+    let start_nodes = template[start_nodes_field].as_array().unwrap().clone();
+    let init_travel_times = template[init_travel_times_field].as_array().unwrap().clone();
+    if start_nodes.len() != init_travel_times.len() {
+        panic!(
+            "{} has {} values but {} has {}",
+            start_nodes_field,
+            start_nodes.len(),
+            init_travel_times_field,
+            init_travel_times.len()
+        );
+    }
+
+    run_national(&args[1], start_nodes.len(), origins_per_chunk, max_attempts, |origins| {
+        let mut request = template.clone();
+        request[start_nodes_field] = Value::Array(start_nodes[origins.clone()].to_vec());
+        request[init_travel_times_field] = Value::Array(init_travel_times[origins].to_vec());
+        run_request(&request.to_string())
+    });
+}
+
+// Splits origins_count origins into chunks and runs each through run_chunk, which returns the JSON array
+// of results for the origins in the range, in order. Failed chunks are retried after every other chunk
+// has had a go, up to max_attempts times in each run. Once every chunk is done, their results are joined
+// into national_output.jsonl, one line per origin with its origin_ix added. Rerunning with the same
+// arguments skips chunks already done, and gives failed ones another max_attempts
+pub fn run_national(
+    output_dir: &str,
+    origins_count: usize,
+    origins_per_chunk: usize,
+    max_attempts: usize,
+    run_chunk: impl Fn(Range<usize>) -> Result<String, String>,
+) {
+    assert!(origins_per_chunk > 0, "origins_per_chunk must be at least 1");
+    fs_err::create_dir_all(output_dir).unwrap();
+    let mut state = read_or_create_state(output_dir, origins_count, origins_per_chunk);
+    let chunks_count = state.chunks.len();
+
+    for attempt in 0..max_attempts {
+        let chunk_ixs: Vec<usize> = (0..chunks_count)
+            .filter(|chunk_ix| state.chunks[*chunk_ix].status != ChunkStatus::Done)
+            .collect();
+        if chunk_ixs.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            println!("Retrying {} failed chunks", chunk_ixs.len());
+        }
+        for chunk_ix in chunk_ixs {
+            let now = Instant::now();
+            let chunk = &mut state.chunks[chunk_ix];
+            chunk.attempts += 1;
+            let origins = chunk.first_origin_ix..chunk.first_origin_ix + chunk.origins_count;
+            match run_catching_panics(|| run_chunk(origins)) {
+                Ok(results) => {
+                    let path = chunk_path(output_dir, chunk_ix);
+                    let partial_path = format!("{}.partial", path);
+                    fs_err::write(&partial_path, results).unwrap();
+                    fs_err::rename(&partial_path, &path).unwrap();
+                    chunk.status = ChunkStatus::Done;
+                    chunk.last_error = None;
+                    println!("Chunk {} of {} done in {:?}", chunk_ix + 1, chunks_count, now.elapsed());
+                }
+                Err(message) => {
+                    println!("Chunk {} of {} failed: {}", chunk_ix + 1, chunks_count, message);
+                    chunk.status = ChunkStatus::Failed;
+                    chunk.last_error = Some(message);
+                }
+            }
+            state.save(output_dir);
+        }
+    }
+
+    let failed_chunks_count = state.chunks.iter().filter(|chunk| chunk.status != ChunkStatus::Done).count();
+    if failed_chunks_count > 0 {
+        println!(
+            "{} of {} chunks failed {} times: see {}/{} for their errors, and rerun to retry them",
+            failed_chunks_count, chunks_count, max_attempts, output_dir, NATIONAL_RUN_STATE_FILENAME
+        );
+        return;
+    }
+    write_national_output(output_dir, &state);
+}
+
+fn read_or_create_state(output_dir: &str, origins_count: usize, origins_per_chunk: usize) -> NationalRunState {
+    let path = format!("{}/{}", output_dir, NATIONAL_RUN_STATE_FILENAME);
+    if !Path::new(&path).exists() {
+        let state = NationalRunState::new(origins_count, origins_per_chunk);
+        state.save(output_dir);
+        return state;
+    }
+    let state: NationalRunState = serde_json::from_reader(BufReader::new(File::open(&path).unwrap())).unwrap();
+    if state.origins_count != origins_count || state.origins_per_chunk != origins_per_chunk {
+        panic!(
+            "{} holds a run of {} origins in chunks of {}: resume with the same template and chunk size, or use another output directory",
+            output_dir, state.origins_count, state.origins_per_chunk
+        );
+    }
+    let done_count = state.chunks.iter().filter(|chunk| chunk.status == ChunkStatus::Done).count();
+    println!("Resuming national run with {} of {} chunks done", done_count, state.chunks.len());
+    state
+}
+
+// One chunk in memory at a time, so the output can be bigger than memory
+fn write_national_output(output_dir: &str, state: &NationalRunState) {
+    let now = Instant::now();
+    let path = format!("{}/{}", output_dir, NATIONAL_OUTPUT_FILENAME);
+    let partial_path = format!("{}.partial", path);
+    let mut file = BufWriter::new(File::create(&partial_path).unwrap());
+    for (chunk_ix, chunk) in state.chunks.iter().enumerate() {
+        let results: Vec<Value> =
+            serde_json::from_reader(BufReader::new(File::open(chunk_path(output_dir, chunk_ix)).unwrap())).unwrap();
+        assert_eq!(results.len(), chunk.origins_count, "Chunk {} doesn't have one result per origin", chunk_ix);
+        for (i, mut result) in results.into_iter().enumerate() {
+            if let Value::Object(fields) = &mut result {
+                fields.insert("origin_ix".to_string(), Value::from(chunk.first_origin_ix + i));
+            }
+            serde_json::to_writer(&mut file, &result).unwrap();
+            file.write_all(b"\n").unwrap();
+        }
+    }
+    file.into_inner().unwrap().sync_all().unwrap();
+    fs_err::rename(&partial_path, &path).unwrap();
+    println!("Wrote {} results to {} in {:?}", state.origins_count, path, now.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn test_output_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("national_run_{}_{}", name, std::process::id()));
+        let _ = fs_err::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_string()
+    }
+
+    fn results_for(origins: Range<usize>) -> String {
+        let results: Vec<Value> = origins.map(|origin| serde_json::json!({ "start_node": origin * 10 })).collect();
+        serde_json::to_string(&results).unwrap()
+    }
+
+    fn read_state(output_dir: &str) -> NationalRunState {
+        let path = format!("{}/{}", output_dir, NATIONAL_RUN_STATE_FILENAME);
+        serde_json::from_reader(BufReader::new(File::open(path).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn resumed_runs_only_run_chunks_not_done() {
+        let output_dir = test_output_dir("resume");
+        let output_path = format!("{}/{}", output_dir, NATIONAL_OUTPUT_FILENAME);
+
+        // The chunk of origins 2 and 3 fails every attempt, so there's no output yet
+        run_national(&output_dir, 5, 2, 2, |origins| match origins.start {
+            2 => Err("out of memory".to_string()),
+            _ => Ok(results_for(origins)),
+        });
+        let state = read_state(&output_dir);
+        let statuses: Vec<ChunkStatus> = state.chunks.iter().map(|chunk| chunk.status).collect();
+        assert_eq!(statuses, vec![ChunkStatus::Done, ChunkStatus::Failed, ChunkStatus::Done]);
+        assert_eq!(state.chunks[1].attempts, 2);
+        assert_eq!(state.chunks[1].last_error.as_deref(), Some("out of memory"));
+        assert!(!Path::new(&output_path).exists());
+
+        let chunks_run = RefCell::new(Vec::new());
+        run_national(&output_dir, 5, 2, 2, |origins| {
+            chunks_run.borrow_mut().push(origins.clone());
+            Ok(results_for(origins))
+        });
+        assert_eq!(chunks_run.into_inner(), vec![2..4]);
+        let state = read_state(&output_dir);
+        assert!(state.chunks.iter().all(|chunk| chunk.status == ChunkStatus::Done));
+        assert_eq!(state.chunks[1].attempts, 3);
+        assert_eq!(state.chunks[1].last_error, None);
+
+        let output_lines: Vec<Value> = fs_err::read_to_string(&output_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let expected: Vec<Value> =
+            (0..5).map(|origin| serde_json::json!({ "start_node": origin * 10, "origin_ix": origin })).collect();
+        assert_eq!(output_lines, expected);
+
+        fs_err::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn failed_chunks_are_retried_after_the_others() {
+        let output_dir = test_output_dir("retry");
+        let chunks_run = RefCell::new(Vec::new());
+        run_national(&output_dir, 3, 1, 3, |origins| {
+            chunks_run.borrow_mut().push(origins.start);
+            if origins.start == 0 && chunks_run.borrow().len() == 1 {
+                panic!("first attempt fails");
+            }
+            Ok(results_for(origins))
+        });
+        assert_eq!(chunks_run.into_inner(), vec![0, 1, 2, 0]);
+        assert_eq!(read_state(&output_dir).chunks[0].attempts, 2);
+        fs_err::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "resume with the same template and chunk size")]
+    fn resuming_with_another_chunk_size_panics() {
+        let output_dir = test_output_dir("mismatch");
+        run_national(&output_dir, 4, 2, 1, |_| Err("stopped".to_string()));
+        run_national(&output_dir, 4, 3, 1, |origins| Ok(results_for(origins)));
+    }
+}
@@ -0,0 +1,75 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+// Lines held between the floodfills and the client. Once this many are waiting, floodfills block until
+// the client reads more, so memory stays bounded however many start nodes there are
+const NDJSON_BUFFERED_LINES: usize = 64;
+
+// One line per start node, tagged with its index as lines arrive in the order floodfills finish
+#[derive(Serialize)]
+pub struct NdjsonLine<'a, T: Serialize> {
+    pub origin_ix: usize,
+    #[serde(flatten)]
+    pub result: &'a T,
+}
+
+#[derive(Clone)]
+pub struct NdjsonSender {
+    sender: Sender<Bytes>,
+}
+
+impl NdjsonSender {
+    // Blocks while the buffer is full, so only call from outside the async runtime, eg from
+    // ndjson_thread_pool. Returns false once the client has gone, so the caller can stop
+    pub fn send<T: Serialize>(&self, origin_ix: usize, result: &T) -> bool {
+        let mut line = serde_json::to_vec(&NdjsonLine { origin_ix, result }).unwrap();
+        line.push(b'\n');
+        self.sender.blocking_send(Bytes::from(line)).is_ok()
+    }
+}
+
+pub struct NdjsonBody {
+    receiver: Receiver<Bytes>,
+}
+
+impl MessageBody for NdjsonBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.get_mut().receiver.poll_recv(cx).map(|line| line.map(Ok))
+    }
+}
+
+// Streamed floodfills run on this pool rather than rayon's global one, as a client that stops reading
+// blocks the threads sending to it: this way it can only hold up other streams, not every request
+pub fn ndjson_thread_pool() -> &'static ThreadPool {
+    static NDJSON_THREAD_POOL: OnceLock<ThreadPool> = OnceLock::new();
+    NDJSON_THREAD_POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .thread_name(|thread_ix| format!("ndjson-{}", thread_ix))
+            .build()
+            .unwrap()
+    })
+}
+
+// The body ends when every clone of the sender has been dropped
+pub fn ndjson_channel() -> (NdjsonSender, NdjsonBody) {
+    let (sender, receiver) = channel(NDJSON_BUFFERED_LINES);
+    (NdjsonSender { sender }, NdjsonBody { receiver })
+}
+
+pub fn ndjson_response(body: NdjsonBody) -> HttpResponse {
+    HttpResponse::Ok().content_type("application/x-ndjson").body(body)
+}
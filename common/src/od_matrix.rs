@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::structs::{Cost, NodeID};
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OdMatrixFormat {
+    #[default]
+    Dense,
+    Sparse,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OdMatrixRequest {
+    #[serde(default)]
+    pub format: OdMatrixFormat,
+}
+
+// Rows follow the start nodes and columns destination_nodes, in the order they were requested, so
+// results can be joined by index onto the tables they came from
+#[derive(Serialize)]
+pub struct OdMatrixOutput {
+    pub origins: Vec<NodeID>,
+    pub destinations: Vec<NodeID>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<Vec<Vec<Option<Cost>>>>,   // dense: [origin_ix][destination_ix], null where unreachable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<[usize; 3]>>,   // sparse: [origin_ix, destination_ix, cost] of reachable pairs only
+}
+
+impl OdMatrixOutput {
+    pub fn new(
+        origins: &[NodeID],
+        destinations: &[NodeID],
+        matrix: Vec<Vec<Option<Cost>>>,
+        format: OdMatrixFormat,
+    ) -> Self {
+        let (matrix, entries) = match format {
+            OdMatrixFormat::Dense => (Some(matrix), None),
+            OdMatrixFormat::Sparse => {
+                let mut entries = Vec::new();
+                for (origin_ix, row) in matrix.iter().enumerate() {
+                    for (destination_ix, cost) in row.iter().enumerate() {
+                        if let Some(cost) = cost {
+                            entries.push([origin_ix, destination_ix, cost.0]);
+                        }
+                    }
+                }
+                (None, Some(entries))
+            }
+        };
+        OdMatrixOutput {
+            origins: origins.to_vec(),
+            destinations: destinations.to_vec(),
+            matrix,
+            entries,
+        }
+    }
+}
+
+// One row per origin from its floodfill's od_pairs_found ([cost, node]). Destinations can be repeated
+pub fn od_matrix_from_od_pairs<'a>(
+    od_pairs_found_by_origin: impl Iterator<Item = &'a Vec<[usize; 2]>>,
+    destinations: &[NodeID],
+) -> Vec<Vec<Option<Cost>>> {
+    let mut destination_ixs_by_node: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (destination_ix, destination) in destinations.iter().enumerate() {
+        destination_ixs_by_node.entry(destination.0).or_default().push(destination_ix);
+    }
+
+    od_pairs_found_by_origin
+        .map(|od_pairs_found| {
+            let mut row = vec![None; destinations.len()];
+            for [cost, node] in od_pairs_found.iter() {
+                if let Some(destination_ixs) = destination_ixs_by_node.get(node) {
+                    for destination_ix in destination_ixs.iter() {
+                        row[*destination_ix] = Some(Cost(*cost));
+                    }
+                }
+            }
+            row
+        })
+        .collect()
+}
@@ -1,27 +1,33 @@
 use fs_err::File;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::time::Instant;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::Path;
 
-use crate::structs::{Multiplier, NodeRoute, NodeWalk, SubpurposeScore, NodeWalkCyclingCar, SUBPURPOSES_COUNT, SubpurposeSmallMediumLargeCount};
+use crate::contraction_hierarchy::ContractionHierarchy;
+use crate::turn_costs::{TurnCostProfiles, TURN_COST_PROFILES_VERSION};
+use crate::structs::{
+    set_cost_width_bytes, BincodeFileHeader, CostUnits, BINCODE_FILE_MAGIC, BINCODE_FORMAT_VERSION, COST_WIDTH_BYTES,
+    LEGACY_COST_WIDTH_BYTES, LinkCostProfiles, Multiplier, SecondsPastMidnight, TimePeriod, TimePeriods, TIME_PERIODS_VERSION, NodeRoute, NodeWalk, PurposeTaxonomy, SubpurposeScore, NodeWalkCyclingCar, NodeWalkCyclingCarWithoutDistances, SubpurposeSmallMediumLargeCount, ZoneStartNode, PURPOSE_TAXONOMY_VERSION};
 
-pub fn read_files_serial_walk_cycling_car(mode: &String, time_of_day: usize) -> (Vec<Multiplier>, Vec<Vec<SubpurposeScore>>, Vec<NodeWalkCyclingCar>) {
+pub fn read_files_serial_walk_cycling_car(mode: &String, file_suffix: &str, subpurposes_count: usize) -> (Vec<Multiplier>, Vec<Vec<SubpurposeScore>>, Vec<NodeWalkCyclingCar>) {
 
     let mut travel_time_relationships: Vec<Multiplier> = Vec::new();
     let mut graph: Vec<NodeWalkCyclingCar> = Vec::new();
     let mut sparse_node_values: Vec<Vec<SubpurposeScore>> = Vec::new();
     
+    graph = read_graph_walk_cycling_car_for_period(mode, file_suffix);
     if mode == "car" {
-        graph = deserialize_bincoded_file(&format!("graph_{}_{}", &mode, time_of_day));
-        sparse_node_values = deserialize_bincoded_file(&format!("sparse_node_values_{}_{}", &mode, time_of_day));    
+        sparse_node_values = read_sparse_node_values(&format!("sparse_node_values_{}_{}", &mode, file_suffix), subpurposes_count);
     }
     
     else {
-        graph = deserialize_bincoded_file(&format!("graph_{}", &mode));
-        sparse_node_values = deserialize_bincoded_file(&format!("sparse_node_values_{}", &mode));    
+        sparse_node_values = read_sparse_node_values(&format!("sparse_node_values_{}", &mode), subpurposes_count);
     }
     
-    travel_time_relationships = deserialize_bincoded_file(&format!("{}_travel_time_relationships_{}", mode, time_of_day));
+    travel_time_relationships = deserialize_bincoded_file(&format!("{}_travel_time_relationships_{}", mode, file_suffix));
     
     (
         travel_time_relationships,
@@ -30,6 +36,43 @@ pub fn read_files_serial_walk_cycling_car(mode: &String, time_of_day: usize) ->
     )
 }
 
+// Only car graphs vary by time period
+pub fn read_graph_walk_cycling_car_for_period(mode: &str, file_suffix: &str) -> Vec<NodeWalkCyclingCar> {
+    if mode == "car" {
+        read_graph_walk_cycling_car(&format!("graph_{}_{}", mode, file_suffix))
+    } else {
+        read_graph_walk_cycling_car(&format!("graph_{}", mode))
+    }
+}
+
+// Named like the graph it was built from. Optional: None if build_contraction_hierarchies hasn't been run
+pub fn read_contraction_hierarchy(mode: &str, file_suffix: &str) -> Option<ContractionHierarchy> {
+    let filename = contraction_hierarchy_filename(mode, file_suffix);
+    if !Path::new(&format!("serialised_data/{}.bin", filename)).exists() {
+        println!("No contraction hierarchy {}", filename);
+        return None;
+    }
+    Some(deserialize_bincoded_file(&filename))
+}
+
+pub fn contraction_hierarchy_filename(mode: &str, file_suffix: &str) -> String {
+    if mode == "car" {
+        format!("contraction_hierarchy_{}_{}", mode, file_suffix)
+    } else {
+        format!("contraction_hierarchy_{}", mode)
+    }
+}
+
+// Optional: None if no coordinates were serialised for the mode's graph
+pub fn read_node_longlat_lookup_walk_cycling_car(mode: &str) -> Option<Vec<[f64; 2]>> {
+    let filename = format!("{}_nodes_long_lat", mode);
+    if !Path::new(&format!("serialised_data/{}.bin", filename)).exists() {
+        println!("No coordinates for the {} graph", mode);
+        return None;
+    }
+    Some(deserialize_bincoded_file(&filename))
+}
+
 // read stop_rail_statuses_2022 as binary: standard deserialisation may be fine
 pub fn read_stop_rail_statuses(year: i32) -> Vec<bool> {
     let stop_rail_statuses: Vec<bool> =
@@ -37,6 +80,11 @@ pub fn read_stop_rail_statuses(year: i32) -> Vec<bool> {
     stop_rail_statuses
 }
 
+// Made by do_serialisation from the graphs for each time of day period
+pub fn read_link_cost_profiles(mode: &str) -> LinkCostProfiles {
+    deserialize_bincoded_file(&format!("{}_link_cost_profiles", mode))
+}
+
 pub fn read_car_nodes_is_closest_to_pt() -> Vec<bool> {
     let car_nodes_is_closest_to_pt: Vec<bool> =
         deserialize_bincoded_file(&format!("car_nodes_is_closest_to_pt"));
@@ -51,10 +99,18 @@ pub fn read_small_medium_large_subpurpose_destinations(mode_chosen: &str) -> Vec
     small_medium_large_subpurpose_destinations
 }
 
-pub fn read_sparse_node_values_2d_serial(year: i32) -> Vec<Vec<SubpurposeScore>> {
+// zone_type is eg 'LSOA' or 'OA'; mode_chosen is the graph the zone's start nodes are on, as per read_small_medium_large_subpurpose_destinations
+pub fn read_zone_definitions(zone_type: &str, mode_chosen: &str) -> HashMap<String, Vec<ZoneStartNode>> {
+    let now = Instant::now();
+    let zone_definitions: HashMap<String, Vec<ZoneStartNode>> =
+        deserialize_bincoded_file(&format!("zone_start_nodes_{}_{}", zone_type, mode_chosen));
+    println!("Reading {} zone definitions for {} took {:?}", zone_type, mode_chosen, now.elapsed());
+    zone_definitions
+}
+
+pub fn read_sparse_node_values_2d_serial(year: i32, subpurposes_count: usize) -> Vec<Vec<SubpurposeScore>> {
     let now = Instant::now();
-    let sparse_node_values_2d: Vec<Vec<SubpurposeScore>> =
-        deserialize_bincoded_file(&format!("sparse_node_values_6am_{year}_2d"));
+    let sparse_node_values_2d = read_sparse_node_values(&format!("sparse_node_values_6am_{year}_2d"), subpurposes_count);
     println!("Serial loading took {:?}", now.elapsed());
     sparse_node_values_2d
 }
@@ -65,18 +121,18 @@ pub fn read_rust_node_longlat_lookup_serial() -> Vec<[f64; 2]> {
     rust_node_longlat_lookup
 }
 
-pub fn read_files_parallel_inc_node_values(year: i32) -> (Vec<Vec<SubpurposeScore>>, Vec<NodeWalk>, Vec<NodeRoute>) {
+pub fn read_files_parallel_inc_node_values(year: i32, subpurposes_count: usize) -> (Vec<Vec<SubpurposeScore>>, Vec<NodeWalk>, Vec<NodeRoute>) {
     let now = Instant::now();
     
     let (node_values_2d, (graph_walk, graph_routes)) = rayon::join(
-        || deserialize_bincoded_file::<Vec<Vec<SubpurposeScore>>>(&format!("sparse_node_values_6am_{year}_2d")),
+        || read_sparse_node_values(&format!("sparse_node_values_6am_{year}_2d"), subpurposes_count),
         || {
             rayon::join(
                 || {
-                    deserialize_bincoded_file::<Vec<NodeWalk>>(&format!("graph_pt_walk_6am_{year}"))
+                    read_graph_pt_walk(&format!("graph_pt_walk_6am_{year}"))
                 },
                 || {
-                    deserialize_bincoded_file::<Vec<NodeRoute>>(&format!("graph_pt_routes_6am_{year}"))
+                    read_graph_pt_routes(&format!("graph_pt_routes_6am_{year}"))
                 },
             )
         },
@@ -90,7 +146,7 @@ pub fn read_files_parallel_inc_node_values(year: i32) -> (Vec<Vec<SubpurposeScor
 }
 
 // TO TRY: possible speed improvement: do appending with rayon too, so both graphs are appended to in parallel
-pub fn read_files_extra_parallel_inc_node_values(year: i32) -> (Vec<Vec<SubpurposeScore>>, Vec<NodeWalk>, Vec<NodeRoute>) {
+pub fn read_files_extra_parallel_inc_node_values(year: i32, subpurposes_count: usize) -> (Vec<Vec<SubpurposeScore>>, Vec<NodeWalk>, Vec<NodeRoute>) {
     let now = Instant::now();
     
     // if editing: make sure you get the types being deserialised into right: the compiler may panic without telling you why if you do
@@ -100,25 +156,25 @@ pub fn read_files_extra_parallel_inc_node_values(year: i32) -> (Vec<Vec<Subpurpo
             rayon::join(
                 || {
                     rayon::join(
-                        || deserialize_bincoded_file::<Vec<NodeWalk>>(&format!("graph_pt_walk_chunk_1")),
-                        || deserialize_bincoded_file::<Vec<NodeWalk>>(&format!("graph_pt_walk_chunk_2")),
+                        || read_graph_pt_walk(&format!("graph_pt_walk_chunk_1")),
+                        || read_graph_pt_walk(&format!("graph_pt_walk_chunk_2")),
                     )
                 },
-                || deserialize_bincoded_file::<Vec<NodeWalk>>(&format!("graph_pt_walk_chunk_3")),
+                || read_graph_pt_walk(&format!("graph_pt_walk_chunk_3")),
             )
         },
         || {
             rayon::join(
                 || {
                     rayon::join(
-                        || deserialize_bincoded_file::<Vec<NodeRoute>>(&format!("graph_pt_routes_chunk_1")),
-                        || deserialize_bincoded_file::<Vec<NodeRoute>>(&format!("graph_pt_routes_chunk_2")),
+                        || read_graph_pt_routes(&format!("graph_pt_routes_chunk_1")),
+                        || read_graph_pt_routes(&format!("graph_pt_routes_chunk_2")),
                     )
                 },
                 || {
                     rayon::join(
-                        || deserialize_bincoded_file::<Vec<NodeRoute>>(&format!("graph_pt_routes_chunk_3")),
-                        || deserialize_bincoded_file::<Vec<Vec<SubpurposeScore>>>(&format!("sparse_node_values_6am_{year}_2d")),
+                        || read_graph_pt_routes(&format!("graph_pt_routes_chunk_3")),
+                        || read_sparse_node_values(&format!("sparse_node_values_6am_{year}_2d"), subpurposes_count),
                     )
                 },
             )
@@ -151,8 +207,8 @@ pub fn read_files_parallel_excluding_node_values(year: i32) -> (Vec<NodeWalk>, V
     let now = Instant::now();
 
     let (graph_walk, graph_routes) = rayon::join(
-        || deserialize_bincoded_file::<Vec<NodeWalk>>(&format!("graph_pt_walk_6am_{year}")),
-        || deserialize_bincoded_file::<Vec<NodeRoute>>(&format!("graph_pt_routes_6am_{year}")),
+        || read_graph_pt_walk(&format!("graph_pt_walk_6am_{year}")),
+        || read_graph_pt_routes(&format!("graph_pt_routes_6am_{year}")),
     );
 
     println!(
@@ -162,72 +218,261 @@ pub fn read_files_parallel_excluding_node_values(year: i32) -> (Vec<NodeWalk>, V
     (graph_walk, graph_routes)
 }
 
-pub fn read_small_files_serial() -> (
-    Vec<Multiplier>,
-    Vec<Multiplier>,
-    Vec<Multiplier>,
-    Vec<Multiplier>,
-    [usize; SUBPURPOSES_COUNT],
-) {
+// One travel_time_relationships per time period, indexed as TimePeriods::periods
+pub fn read_travel_time_relationships_by_period(time_periods: &TimePeriods) -> Vec<Vec<Multiplier>> {
     let now = Instant::now();
-
-    let travel_time_relationships_7: Vec<Multiplier> =
-        deserialize_bincoded_file("travel_time_relationships_7");
-    let travel_time_relationships_10: Vec<Multiplier> =
-        deserialize_bincoded_file("travel_time_relationships_10");
-    let travel_time_relationships_16: Vec<Multiplier> =
-        deserialize_bincoded_file("travel_time_relationships_16");
-    let travel_time_relationships_19: Vec<Multiplier> =
-        deserialize_bincoded_file("travel_time_relationships_19");
-    
-    let subpurpose_purpose_lookup: [usize; SUBPURPOSES_COUNT] = 
-        read_vec_as_array_usize("subpurpose_to_purpose_integer");
-
+    let travel_time_relationships_all = (0..time_periods.periods_count())
+        .map(|period_ix| {
+            deserialize_bincoded_file(&format!(
+                "travel_time_relationships_{}",
+                time_periods.file_suffix(period_ix, "PT")
+            ))
+        })
+        .collect();
     println!("Serial loading took {:?}", now.elapsed());
-    (
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-        subpurpose_purpose_lookup,
-    )
+    travel_time_relationships_all
 }
 
+// Files written by write_bincoded_file() start with a BincodeFileHeader giving the width of their
+// costs. Files without one are legacy files, with costs in seconds saved as u16
 pub fn deserialize_bincoded_file<T: DeserializeOwned>(filename: &str) -> T {
     let path = format!("serialised_data/{}.bin", filename);
-    let file = BufReader::new(File::open(path).unwrap());
-    bincode::deserialize_from(file).unwrap()
+    let mut file = BufReader::new(File::open(path).unwrap());
+    let header = read_header(&mut file, filename);
+
+    let previous_cost_width_bytes = set_cost_width_bytes(header.cost_width_bytes);
+    let value = bincode::deserialize_from(file);
+    set_cost_width_bytes(previous_cost_width_bytes);
+    value.unwrap()
 }
 
+// For files whose costs must be in cost_units, eg graphs in seconds. A file with other units would read
+// without error and give nonsense costs, so panics instead. Legacy files are in seconds
+pub fn deserialize_bincoded_file_in_units<T: DeserializeOwned>(filename: &str, cost_units: CostUnits) -> T {
+    check_cost_units(&read_bincoded_file_header(filename), filename, cost_units);
+    deserialize_bincoded_file(filename)
+}
 
-pub fn read_vec_as_array_usize(filename: &str) -> [usize; SUBPURPOSES_COUNT] {
-    let inpath = format!("serialised_data/{}.json", filename);
-    let contents = fs_err::read_to_string(&inpath).unwrap();
-    let output_vector: Vec<usize> = serde_json::from_str(&contents).unwrap();
-    
-    let mut output: [usize; SUBPURPOSES_COUNT] = [0; 33];
-    for (index, value) in output_vector.iter().enumerate() {
-        output[index] = *value;
+fn check_cost_units(header: &BincodeFileHeader, filename: &str, cost_units: CostUnits) {
+    if header.cost_units != cost_units {
+        panic!(
+            "{} has costs in {:?} but {:?} were expected",
+            filename, header.cost_units, cost_units
+        );
     }
-    return output
 }
 
-pub fn read_vec_as_array_multiplier(filename: &str) -> [Multiplier; SUBPURPOSES_COUNT] {
-    let inpath = format!("serialised_data/{}.json", filename);
-    let contents = fs_err::read_to_string(&inpath).unwrap();
-    let output_vector: Vec<Multiplier> = serde_json::from_str(&contents).unwrap();
-    
-    let mut output: [Multiplier; SUBPURPOSES_COUNT] = [Multiplier(0.0); 33];
-    for (index, value) in output_vector.iter().enumerate() {
-        output[index] = *value;
+// Reads just the header, eg to choose which type to deserialise the rest of the file as
+pub fn read_bincoded_file_header(filename: &str) -> BincodeFileHeader {
+    let path = format!("serialised_data/{}.bin", filename);
+    let mut file = BufReader::new(File::open(path).unwrap());
+    read_header(&mut file, filename)
+}
+
+// Legacy files get a header with format_version 0
+fn read_header(file: &mut BufReader<File>, filename: &str) -> BincodeFileHeader {
+    if !file.fill_buf().unwrap().starts_with(&BINCODE_FILE_MAGIC) {
+        return BincodeFileHeader {
+            magic: BINCODE_FILE_MAGIC,
+            format_version: 0,
+            cost_width_bytes: LEGACY_COST_WIDTH_BYTES,
+            cost_units: CostUnits::Seconds,
+        };
+    }
+    let header: BincodeFileHeader = bincode::deserialize_from(file).unwrap();
+    assert!(
+        header.format_version <= BINCODE_FORMAT_VERSION,
+        "{} is bincode format version {} but this code reads up to version {}",
+        filename,
+        header.format_version,
+        BINCODE_FORMAT_VERSION
+    );
+    header
+}
+
+// Edge costs are seconds; distances, where there are any, are always metres. Graphs before format
+// version 2 have no edge distances
+pub fn read_graph_walk_cycling_car(filename: &str) -> Vec<NodeWalkCyclingCar> {
+    let header = read_bincoded_file_header(filename);
+    check_cost_units(&header, filename, CostUnits::Seconds);
+    if header.format_version >= 2 {
+        deserialize_bincoded_file(filename)
+    } else {
+        let graph: Vec<NodeWalkCyclingCarWithoutDistances> = deserialize_bincoded_file(filename);
+        graph.into_iter().map(NodeWalkCyclingCar::from).collect()
+    }
+}
+
+// PT graph costs are seconds
+pub fn read_graph_pt_walk(filename: &str) -> Vec<NodeWalk> {
+    deserialize_bincoded_file_in_units(filename, CostUnits::Seconds)
+}
+
+pub fn read_graph_pt_routes(filename: &str) -> Vec<NodeRoute> {
+    deserialize_bincoded_file_in_units(filename, CostUnits::Seconds)
+}
+
+// Every subpurpose_ix must index the purpose taxonomy's subpurposes, so the floodfills can index
+// subpurpose scores without checking
+pub fn read_sparse_node_values(filename: &str, subpurposes_count: usize) -> Vec<Vec<SubpurposeScore>> {
+    let sparse_node_values: Vec<Vec<SubpurposeScore>> = deserialize_bincoded_file(filename);
+    for (node, subpurpose_scores) in sparse_node_values.iter().enumerate() {
+        for subpurpose_score in subpurpose_scores.iter() {
+            if subpurpose_score.subpurpose_ix >= subpurposes_count {
+                panic!(
+                    "{} has subpurpose_ix {} at node {} but the purpose taxonomy has {} subpurposes",
+                    filename, subpurpose_score.subpurpose_ix, node, subpurposes_count
+                );
+            }
+        }
+    }
+    sparse_node_values
+}
+
+// Writes serialised_data/{filename}.bin with a header, and costs saved as COST_WIDTH_BYTES
+pub fn write_bincoded_file<T: Serialize>(filename: &str, value: &T, cost_units: CostUnits) {
+    let path = format!("serialised_data/{}.bin", filename);
+    let mut file = BufWriter::new(File::create(path).unwrap());
+    let header = BincodeFileHeader {
+        magic: BINCODE_FILE_MAGIC,
+        format_version: BINCODE_FORMAT_VERSION,
+        cost_width_bytes: COST_WIDTH_BYTES,
+        cost_units,
+    };
+    bincode::serialize_into(&mut file, &header).unwrap();
+
+    let previous_cost_width_bytes = set_cost_width_bytes(COST_WIDTH_BYTES);
+    let result = bincode::serialize_into(&mut file, value);
+    set_cost_width_bytes(previous_cost_width_bytes);
+    result.unwrap();
+}
+
+
+// Modes without a profile in turn_cost_profiles.json, or every mode if there's no such file, use the
+// default profile for the mode
+pub fn read_turn_cost_profiles() -> TurnCostProfiles {
+    let inpath = "serialised_data/turn_cost_profiles.json";
+    if !Path::new(inpath).exists() {
+        println!("No {}: using the default turn cost profiles", inpath);
+        return TurnCostProfiles {
+            version: TURN_COST_PROFILES_VERSION,
+            profiles: HashMap::new(),
+        };
+    }
+    let contents = fs_err::read_to_string(inpath).unwrap();
+    let turn_cost_profiles: TurnCostProfiles = serde_json::from_str(&contents).unwrap();
+
+    if turn_cost_profiles.version != TURN_COST_PROFILES_VERSION {
+        panic!(
+            "{} is version {}; this code reads version {}",
+            inpath, turn_cost_profiles.version, TURN_COST_PROFILES_VERSION
+        );
+    }
+    for (mode, profile) in turn_cost_profiles.profiles.iter() {
+        if let Err(message) = profile.validate() {
+            panic!("Turn cost profile for {} in {}: {}", mode, inpath, message);
+        }
     }
-    return output
+    turn_cost_profiles
 }
 
+// Indexed by LinkID: only needed for turn cost profiles with a major road crossing penalty
+// Optional: None if no major roads were serialised for the mode's graph
+pub fn read_link_is_major_road(mode: &str) -> Option<Vec<bool>> {
+    let filename = format!("{}_link_is_major_road", mode);
+    if !Path::new(&format!("serialised_data/{}.bin", filename)).exists() {
+        println!("No major roads for the {} graph", mode);
+        return None;
+    }
+    Some(deserialize_bincoded_file(&filename))
+}
 
+// Falls back to the default periods if there's no time_periods.json. Panics if the periods of a day
+// type don't cover the day between them without gaps or overlaps
+pub fn read_time_periods() -> TimePeriods {
+    let inpath = "serialised_data/time_periods.json";
+    if !Path::new(inpath).exists() {
+        println!("No {}: using the default time periods", inpath);
+        return TimePeriods::make_default_instance();
+    }
+    let contents = fs_err::read_to_string(inpath).unwrap();
+    let time_periods: TimePeriods = serde_json::from_str(&contents).unwrap();
 
+    if time_periods.version != TIME_PERIODS_VERSION {
+        panic!(
+            "{} is version {}; this code reads version {}",
+            inpath, time_periods.version, TIME_PERIODS_VERSION
+        );
+    }
+    let mut periods_by_day_type: HashMap<&str, Vec<&TimePeriod>> = HashMap::new();
+    for period in time_periods.periods.iter() {
+        periods_by_day_type.entry(&period.day_type).or_default().push(period);
+    }
+    for (day_type, mut periods) in periods_by_day_type {
+        periods.sort_by_key(|period| period.start_seconds);
+        let mut end_of_previous_period = SecondsPastMidnight(0);
+        for period in periods {
+            if period.start_seconds != end_of_previous_period || period.end_seconds <= period.start_seconds {
+                panic!(
+                    "Time period {} for day type {} runs from {} to {}, but should start at {} and end after it starts",
+                    period.name, day_type, period.start_seconds.0, period.end_seconds.0, end_of_previous_period.0
+                );
+            }
+            end_of_previous_period = period.end_seconds;
+        }
+    }
 
+    println!("Read {} time periods", time_periods.periods_count());
+    time_periods
+}
 
+// Panics if the taxonomy is for a different version of this code, or is inconsistent with itself:
+// better to fail on startup than to silently mislabel scores
+pub fn read_purpose_taxonomy() -> PurposeTaxonomy {
+    let inpath = "serialised_data/purpose_taxonomy.json";
+    let contents = fs_err::read_to_string(inpath).unwrap();
+    let mut purpose_taxonomy: PurposeTaxonomy = serde_json::from_str(&contents).unwrap();
 
+    if purpose_taxonomy.version != PURPOSE_TAXONOMY_VERSION {
+        panic!(
+            "{} is version {}; this code reads version {}",
+            inpath, purpose_taxonomy.version, PURPOSE_TAXONOMY_VERSION
+        );
+    }
+    for subpurpose in purpose_taxonomy.subpurposes.iter() {
+        if subpurpose.purpose_ix >= purpose_taxonomy.purposes_count() {
+            panic!(
+                "Subpurpose {} has purpose_ix {} but there are only {} purposes",
+                subpurpose.name, subpurpose.purpose_ix, purpose_taxonomy.purposes_count()
+            );
+        }
+    }
+    for (mode, score_multipliers) in purpose_taxonomy.score_multipliers.iter() {
+        if score_multipliers.len() != purpose_taxonomy.subpurposes_count() {
+            panic!(
+                "{} score multipliers for mode {} but {} subpurposes",
+                score_multipliers.len(), mode, purpose_taxonomy.subpurposes_count()
+            );
+        }
+    }
+    // destination counts are stored as small, medium and large: see SubpurposeSmallMediumLargeCount
+    if purpose_taxonomy.size_bands.len() != 3 {
+        panic!(
+            "{} size bands in {}; destination counts have 3",
+            purpose_taxonomy.size_bands.len(), inpath
+        );
+    }
 
+    purpose_taxonomy.subpurpose_purpose_lookup = purpose_taxonomy
+        .subpurposes
+        .iter()
+        .map(|subpurpose| subpurpose.purpose_ix)
+        .collect();
 
+    println!(
+        "Read purpose taxonomy with {} purposes and {} subpurposes",
+        purpose_taxonomy.purposes_count(),
+        purpose_taxonomy.subpurposes_count()
+    );
+    purpose_taxonomy
+}
@@ -0,0 +1,258 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use typed_index_collections::TiVec;
+
+use crate::search_workspace::{PathIx, SearchWorkspace};
+use crate::spatial_index::haversine_metres;
+use crate::structs::{Angle, Cost, LinkID, NodeID, NodeWalkCyclingCar, RouteOutput, START_LINK_ID};
+use crate::turn_costs::{MajorRoads, TurnCostProfile};
+
+// Use with `BinaryHeap`. Since it's a max-heap, reverse the comparison to get the smallest estimate
+// first.
+#[derive(PartialEq, Eq, Clone)]
+struct RouteQueueItem {
+    estimated_total_cost: Cost,   // cost so far plus the heuristic's lower bound on the cost still to go
+    cost: Cost,
+    node: NodeID,
+    angle_arrived_from: Angle,
+    link_arrived_from: LinkID,
+    path_so_far: Option<PathIx>,   // nodes before this one, in the workspace's path arena
+}
+
+impl PartialOrd for RouteQueueItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RouteQueueItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ord = other.estimated_total_cost.cmp(&self.estimated_total_cost);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+        // The tie-breaker is arbitrary, based on the node
+        self.node.cmp(&other.node)
+    }
+}
+
+// Lower bound on the cost between two nodes from the straight line distance between them. The cost per
+// metre is the lowest of any edge in the graph (see min_cost_per_metre), so the bound never overestimates
+// and A* stays exact
+pub struct RouteHeuristic<'a> {
+    node_longlat_lookup: &'a TiVec<NodeID, [f64; 2]>,
+    cost_per_metre: f64,
+}
+
+// Scans every edge, so worth keeping for each graph rather than finding for every route
+pub fn min_cost_per_metre(
+    node_longlat_lookup: &TiVec<NodeID, [f64; 2]>,
+    graph: &TiVec<NodeID, NodeWalkCyclingCar>,
+    optimise_on_distance: bool,
+) -> f64 {
+    let mut cost_per_metre = f64::MAX;
+    for (node_id, node) in graph.iter_enumerated() {
+        for edge in node.edges.iter() {
+            let metres = haversine_metres(node_longlat_lookup[node_id], node_longlat_lookup[edge.to]);
+            // edges between nodes at the same coordinates put no bound on the cost per metre
+            if metres < 1.0 {
+                continue;
+            }
+            let edge_cost = if optimise_on_distance { edge.distance_metres.unwrap() } else { edge.cost };
+            cost_per_metre = cost_per_metre.min(edge_cost.0 as f64 / metres);
+        }
+    }
+    if cost_per_metre == f64::MAX {
+        cost_per_metre = 0.0;
+    }
+    cost_per_metre
+}
+
+impl<'a> RouteHeuristic<'a> {
+    pub fn new(node_longlat_lookup: &'a TiVec<NodeID, [f64; 2]>, cost_per_metre: f64) -> Self {
+        RouteHeuristic {
+            node_longlat_lookup,
+            cost_per_metre,
+        }
+    }
+
+    fn cost_to_go(&self, node: NodeID, target_node: NodeID) -> Cost {
+        let metres = haversine_metres(self.node_longlat_lookup[node], self.node_longlat_lookup[target_node]);
+        Cost((metres * self.cost_per_metre).floor() as usize)
+    }
+}
+
+// A* from start_node to target_node on a walk, cycling or car graph. Like the floodfill, each link is
+// taken at most once so turn costs are exact. Without a heuristic this is Dijkstra stopped at the target.
+// Turn costs add to seconds only: when optimising on distance, turns add no distance
+pub fn find_route_walk_cycling_car(
+    graph: &TiVec<NodeID, NodeWalkCyclingCar>,
+    workspace: &mut SearchWorkspace,
+    turn_cost_profile: &TurnCostProfile,
+    major_roads: Option<&MajorRoads>,
+    heuristic: Option<&RouteHeuristic>,
+    start_node: NodeID,
+    target_node: NodeID,
+    optimise_on_distance: bool,
+) -> Option<RouteOutput> {
+    workspace.start_search(graph.len());
+
+    let cost_to_go = |node: NodeID| match heuristic {
+        Some(heuristic) => heuristic.cost_to_go(node, target_node),
+        None => Cost(0),
+    };
+
+    let mut queue: BinaryHeap<RouteQueueItem> = BinaryHeap::new();
+    queue.push(RouteQueueItem {
+        estimated_total_cost: cost_to_go(start_node),
+        cost: Cost(0),
+        node: start_node,
+        angle_arrived_from: Angle(0),
+        link_arrived_from: START_LINK_ID,
+        path_so_far: None,
+    });
+
+    let mut iters: usize = 0;
+
+    while let Some(current) = queue.pop() {
+        if !workspace.visit_link(current.link_arrived_from) {
+            continue;
+        }
+        iters += 1;
+        let path_including_current =
+            workspace.extend_path(current.path_so_far, current.node, current.link_arrived_from);
+
+        if current.node == target_node {
+            let (nodes, mut link_ids) = workspace.path_nodes_and_links(path_including_current);
+            link_ids.remove(0);   // START_LINK_ID
+            return Some(RouteOutput {
+                start_node,
+                target_node,
+                nodes,
+                link_ids,
+                cost: current.cost,
+                geometry: None,   // added by the caller, which has the coordinates
+                iters,
+            });
+        }
+
+        for edge in graph[current.node].edges.iter() {
+            let new_cost = if optimise_on_distance {
+                current.cost + edge.distance_metres.unwrap()
+            } else {
+                let mut time_turn_previous_node =
+                    turn_cost_profile.cost_of_turn(edge.angle_leaving_node_from, current.angle_arrived_from);
+                if let Some(major_roads) = major_roads {
+                    if major_roads.is_crossing(current.link_arrived_from, current.node, edge.link_arrived_from) {
+                        time_turn_previous_node = time_turn_previous_node + turn_cost_profile.major_road_crossing_seconds;
+                    }
+                }
+                current.cost + edge.cost + time_turn_previous_node
+            };
+            queue.push(RouteQueueItem {
+                estimated_total_cost: new_cost + cost_to_go(edge.to),
+                cost: new_cost,
+                node: edge.to,
+                angle_arrived_from: edge.angle_arrived_from,
+                link_arrived_from: edge.link_arrived_from,
+                path_so_far: Some(path_including_current),
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::EdgeWalkCyclingCar;
+
+    const COLUMNS: usize = 8;
+    const ROWS: usize = 6;
+
+    // A grid of streets about 70m apart east-west and 110m north-south, with speeds varying from edge
+    // to edge so the quickest routes aren't the shortest
+    fn grid_graph() -> (TiVec<NodeID, NodeWalkCyclingCar>, TiVec<NodeID, [f64; 2]>) {
+        let node_longlat_lookup: TiVec<NodeID, [f64; 2]> = (0..ROWS * COLUMNS)
+            .map(|ix| [-1.0 + (ix % COLUMNS) as f64 * 0.001, 51.0 + (ix / COLUMNS) as f64 * 0.001])
+            .collect();
+        let mut graph: TiVec<NodeID, NodeWalkCyclingCar> =
+            (0..ROWS * COLUMNS).map(|_| NodeWalkCyclingCar { edges: Default::default() }).collect();
+        let mut links_count = 0;
+        for ix in 0..ROWS * COLUMNS {
+            let (column, row) = (ix % COLUMNS, ix / COLUMNS);
+            let mut neighbours = vec![];
+            if row + 1 < ROWS {
+                neighbours.push((ix + COLUMNS, 0));
+            }
+            if column + 1 < COLUMNS {
+                neighbours.push((ix + 1, 90));
+            }
+            if row > 0 {
+                neighbours.push((ix - COLUMNS, 180));
+            }
+            if column > 0 {
+                neighbours.push((ix - 1, 270));
+            }
+            for (to, bearing) in neighbours {
+                let metres = haversine_metres(node_longlat_lookup[NodeID(ix)], node_longlat_lookup[NodeID(to)]);
+                let metres_per_second = 5.0 + (links_count * 7 % 11) as f64;
+                graph[NodeID(ix)].edges.push(EdgeWalkCyclingCar {
+                    to: NodeID(to),
+                    cost: Cost((metres / metres_per_second).ceil() as usize),
+                    angle_leaving_node_from: Angle(bearing),
+                    angle_arrived_from: Angle(bearing),
+                    link_arrived_from: LinkID(links_count),
+                    distance_metres: Some(Cost(metres.round() as usize)),
+                });
+                links_count += 1;
+            }
+        }
+        (graph, node_longlat_lookup)
+    }
+
+    #[test]
+    fn a_star_costs_match_dijkstra() {
+        let (graph, node_longlat_lookup) = grid_graph();
+        let turn_cost_profile = TurnCostProfile::make_default_instance("car");
+        let mut workspace = SearchWorkspace::new(graph.len());
+
+        for optimise_on_distance in [false, true] {
+            let cost_per_metre = min_cost_per_metre(&node_longlat_lookup, &graph, optimise_on_distance);
+            assert!(cost_per_metre > 0.0);
+            let heuristic = RouteHeuristic::new(&node_longlat_lookup, cost_per_metre);
+            let (mut a_star_iters, mut dijkstra_iters) = (0, 0);
+
+            for start in 0..graph.len() {
+                for target in 0..graph.len() {
+                    let (start_node, target_node) = (NodeID(start), NodeID(target));
+                    let mut find_route = |heuristic: Option<&RouteHeuristic>| {
+                        find_route_walk_cycling_car(
+                            &graph,
+                            &mut workspace,
+                            &turn_cost_profile,
+                            None,
+                            heuristic,
+                            start_node,
+                            target_node,
+                            optimise_on_distance,
+                        )
+                        .unwrap()
+                    };
+                    let a_star_route = find_route(Some(&heuristic));
+                    let dijkstra_route = find_route(None);
+                    assert_eq!(
+                        a_star_route.cost, dijkstra_route.cost,
+                        "from {} to {}, optimise_on_distance {}", start, target, optimise_on_distance
+                    );
+                    assert_eq!(a_star_route.nodes.first(), Some(&start_node));
+                    assert_eq!(a_star_route.nodes.last(), Some(&target_node));
+                    assert_eq!(a_star_route.link_ids.len(), a_star_route.nodes.len() - 1);
+                    a_star_iters += a_star_route.iters;
+                    dijkstra_iters += dijkstra_route.iters;
+                }
+            }
+            assert!(a_star_iters < dijkstra_iters, "{} A* iters, {} Dijkstra", a_star_iters, dijkstra_iters);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::cell::RefCell;
+use typed_index_collections::TiVec;
+
+use crate::structs::{LinkID, NodeID, START_LINK_ID};
+
+// Per thread state reused across floodfills, so each start node doesn't allocate and zero arrays the
+// size of the graph. A node or link is marked when its stamp equals the current generation, so
+// bumping the generation in start_search() clears every mark at once
+pub struct SearchWorkspace {
+    generation: u32,
+    node_visited: TiVec<NodeID, u32>,
+    node_is_destination: TiVec<NodeID, u32>,
+    link_visited: Vec<u32>,   // indexed by LinkID; grown as higher LinkIDs are seen
+    predecessors: TiVec<NodeID, NodeID>,   // only meaningful for nodes visited in the current search
+    path_nodes: Vec<NodeID>,   // arena of paths for the current search: each entry points to its parent
+    path_links: Vec<LinkID>,   // the link each path_nodes entry was reached by
+    path_parents: Vec<Option<PathIx>>,
+    path_depths: Vec<u32>,   // how many nodes each path has, so two paths can be walked back to where they meet
+    node_on_path: TiVec<NodeID, u32>,   // stamped with the generation while a node is on current_path
+    current_path: Option<PathIx>,
+    path_to_mark: Vec<PathIx>,   // scratch space for set_current_path
+}
+
+thread_local! {
+    static THREAD_WORKSPACE: RefCell<Option<SearchWorkspace>> = const { RefCell::new(None) };
+}
+
+// Runs search with this thread's workspace, made on first use. rayon's map_init and for_each_init make
+// a value for every split of the work, which can be many per thread, so floodfills run in parallel use
+// this to have one workspace per rayon worker. It lives as long as the thread, sized for the biggest
+// graph searched on it.
+// The workspace is taken out of the thread local while search runs, so if search calls into rayon and
+// this thread picks up another search meanwhile, that one gets a fresh workspace rather than a panic
+pub fn with_thread_workspace<T>(nodes_count: usize, search: impl FnOnce(&mut SearchWorkspace) -> T) -> T {
+    let mut workspace = THREAD_WORKSPACE
+        .with(|workspace| workspace.borrow_mut().take())
+        .unwrap_or_else(|| SearchWorkspace::new(nodes_count));
+    let result = search(&mut workspace);
+    THREAD_WORKSPACE.with(|thread_workspace| *thread_workspace.borrow_mut() = Some(workspace));
+    result
+}
+
+// An entry in the path arena, standing for the path from the start node to path_nodes[ix]. Sharing
+// parents means extending a path is O(1), rather than cloning every node before it
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct PathIx(u32);
+
+impl SearchWorkspace {
+    pub fn new(nodes_count: usize) -> Self {
+        SearchWorkspace {
+            generation: 0,
+            node_visited: vec![0; nodes_count].into(),
+            node_is_destination: vec![0; nodes_count].into(),
+            link_visited: vec![0; nodes_count],
+            predecessors: vec![NodeID(0); nodes_count].into(),
+            path_nodes: Vec::new(),
+            path_links: Vec::new(),
+            path_parents: Vec::new(),
+            path_depths: Vec::new(),
+            node_on_path: vec![0; nodes_count].into(),
+            current_path: None,
+            path_to_mark: Vec::new(),
+        }
+    }
+
+    // Call at the start of every floodfill. nodes_count can grow between calls, as the service change
+    // api adds nodes to the graph
+    pub fn start_search(&mut self, nodes_count: usize) {
+        if nodes_count > self.node_visited.len() {
+            self.node_visited.resize(nodes_count, 0);
+            self.node_is_destination.resize(nodes_count, 0);
+            self.predecessors.resize(nodes_count, NodeID(0));
+            self.node_on_path.resize(nodes_count, 0);
+        }
+        self.path_nodes.clear();
+        self.path_links.clear();
+        self.path_parents.clear();
+        self.path_depths.clear();
+        self.current_path = None;
+        self.generation = self.generation.wrapping_add(1);
+        // After 4 billion searches the stamps would repeat, so clear them for real
+        if self.generation == 0 {
+            self.node_visited.iter_mut().for_each(|stamp| *stamp = 0);
+            self.node_is_destination.iter_mut().for_each(|stamp| *stamp = 0);
+            self.link_visited.iter_mut().for_each(|stamp| *stamp = 0);
+            self.node_on_path.iter_mut().for_each(|stamp| *stamp = 0);
+            self.generation = 1;
+        }
+    }
+
+    pub fn is_node_visited(&self, node: NodeID) -> bool {
+        self.node_visited[node] == self.generation
+    }
+
+    // Returns false if the node was already visited in this search
+    pub fn visit_node(&mut self, node: NodeID) -> bool {
+        if self.node_visited[node] == self.generation {
+            return false;
+        }
+        self.node_visited[node] = self.generation;
+        true
+    }
+
+    // Returns false if the link was already visited in this search. START_LINK_ID is never marked
+    pub fn visit_link(&mut self, link: LinkID) -> bool {
+        if link == START_LINK_ID {
+            return true;
+        }
+        let ix = link.0 as usize;
+        if ix >= self.link_visited.len() {
+            self.link_visited.resize(ix + 1, 0);
+        }
+        if self.link_visited[ix] == self.generation {
+            return false;
+        }
+        self.link_visited[ix] = self.generation;
+        true
+    }
+
+    pub fn mark_destinations(&mut self, nodes: &[NodeID]) {
+        for node in nodes.iter() {
+            self.node_is_destination[*node] = self.generation;
+        }
+    }
+
+    pub fn is_destination(&self, node: NodeID) -> bool {
+        self.node_is_destination[node] == self.generation
+    }
+
+    pub fn set_predecessor(&mut self, node: NodeID, previous_node: NodeID) {
+        self.predecessors[node] = previous_node;
+    }
+
+    // None if the node hasn't been visited in this search
+    pub fn predecessor(&self, node: NodeID) -> Option<NodeID> {
+        self.is_node_visited(node).then(|| self.predecessors[node])
+    }
+
+    // A parent of None extends the empty path
+    pub fn extend_path(&mut self, parent: Option<PathIx>, node: NodeID, link_arrived_from: LinkID) -> PathIx {
+        let depth = self.path_depth(parent) + 1;
+        self.path_nodes.push(node);
+        self.path_links.push(link_arrived_from);
+        self.path_parents.push(parent);
+        self.path_depths.push(depth);
+        PathIx(self.path_nodes.len() as u32 - 1)
+    }
+
+    fn path_depth(&self, path: Option<PathIx>) -> u32 {
+        path.map_or(0, |PathIx(ix)| self.path_depths[ix as usize])
+    }
+
+    // Marks the nodes of path, so is_on_current_path() is O(1). Only the nodes that differ from the
+    // previous current path are touched: both are walked back to the entry they share, unmarking the old
+    // nodes and marking the new ones. Consecutive paths in a floodfill mostly share a long prefix
+    pub fn set_current_path(&mut self, path: Option<PathIx>) {
+        let mut old_path = self.current_path;
+        let mut new_path = path;
+        self.path_to_mark.clear();
+        while old_path != new_path {
+            if self.path_depth(old_path) >= self.path_depth(new_path) {
+                let PathIx(ix) = old_path.unwrap();
+                self.node_on_path[self.path_nodes[ix as usize]] = 0;
+                old_path = self.path_parents[ix as usize];
+            } else {
+                let PathIx(ix) = new_path.unwrap();
+                self.path_to_mark.push(PathIx(ix));
+                new_path = self.path_parents[ix as usize];
+            }
+        }
+        for PathIx(ix) in self.path_to_mark.iter() {
+            self.node_on_path[self.path_nodes[*ix as usize]] = self.generation;
+        }
+        self.current_path = path;
+    }
+
+    // Whether node is on the path last given to set_current_path()
+    pub fn is_on_current_path(&self, node: NodeID) -> bool {
+        self.node_on_path[node] == self.generation
+    }
+
+    // The nodes of a path from its first to its last, with the link each was reached by
+    pub fn path_nodes_and_links(&self, path: PathIx) -> (Vec<NodeID>, Vec<LinkID>) {
+        let mut nodes = Vec::new();
+        let mut links = Vec::new();
+        let mut path = Some(path);
+        while let Some(PathIx(ix)) = path {
+            nodes.push(self.path_nodes[ix as usize]);
+            links.push(self.path_links[ix as usize]);
+            path = self.path_parents[ix as usize];
+        }
+        nodes.reverse();
+        links.reverse();
+        (nodes, links)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_searches_on_one_thread_get_their_own_workspace() {
+        let visited = with_thread_workspace(4, |outer| {
+            outer.start_search(4);
+            outer.visit_node(NodeID(1));
+            let inner_visited = with_thread_workspace(8, |inner| {
+                inner.start_search(8);
+                inner.is_node_visited(NodeID(1))
+            });
+            (outer.is_node_visited(NodeID(1)), inner_visited)
+        });
+        assert_eq!(visited, (true, false));
+    }
+
+    #[test]
+    fn current_path_marks_follow_branching_paths() {
+        let mut workspace = SearchWorkspace::new(10);
+        workspace.start_search(10);
+        // 0-1-2-3 with a branch 1-4-5
+        let path_0 = workspace.extend_path(None, NodeID(0), START_LINK_ID);
+        let path_1 = workspace.extend_path(Some(path_0), NodeID(1), LinkID(0));
+        let path_2 = workspace.extend_path(Some(path_1), NodeID(2), LinkID(1));
+        let path_3 = workspace.extend_path(Some(path_2), NodeID(3), LinkID(2));
+        let path_4 = workspace.extend_path(Some(path_1), NodeID(4), LinkID(3));
+        let path_5 = workspace.extend_path(Some(path_4), NodeID(5), LinkID(4));
+        let on_path = |workspace: &SearchWorkspace| -> Vec<usize> {
+            (0..10).filter(|ix| workspace.is_on_current_path(NodeID(*ix))).collect()
+        };
+
+        workspace.set_current_path(Some(path_3));
+        assert_eq!(on_path(&workspace), vec![0, 1, 2, 3]);
+        workspace.set_current_path(Some(path_5));
+        assert_eq!(on_path(&workspace), vec![0, 1, 4, 5]);
+        workspace.set_current_path(Some(path_2));
+        assert_eq!(on_path(&workspace), vec![0, 1, 2]);
+        workspace.set_current_path(None);
+        assert_eq!(on_path(&workspace), Vec::<usize>::new());
+
+        workspace.set_current_path(Some(path_5));
+        workspace.start_search(10);
+        assert_eq!(on_path(&workspace), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn current_path_marks_cover_a_long_chain() {
+        let nodes_count = 100_000;
+        let mut workspace = SearchWorkspace::new(nodes_count);
+        workspace.start_search(nodes_count);
+        let mut path = None;
+        for ix in 0..nodes_count {
+            path = Some(workspace.extend_path(path, NodeID(ix), LinkID(ix as u32)));
+            workspace.set_current_path(path);
+            assert!(workspace.is_on_current_path(NodeID(0)));
+            assert!(workspace.is_on_current_path(NodeID(ix)));
+            assert!(ix + 1 == nodes_count || !workspace.is_on_current_path(NodeID(ix + 1)));
+        }
+        assert_eq!(workspace.path_nodes_and_links(path.unwrap()).0.len(), nodes_count);
+    }
+}
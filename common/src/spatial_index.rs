@@ -0,0 +1,212 @@
+use typed_index_collections::TiVec;
+
+use crate::structs::{Cost, NearestNode, NodeID};
+
+// Each grid cell is 0.01 degrees square: roughly 1.1km north-south and 650m east-west in England
+const GRID_CELL_SIZE_DEGREES: f64 = 0.01;
+const EARTH_RADIUS_METRES: f64 = 6_371_000.0;
+
+// Straight line distances undercount how far you have to walk on the network, so scale them up
+const WALKING_DETOUR_FACTOR: f64 = 1.3;
+const WALKING_SPEED_METRES_PER_SECOND: f64 = 1.33;
+
+// Uniform grid over the long/lat of every node. Each cell holds the NodeIDs whose coordinates fall inside it
+pub struct NodeGridIndex {
+    min_longitude: f64,
+    min_latitude: f64,
+    columns: usize,
+    rows: usize,
+    cells: Vec<Vec<NodeID>>,
+}
+
+impl NodeGridIndex {
+    pub fn new(rust_node_longlat_lookup: &TiVec<NodeID, [f64; 2]>) -> Self {
+        let mut min_longitude = f64::MAX;
+        let mut min_latitude = f64::MAX;
+        let mut max_longitude = f64::MIN;
+        let mut max_latitude = f64::MIN;
+        for [longitude, latitude] in rust_node_longlat_lookup.iter() {
+            min_longitude = min_longitude.min(*longitude);
+            min_latitude = min_latitude.min(*latitude);
+            max_longitude = max_longitude.max(*longitude);
+            max_latitude = max_latitude.max(*latitude);
+        }
+
+        // catch an empty lookup, so the grid below still has one cell
+        if rust_node_longlat_lookup.is_empty() {
+            min_longitude = 0.0;
+            min_latitude = 0.0;
+            max_longitude = 0.0;
+            max_latitude = 0.0;
+        }
+
+        let columns = 1 + ((max_longitude - min_longitude) / GRID_CELL_SIZE_DEGREES) as usize;
+        let rows = 1 + ((max_latitude - min_latitude) / GRID_CELL_SIZE_DEGREES) as usize;
+
+        let mut index = NodeGridIndex {
+            min_longitude,
+            min_latitude,
+            columns,
+            rows,
+            cells: vec![Vec::new(); columns * rows],
+        };
+
+        for (node_id, longlat) in rust_node_longlat_lookup.iter_enumerated() {
+            let (column, row) = index.cell_of(*longlat);
+            index.cells[row * index.columns + column].push(node_id);
+        }
+        index
+    }
+
+    // Clamps to the edge of the grid, so points outside the bounding box of all nodes still get a cell
+    fn cell_of(&self, longlat: [f64; 2]) -> (usize, usize) {
+        let column = ((longlat[0] - self.min_longitude) / GRID_CELL_SIZE_DEGREES).max(0.0) as usize;
+        let row = ((longlat[1] - self.min_latitude) / GRID_CELL_SIZE_DEGREES).max(0.0) as usize;
+        (column.min(self.columns - 1), row.min(self.rows - 1))
+    }
+
+    // Searches rings of cells outwards from the query point's cell, stopping once no unsearched cell
+    // can hold a node nearer than the nodes_count'th nearest found so far
+    pub fn find_nearest_nodes(
+        &self,
+        rust_node_longlat_lookup: &TiVec<NodeID, [f64; 2]>,
+        longlat: [f64; 2],
+        nodes_count: usize,
+    ) -> Vec<NearestNode> {
+        let mut nearest_nodes: Vec<NearestNode> = Vec::new();
+        if nodes_count == 0 {
+            return nearest_nodes;
+        }
+
+        let (centre_column, centre_row) = self.cell_of(longlat);
+        let max_ring = self.columns.max(self.rows);
+
+        for ring in 0..=max_ring {
+            if nearest_nodes.len() >= nodes_count {
+                let furthest_kept = nearest_nodes[nodes_count - 1].metres;
+                let nearest_possible_in_ring = (ring as f64 - 1.0).max(0.0) * min_metres_per_cell(longlat[1], ring);
+                if nearest_possible_in_ring > furthest_kept {
+                    break;
+                }
+            }
+
+            for (column, row) in self.cells_in_ring(centre_column, centre_row, ring) {
+                for node_id in self.cells[row * self.columns + column].iter() {
+                    let metres = haversine_metres(longlat, rust_node_longlat_lookup[*node_id]);
+                    nearest_nodes.push(NearestNode {
+                        node: *node_id,
+                        metres,
+                        seconds_walk: estimate_seconds_walk(metres),
+                    });
+                }
+            }
+
+            nearest_nodes.sort_by(|a, b| a.metres.total_cmp(&b.metres));
+            nearest_nodes.truncate(nodes_count);
+        }
+        nearest_nodes
+    }
+
+    // Cells exactly `ring` cells away (in either direction) from the centre cell, clipped to the grid
+    fn cells_in_ring(&self, centre_column: usize, centre_row: usize, ring: usize) -> Vec<(usize, usize)> {
+        let min_column = centre_column.saturating_sub(ring);
+        let max_column = (centre_column + ring).min(self.columns - 1);
+        let min_row = centre_row.saturating_sub(ring);
+        let max_row = (centre_row + ring).min(self.rows - 1);
+
+        let mut cells = Vec::new();
+        for row in min_row..=max_row {
+            for column in min_column..=max_column {
+                let on_ring = column.abs_diff(centre_column) == ring || row.abs_diff(centre_row) == ring;
+                if on_ring {
+                    cells.push((column, row));
+                }
+            }
+        }
+        cells
+    }
+}
+
+// The narrowest side of any cell up to `ring` cells from a point at this latitude. Longitude degrees
+// shrink towards the poles, so this is the east-west side at the most poleward latitude the ring
+// reaches, less 1% as haversine_metres follows the curve of the earth rather than the cell's sides
+fn min_metres_per_cell(latitude: f64, ring: usize) -> f64 {
+    let most_poleward_latitude = (latitude.abs() + (ring + 1) as f64 * GRID_CELL_SIZE_DEGREES).min(90.0);
+    0.99 * EARTH_RADIUS_METRES * GRID_CELL_SIZE_DEGREES.to_radians() * most_poleward_latitude.to_radians().cos()
+}
+
+pub fn haversine_metres(longlat_a: [f64; 2], longlat_b: [f64; 2]) -> f64 {
+    let latitude_a = longlat_a[1].to_radians();
+    let latitude_b = longlat_b[1].to_radians();
+    let delta_latitude = latitude_b - latitude_a;
+    let delta_longitude = (longlat_b[0] - longlat_a[0]).to_radians();
+
+    let a = (delta_latitude / 2.0).sin().powi(2)
+        + latitude_a.cos() * latitude_b.cos() * (delta_longitude / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METRES * a.sqrt().asin()
+}
+
+pub fn estimate_seconds_walk(metres: f64) -> Cost {
+    Cost((metres * WALKING_DETOUR_FACTOR / WALKING_SPEED_METRES_PER_SECOND).round() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nearest_node_ids(longlats: &[[f64; 2]], longlat: [f64; 2], nodes_count: usize) -> Vec<usize> {
+        let rust_node_longlat_lookup: TiVec<NodeID, [f64; 2]> = longlats.to_vec().into();
+        let index = NodeGridIndex::new(&rust_node_longlat_lookup);
+        index
+            .find_nearest_nodes(&rust_node_longlat_lookup, longlat, nodes_count)
+            .iter()
+            .map(|nearest_node| nearest_node.node.0)
+            .collect()
+    }
+
+    #[test]
+    fn haversine_matches_known_distances() {
+        assert_eq!(haversine_metres([-0.0553, 51.527], [-0.0553, 51.527]), 0.0);
+        // a degree of latitude anywhere, and of longitude on the equator, is the earth's radius in radians
+        let metres_per_degree = EARTH_RADIUS_METRES * 1.0_f64.to_radians();
+        assert!((haversine_metres([-1.5, 50.0], [-1.5, 51.0]) - metres_per_degree).abs() < 0.01);
+        assert!((haversine_metres([0.0, 0.0], [1.0, 0.0]) - metres_per_degree).abs() < 0.01);
+        // London to Manchester
+        let metres = haversine_metres([-0.1276, 51.5072], [-2.2426, 53.4808]);
+        assert!((metres - 262_000.0).abs() < 1_000.0, "{}", metres);
+    }
+
+    #[test]
+    fn ring_bound_is_below_the_distance_across_a_cell() {
+        // Two points either side of a cell, as far north as the ring reaches, at the latitudes of England
+        for latitude in [49.9, 51.5, 55.8] {
+            for ring in [1, 2, 10] {
+                let poleward_latitude = latitude + (ring + 1) as f64 * GRID_CELL_SIZE_DEGREES;
+                let across_cell = haversine_metres([0.0, poleward_latitude], [GRID_CELL_SIZE_DEGREES, poleward_latitude]);
+                assert!(min_metres_per_cell(latitude, ring) < across_cell);
+                assert!(min_metres_per_cell(latitude, ring) > 0.98 * across_cell);
+            }
+        }
+    }
+
+    #[test]
+    fn finds_a_nearer_node_just_over_a_cell_boundary() {
+        // The query is at the east edge of cell (0, 0). Node 1, one cell north, is a little further than
+        // node 2, just inside the cell two east, so the search mustn't stop before ring 2
+        let longlats = [[0.0, 0.0], [0.0099999, 0.015005], [0.02, 0.005]];
+        assert_eq!(nearest_node_ids(&longlats, [0.0099999, 0.005], 1), vec![2]);
+        assert_eq!(nearest_node_ids(&longlats, [0.0099999, 0.005], 3), vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn searches_past_empty_rings() {
+        // Nothing within 5 cells of the query, and more nodes asked for than there are
+        let longlats = [[-1.0, 51.0], [-0.95, 51.05], [-0.94, 51.05]];
+        assert_eq!(nearest_node_ids(&longlats, [-1.0, 51.0], 1), vec![0]);
+        assert_eq!(nearest_node_ids(&longlats, [-0.999, 51.0], 5), vec![0, 1, 2]);
+        assert_eq!(nearest_node_ids(&longlats, [-0.94, 51.049], 2), vec![2, 1]);
+        // Points outside the grid are searched from its nearest edge
+        assert_eq!(nearest_node_ids(&longlats, [-0.5, 51.2], 1), vec![2]);
+        assert_eq!(nearest_node_ids(&[], [-0.5, 51.2], 1), Vec::<usize>::new());
+    }
+}
@@ -2,15 +2,32 @@ use derive_more::{From, Into};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use smallvec::SmallVec;
 use std::cmp::{Ord, PartialEq, PartialOrd};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 use std::ops::{Add, AddAssign, SubAssign, Sub, Div};
 use std::fmt::{Debug};
+use std::cell::Cell;
+
+use crate::decay_functions::DecayFunction;
+use crate::od_matrix::OdMatrixRequest;
+use crate::turn_costs::TurnCostProfile;
 
 pub const TOP_CLUSTERS_COUNT: usize = 10;
-pub const PURPOSES_COUNT: usize = 6;
-pub const SUBPURPOSES_COUNT: usize = 33;
+pub const PURPOSE_TAXONOMY_VERSION: u32 = 1;
 pub const RAIL_MULTIPLIER: Cost = Cost(2);
+// Costs are no longer saved as u16 (see COST_WIDTH_BYTES), so the cap is no longer the width of costs:
+// PT travel times of up to RAIL_MULTIPLIER times this still fit easily in the 4 byte width. It bounds
+// the size of the per request decay tables instead, which have one multiplier per second per purpose
+pub const MAX_TRAVEL_TIME_SECONDS: Cost = Cost(24 * 3600);
+pub const DEFAULT_MAX_TRAVEL_TIME_SECONDS: Cost = Cost(3600);
+pub const TIME_PERIODS_VERSION: u32 = 1;
+pub const DEFAULT_DAY_TYPE: &str = "weekday";
+// Start of every bincode file written with a header. Files without it are read as legacy files
+pub const BINCODE_FILE_MAGIC: [u8; 8] = *b"RCONNBIN";
+// Version 2 added distance_metres to EdgeWalkCyclingCar
+pub const BINCODE_FORMAT_VERSION: u32 = 2;
+pub const LEGACY_COST_WIDTH_BYTES: u8 = 2;
+pub const COST_WIDTH_BYTES: u8 = 4;
 
 // Serializes a `usize` as a `u32` to save space. Useful when you need `usize` for indexing, and
 // the values don't exceed 2^32.
@@ -32,18 +49,57 @@ pub fn deserialize_usize<'de, D: Deserializer<'de>>(d: D) -> Result<usize, D::Er
     Ok(x as usize)
 }
 
-// Same as above but for serialising u32 as u16
-pub fn serialize_usize_as_u16<S: Serializer>(x: &usize, s: S) -> Result<S::Ok, S::Error> {
-    if let Ok(x) = u16::try_from(*x) {
+// Costs in bincode files are saved with the width given in the file's header (see BincodeFileHeader):
+// 2 bytes for files made before the header existed, so they still read, and 4 bytes for new files so
+// costs over 65535 (long rail legs, distances in metres) fit. The width is per thread, as files are
+// read on several threads at once. Human readable formats (eg: JSON requests and responses) ignore it
+thread_local! {
+    static CURRENT_COST_WIDTH_BYTES: Cell<u8> = const { Cell::new(LEGACY_COST_WIDTH_BYTES) };
+}
+
+pub fn get_cost_width_bytes() -> u8 {
+    CURRENT_COST_WIDTH_BYTES.with(|width| width.get())
+}
+
+// Returns the previous width, to restore once the file is read or written
+pub fn set_cost_width_bytes(cost_width_bytes: u8) -> u8 {
+    assert!(
+        cost_width_bytes == 2 || cost_width_bytes == 4,
+        "Cost width must be 2 or 4 bytes, not {}",
+        cost_width_bytes
+    );
+    CURRENT_COST_WIDTH_BYTES.with(|width| width.replace(cost_width_bytes))
+}
+
+pub fn serialize_cost<S: Serializer>(x: &usize, s: S) -> Result<S::Ok, S::Error> {
+    if s.is_human_readable() {
+        return (*x as u64).serialize(s);
+    }
+    if get_cost_width_bytes() == 4 {
+        if let Ok(x) = u32::try_from(*x) {
+            x.serialize(s)
+        } else {
+            Err(serde::ser::Error::custom(format!("{} can't fit in u32", x)))
+        }
+    } else if let Ok(x) = u16::try_from(*x) {
         x.serialize(s)
     } else {
         Err(serde::ser::Error::custom(format!("{} can't fit in u16", x)))
     }
 }
 
-pub fn deserialize_usize_as_u16<'de, D: Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
-    let x = <u16>::deserialize(d)?;
-    Ok(x as usize)
+pub fn deserialize_cost<'de, D: Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
+    if d.is_human_readable() {
+        let x = <u64>::deserialize(d)?;
+        return Ok(x as usize);
+    }
+    if get_cost_width_bytes() == 4 {
+        let x = <u32>::deserialize(d)?;
+        Ok(x as usize)
+    } else {
+        let x = <u16>::deserialize(d)?;
+        Ok(x as usize)
+    }
 }
 
 // NodeID is a usize, which is saved as u32 to save space
@@ -78,12 +134,12 @@ impl Sub for SecondsPastMidnight {
 }
 
 #[derive(
-    Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, From, Into,
+    Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default, From, Into,
 )]
 pub struct Cost(
     #[serde(
-        serialize_with = "serialize_usize_as_u16",
-        deserialize_with = "deserialize_usize_as_u16"
+        serialize_with = "serialize_cost",
+        deserialize_with = "deserialize_cost"
     )]
     pub usize,
 );
@@ -103,6 +159,10 @@ impl Div for Cost {
     }
 }
 
+fn default_max_travel_time_seconds() -> Cost {
+    DEFAULT_MAX_TRAVEL_TIME_SECONDS
+}
+
 impl SubAssign for Cost {
     fn sub_assign(&mut self, other: Self) {
         self.0 -= other.0;
@@ -123,7 +183,7 @@ impl From<SecondsPastMidnight> for Cost {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub struct Multiplier(pub f64);
 
 #[derive(Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy, Debug, From, Into)]
@@ -167,6 +227,9 @@ impl Default for Score {
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub struct LinkID(pub u32);
 
+// Stands in for the link arrived from at the start node of a floodfill
+pub const START_LINK_ID: LinkID = LinkID(99_999_999);
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)]
 pub struct Angle(pub u16);
 
@@ -197,6 +260,30 @@ pub struct EdgeWalkCyclingCar {
     pub angle_leaving_node_from: Angle,
     pub angle_arrived_from: Angle,
     pub link_arrived_from: LinkID,
+    pub distance_metres: Option<Cost>,   // None for graphs serialised without distances
+}
+
+// Layout of EdgeWalkCyclingCar in files before bincode format version 2, which had no distances
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct EdgeWalkCyclingCarWithoutDistance {
+    pub to: NodeID,
+    pub cost: Cost,
+    pub angle_leaving_node_from: Angle,
+    pub angle_arrived_from: Angle,
+    pub link_arrived_from: LinkID,
+}
+
+impl From<EdgeWalkCyclingCarWithoutDistance> for EdgeWalkCyclingCar {
+    fn from(edge: EdgeWalkCyclingCarWithoutDistance) -> Self {
+        EdgeWalkCyclingCar {
+            to: edge.to,
+            cost: edge.cost,
+            angle_leaving_node_from: edge.angle_leaving_node_from,
+            angle_arrived_from: edge.angle_arrived_from,
+            link_arrived_from: edge.link_arrived_from,
+            distance_metres: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
@@ -221,6 +308,138 @@ pub struct SubpurposeSmallMediumLargeCount {
     pub large_destinations_count: Score,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Subpurpose {
+    pub name: String,
+    pub purpose_ix: usize,
+}
+
+// Purposes (eg: education) and the subpurposes which make them up (eg: primary schools). Read from
+// purpose_taxonomy.json at startup, so destination types can be added without recompiling.
+// subpurpose_ix in node values and destination counts index into subpurposes
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PurposeTaxonomy {
+    pub version: u32,
+    pub purposes: Vec<String>,
+    pub subpurposes: Vec<Subpurpose>,
+    pub score_multipliers: HashMap<String, Vec<Multiplier>>,  // mode name -> one multiplier per subpurpose, to account for the average size of destinations
+    #[serde(default = "default_size_bands")]
+    pub size_bands: Vec<String>,  // names of the destination size bands in SubpurposeSmallMediumLargeCount, in the same order
+    #[serde(skip)]
+    pub subpurpose_purpose_lookup: Vec<usize>,  // made from subpurposes when the taxonomy is read: kept as a plain vec as it's used for every node reached
+}
+
+fn default_size_bands() -> Vec<String> {
+    vec!["small".to_string(), "medium".to_string(), "large".to_string()]
+}
+
+impl PurposeTaxonomy {
+    pub fn purposes_count(&self) -> usize {
+        self.purposes.len()
+    }
+
+    pub fn subpurposes_count(&self) -> usize {
+        self.subpurposes.len()
+    }
+
+    pub fn score_multipliers(&self, mode: &str) -> &[Multiplier] {
+        match self.score_multipliers.get(mode) {
+            Some(score_multipliers) => score_multipliers,
+            None => panic!("No score multipliers in purpose taxonomy for mode {}", mode),
+        }
+    }
+}
+
+// The time of day periods which travel time relationships, car graphs and link cost profiles are made
+// for. Read from time_periods.json at startup, so periods can be added without recompiling.
+// Periods are indexed by their position in periods, across every day type
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimePeriods {
+    pub version: u32,
+    pub periods: Vec<TimePeriod>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimePeriod {
+    pub name: String,                    // eg 'am_peak'
+    #[serde(default = "default_day_type")]
+    pub day_type: String,                // eg 'weekday' or 'weekend': the periods of each day type cover the day between them
+    pub start_seconds: SecondsPastMidnight,   // inclusive
+    pub end_seconds: SecondsPastMidnight,     // exclusive
+    pub file_suffixes: HashMap<String, String>,   // mode -> suffix of that mode's files for this period, eg 'car' -> '7' for graph_car_7
+}
+
+pub fn default_day_type() -> String {
+    DEFAULT_DAY_TYPE.to_string()
+}
+
+impl TimePeriods {
+    pub fn periods_count(&self) -> usize {
+        self.periods.len()
+    }
+
+    // Times after the end of the last period of the day type (eg: a floodfill running past midnight)
+    // are in that last period
+    pub fn period_index(&self, day_type: &str, seconds_past_midnight: SecondsPastMidnight) -> Option<usize> {
+        let mut last_period_ix = None;
+        for (period_ix, period) in self.periods.iter().enumerate() {
+            if period.day_type != day_type {
+                continue;
+            }
+            if period.start_seconds <= seconds_past_midnight && seconds_past_midnight < period.end_seconds {
+                return Some(period_ix);
+            }
+            last_period_ix = Some(period_ix);
+        }
+        last_period_ix
+    }
+
+    pub fn file_suffix(&self, period_ix: usize, mode: &str) -> &str {
+        let period = &self.periods[period_ix];
+        match period.file_suffixes.get(mode) {
+            Some(file_suffix) => file_suffix,
+            None => panic!("No file suffix for mode {} in time period {}", mode, period.name),
+        }
+    }
+
+    // Periods can share files, so each suffix is only returned once
+    pub fn unique_file_suffixes(&self, mode: &str) -> Vec<String> {
+        let mut file_suffixes: Vec<String> = Vec::new();
+        for period_ix in 0..self.periods_count() {
+            let file_suffix = self.file_suffix(period_ix, mode).to_string();
+            if !file_suffixes.contains(&file_suffix) {
+                file_suffixes.push(file_suffix);
+            }
+        }
+        file_suffixes
+    }
+
+    // The four weekday periods which used to be hard coded, for when there is no time_periods.json.
+    // These included their end hour (eg: 10:00 exactly was am peak), so later periods start a second after
+    pub fn make_default_instance() -> Self {
+        let start_of_hour = |hour: usize| if hour == 0 { 0 } else { hour * 3600 + 1 };
+        let period = |name: &str, start_hour: usize, end_hour: usize, suffix: &str| TimePeriod {
+            name: name.to_string(),
+            day_type: default_day_type(),
+            start_seconds: SecondsPastMidnight(start_of_hour(start_hour)),
+            end_seconds: SecondsPastMidnight(start_of_hour(end_hour)),
+            file_suffixes: [("PT", suffix), ("car", suffix), ("walk", "7"), ("cycling", "7")]
+                .iter()
+                .map(|(mode, suffix)| (mode.to_string(), suffix.to_string()))
+                .collect(),
+        };
+        TimePeriods {
+            version: TIME_PERIODS_VERSION,
+            periods: vec![
+                period("am_peak", 0, 10, "7"),
+                period("inter_peak", 10, 16, "10"),
+                period("pm_peak", 16, 19, "16"),
+                period("evening", 19, 24, "19"),
+            ],
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct NodeWalk {
     pub has_pt: bool,
@@ -232,6 +451,51 @@ pub struct NodeWalkCyclingCar {
     pub edges: SmallVec<[EdgeWalkCyclingCar; 4]>,
 }
 
+impl NodeWalkCyclingCar {
+    pub fn has_distances(&self) -> bool {
+        self.edges.iter().all(|edge| edge.distance_metres.is_some())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NodeWalkCyclingCarWithoutDistances {
+    pub edges: SmallVec<[EdgeWalkCyclingCarWithoutDistance; 4]>,
+}
+
+impl From<NodeWalkCyclingCarWithoutDistances> for NodeWalkCyclingCar {
+    fn from(node: NodeWalkCyclingCarWithoutDistances) -> Self {
+        NodeWalkCyclingCar {
+            edges: node.edges.into_iter().map(EdgeWalkCyclingCar::from).collect(),
+        }
+    }
+}
+
+
+// Seconds to cross each link in each time period of TimePeriods, so travel times can change as the
+// clock advances during a floodfill. One row of periods_count seconds per LinkID, one after the
+// other, saved as u32 rather than Cost to keep this compact as there is one row for every link
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LinkCostProfiles {
+    pub periods_count: usize,
+    pub seconds_by_link_and_period: Vec<u32>,
+}
+
+impl LinkCostProfiles {
+    pub fn links_count(&self) -> usize {
+        self.seconds_by_link_and_period.len() / self.periods_count
+    }
+
+    pub fn seconds(&self, link: LinkID, period_ix: usize) -> u32 {
+        self.seconds_by_link_and_period[link.0 as usize * self.periods_count + period_ix]
+    }
+
+    pub fn covers_graph(&self, graph: &[NodeWalkCyclingCar]) -> bool {
+        graph
+            .iter()
+            .flat_map(|node| node.edges.iter())
+            .all(|edge| (edge.link_arrived_from.0 as usize) < self.links_count())
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Copy)]
 pub struct EdgeRoute {
@@ -280,7 +544,7 @@ pub struct PreviousIterAndCurrentNodeId {
 pub struct FloodfillOutput {
     pub start_node_id: NodeID,
     pub seconds_walk_to_start_node: Cost,
-    pub purpose_scores: [Score;PURPOSES_COUNT],
+    pub purpose_scores: Vec<Score>,
     pub destinations_reached: Vec<DestinationReached>,
 }
 
@@ -288,25 +552,134 @@ pub struct FloodfillOutput {
 pub struct FloodfillOutputOriginDestinationPair {
     pub start_node_id: NodeID,
     pub seconds_walk_to_start_node: Cost,
-    pub purpose_scores: [Score; PURPOSES_COUNT],
+    pub purpose_scores: Vec<Score>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subpurpose_scores: Option<Vec<Score>>,         // before score multipliers and logging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subpurpose_scores_log: Option<Vec<Score>>,     // after: these are what purpose_scores sum
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose_scores_by_time_band: Option<Vec<Vec<Score>>>,   // one vec of purpose scores per time band: these sum to purpose_scores
     pub od_pairs_found: Vec<[usize; 2]>,
+    #[serde(skip)]
+    pub od_pairs_found_rail_adjusted: Vec<[usize; 2]>,   // as od_pairs_found, with the rail adjusted seconds scores are weighted by: for competition, so not returned
     pub iters: usize,
     pub nodes_reached_sequence: Vec<NodeID>,  // sequence of nodes reached en route to target_node, where specified
     pub nodes_reached_time_travelled: Vec<Cost>,
     pub final_cost: Cost,
-    pub destinations_reached_at_time_intervals: Vec<Vec<Vec<Score>>>,
+    pub destinations_reached_at_time_intervals: Vec<DestinationCountsAtTimeInterval>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessibility_metrics: Option<AccessibilityMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub competition_adjusted_scores: Option<CompetitionAdjustedScores>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FloodfillOutputOriginDestinationPairWalkCyclingCar {
     pub start_node_id: NodeID,
     pub seconds_walk_to_start_node: Cost,
-    pub purpose_scores: [Score; PURPOSES_COUNT],
+    pub purpose_scores: Vec<Score>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subpurpose_scores: Option<Vec<Score>>,         // before score multipliers and logging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subpurpose_scores_log: Option<Vec<Score>>,     // after: these are what purpose_scores sum
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose_scores_by_time_band: Option<Vec<Vec<Score>>>,   // one vec of purpose scores per time band: these sum to purpose_scores
     pub od_pairs_found: Vec<[usize; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub od_pairs_found_other_cost: Option<Vec<[usize; 2]>>,   // as od_pairs_found, with distance in metres if optimising on time or seconds if on distance
     pub iters: usize,
     pub nodes_reached_sequence: Vec<NodeID>,  // sequence of nodes reached en route to target_node, where specified
     pub final_cost: Cost,
-    pub destinations_reached_at_time_intervals: Vec<Vec<Vec<Score>>>,
+    pub destinations_reached_at_time_intervals: Vec<DestinationCountsAtTimeInterval>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessibility_metrics: Option<AccessibilityMetrics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub competition_adjusted_scores: Option<CompetitionAdjustedScores>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccessibilityMetricsRequest {
+    pub thresholds: Vec<Cost>,                  // seconds to count cumulative opportunities within
+    #[serde(default)]
+    pub nearest_destinations_count: usize,     // N, for the seconds to reach the nearest N destinations of each subpurpose
+}
+
+// Measures as per DfT Journey Time Statistics, all by subpurpose
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AccessibilityMetrics {
+    pub thresholds: Vec<Cost>,
+    pub cumulative_opportunities: Vec<Vec<Score>>,                // one vec per threshold, of opportunities reached within it
+    pub seconds_to_nearest_destinations: Vec<Vec<Option<Cost>>>,  // null where fewer than N destinations were reached
+    pub weighted_mean_seconds: Vec<Option<f64>>,                   // mean travel time to opportunities reached, weighted by opportunities
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompetitionRequest {
+    pub populations: Vec<f64>,     // one per start node, or one per zone if zone_ids are given
+}
+
+// Two step floating catchment scores: sums of supply / demand, so unlike purpose_scores these aren't logged
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CompetitionAdjustedScores {
+    pub subpurpose_scores: Vec<Score>,
+    pub purpose_scores: Vec<Score>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct NearestNode {
+    pub node: NodeID,
+    pub metres: f64,              // straight line distance from the requested point
+    pub seconds_walk: Cost,       // estimated walk time to the node: use as init_travel_times in floodfill requests
+}
+
+// What the costs in a bincode file measure, checked by readers which need particular units (see
+// deserialize_bincoded_file_in_units). Graphs hold seconds: their edge distances are a separate field,
+// always in metres, which the 4 byte cost width stores without scaling
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CostUnits {
+    Seconds,
+    Metres,
+}
+
+// Written at the start of bincode files, before the data itself
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct BincodeFileHeader {
+    pub magic: [u8; 8],
+    pub format_version: u32,
+    pub cost_width_bytes: u8,
+    pub cost_units: CostUnits,
+}
+
+// One of the nodes a zone (eg: LSOA or OA) starts from, with the walk time to reach it and its share of the zone's population
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ZoneStartNode {
+    pub node: NodeID,
+    pub seconds_walk_to_start_node: Cost,
+    pub weight: f64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct ZoneScores {
+    pub zone_id: String,
+    pub total_weight: f64,
+    pub purpose_scores: Vec<Score>,     // weighted mean of the purpose scores of the zone's start nodes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub competition_adjusted_purpose_scores: Option<Vec<Score>>,
+}
+
+// Counts of the destinations reached within time_interval seconds, for each subpurpose then size band
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DestinationCountsAtTimeInterval {
+    pub time_interval: Cost,
+    pub destination_counts: Vec<Vec<Score>>,
+}
+
+// Destination counts at one of original_time_intervals_to_store_destination_counts, keyed by
+// subpurpose name then size band name
+#[derive(Serialize, Clone)]
+pub struct NamedDestinationCounts {
+    pub time_interval: Cost,
+    pub destination_counts: BTreeMap<String, BTreeMap<String, Score>>,
 }
 
 #[derive(Serialize)]
@@ -314,8 +687,8 @@ pub struct PlanningToolOutput {
     pub start_node: NodeID,
     pub init_travel_time: Cost,
     pub num_iterations: u32,
-    pub key_destinations_per_purpose: [[[f64; 2]; TOP_CLUSTERS_COUNT];PURPOSES_COUNT],
-    pub per_link_score_per_purpose: Vec<[Score; PURPOSES_COUNT]>,
+    pub key_destinations_per_purpose: Vec<[[f64; 2]; TOP_CLUSTERS_COUNT]>,
+    pub per_link_score_per_purpose: Vec<Vec<Score>>,
     pub link_coordinates: Vec<Vec<String>>,
     pub link_is_pt: Vec<u8>,
     pub link_route_details: Vec<HashMap<String, String>>,
@@ -326,6 +699,21 @@ pub struct UserInputJSON {
     pub start_nodes_user_input: Vec<NodeID>,
     pub init_travel_times_user_input: Vec<Cost>,
     pub trip_start_seconds: SecondsPastMidnight,
+    #[serde(default = "default_day_type")]
+    pub day_type: String,   // the day type of the time periods to use, eg 'weekday' or 'weekend'
+    #[serde(default)]
+    pub decay_functions: Vec<DecayFunction>,   // one per purpose, or empty to use travel_time_relationships for every purpose
+    #[serde(default = "default_max_travel_time_seconds")]
+    pub max_travel_time_seconds: Cost,   // the floodfill time limit: defaults to an hour
+}
+
+#[derive(Deserialize)]
+pub struct NearestNodeUserInputJSON {
+    pub longitude: f64,
+    pub latitude: f64,
+    pub nodes_count: usize,
+    #[serde(default)]
+    pub mode: String,   // walk, cycling or car: only read by Walk cycling car batch, as each mode has its own graph
 }
 
 #[derive(Deserialize)]
@@ -334,8 +722,32 @@ pub struct OriginDestinationUserInputJSON {
     pub destination_nodes: Vec<NodeID>,
     pub init_travel_times: Vec<Cost>,
     pub trip_start_seconds: SecondsPastMidnight,
+    #[serde(default = "default_day_type")]
+    pub day_type: String,   // the day type of the time periods to use, eg 'weekday' or 'weekend'
     pub count_destinations_at_intervals: usize,
     pub original_time_intervals_to_store_destination_counts: Vec<Cost>,
+    // If zone_ids are given, start_nodes and init_travel_times are ignored: start nodes are taken from
+    // the zone definitions and population weighted scores are returned per zone
+    #[serde(default)]
+    pub zone_type: String,          // 'LSOA' or 'OA'
+    #[serde(default)]
+    pub zone_ids: Vec<String>,
+    #[serde(default)]
+    pub named_output: usize,       // 0 for false, 1 for true: key scores and destination counts by purpose, subpurpose and size band names
+    #[serde(default)]
+    pub decay_functions: Vec<DecayFunction>,   // one per purpose, or empty to use travel_time_relationships for every purpose
+    #[serde(default = "default_max_travel_time_seconds")]
+    pub max_travel_time_seconds: Cost,   // the floodfill time limit: defaults to an hour
+    #[serde(default)]
+    pub accessibility_metrics: Option<AccessibilityMetricsRequest>,   // extra measures computed in the same floodfill, if given
+    #[serde(default)]
+    pub return_subpurpose_scores: usize,   // 0 for false, 1 for true
+    #[serde(default)]
+    pub score_time_bands: Vec<Cost>,   // eg [600, 1200, 1800] to split purpose scores into 0-10, 10-20, 20-30 and 30+ minutes
+    #[serde(default)]
+    pub competition: Option<CompetitionRequest>,   // if given, also returns two step floating catchment scores
+    #[serde(default)]
+    pub od_matrix: Option<OdMatrixRequest>,   // if given, returns only the matrix of seconds from each start node to each destination node
 }
 
 #[derive(Deserialize)]
@@ -344,15 +756,70 @@ pub struct WalkCyclingCarUserInputJSON {
     pub destination_nodes: Vec<NodeID>,
     pub init_travel_times_user_input: Vec<Cost>,
     pub trip_start_seconds: SecondsPastMidnight,
+    #[serde(default = "default_day_type")]
+    pub day_type: String,   // the day type of the time periods to use, eg 'weekday' or 'weekend'
     pub mode: String,
     pub builds_to_remove: Vec<Vec<usize>>,    // 0 is index_of_nearest_node, 1 is subpurpose_ix
-    pub time_or_distance: String,            // 'time' or 'distance'
+    pub time_or_distance: String,            // 'time' or 'distance': which to find the shortest paths by
     // The following are for finding the optimal PT routes for the route optimisation process
     pub track_pt_nodes_reached: usize,      // 0 for false, 1 for true
     pub seconds_reclaimed_when_pt_stop_reached: usize,
     pub target_node: NodeID,                            // floodfill ends early if this is reached
     pub count_destinations_at_intervals: usize,   // 0 for false, 1 for true
     pub original_time_intervals_to_store_destination_counts: Vec<Cost>,
+    // If zone_ids are given, start_nodes_user_input and init_travel_times_user_input are ignored: start nodes
+    // are taken from the zone definitions and population weighted scores are returned per zone
+    #[serde(default)]
+    pub zone_type: String,          // 'LSOA' or 'OA'
+    #[serde(default)]
+    pub zone_ids: Vec<String>,
+    #[serde(default)]
+    pub named_output: usize,       // 0 for false, 1 for true: key scores and destination counts by purpose, subpurpose and size band names
+    #[serde(default)]
+    pub decay_functions: Vec<DecayFunction>,   // one per purpose, or empty to use travel_time_relationships for every purpose
+    #[serde(default = "default_max_travel_time_seconds")]
+    pub max_travel_time_seconds: Cost,   // the floodfill time limit: defaults to an hour
+    #[serde(default)]
+    pub accessibility_metrics: Option<AccessibilityMetricsRequest>,   // extra measures computed in the same floodfill, if given
+    #[serde(default)]
+    pub return_subpurpose_scores: usize,   // 0 for false, 1 for true
+    #[serde(default)]
+    pub score_time_bands: Vec<Cost>,   // eg [600, 1200, 1800] to split purpose scores into 0-10, 10-20, 20-30 and 30+ minutes
+    #[serde(default)]
+    pub competition: Option<CompetitionRequest>,   // if given, also returns two step floating catchment scores
+    #[serde(default)]
+    pub return_other_cost: usize,   // 0 for false, 1 for true: also return distances to OD pairs if optimising on time, or seconds if on distance
+    #[serde(default)]
+    pub time_dependent: usize,   // 0 for false, 1 for true: car link travel times change with the time of day as the floodfill goes on
+    #[serde(default)]
+    pub turn_cost_profile: Option<TurnCostProfile>,   // overrides the mode's profile from turn_cost_profiles.json
+    #[serde(default)]
+    pub od_matrix: Option<OdMatrixRequest>,   // if given, returns only the matrix of costs from each start node to each destination node
+}
+
+#[derive(Deserialize)]
+pub struct RouteUserInputJSON {
+    pub mode: String,
+    pub start_node: NodeID,
+    pub target_node: NodeID,
+    pub trip_start_seconds: SecondsPastMidnight,   // picks the time period of the car graph to route on
+    #[serde(default = "default_day_type")]
+    pub day_type: String,
+    pub time_or_distance: String,   // 'time' or 'distance': which to find the shortest path by
+    #[serde(default)]
+    pub turn_cost_profile: Option<TurnCostProfile>,   // overrides the mode's profile from turn_cost_profiles.json
+}
+
+#[derive(Serialize)]
+pub struct RouteOutput {
+    pub start_node: NodeID,
+    pub target_node: NodeID,
+    pub nodes: Vec<NodeID>,   // from start_node to target_node
+    pub link_ids: Vec<LinkID>,   // the link taken to each node after start_node
+    pub cost: Cost,   // seconds or metres, as per time_or_distance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<Vec<[f64; 2]>>,   // long/lat of each node, if there are coordinates for the mode
+    pub iters: usize,
 }
 
 #[derive(Deserialize, Debug)]
@@ -360,6 +827,8 @@ pub struct ServiceChangePayload {
     pub start_nodes: Vec<NodeID>,
     pub init_travel_times: Vec<Cost>,
     pub trip_start_seconds: SecondsPastMidnight,
+    #[serde(default = "default_day_type")]
+    pub day_type: String,   // the day type of the time periods to use, eg 'weekday' or 'weekend'
     pub graph_walk_additions: Vec<Vec<[usize; 2]>>,  // 0 is Cost, 1 is NodeID. These are new additions for new nodes: so paths from new nodes
     pub graph_routes_additions: Vec<Vec<[usize; 2]>>,  // previously graph_pt_additions; new routes for us; as of 17th May first pair in inner vec will show next node: need to change this
     pub graph_walk_updates_keys: Vec<NodeID>,                // NodeIDs to be changed by graph_walk_updates_additions
@@ -371,4 +840,16 @@ pub struct ServiceChangePayload {
     pub route_nodes_rail: Vec<NodeID>,
     pub count_destinations_at_intervals: usize,
     pub original_time_intervals_to_store_destination_counts: Vec<Cost>,
+    #[serde(default)]
+    pub named_output: usize,       // 0 for false, 1 for true: key scores and destination counts by purpose, subpurpose and size band names
+    #[serde(default)]
+    pub decay_functions: Vec<DecayFunction>,   // one per purpose, or empty to use travel_time_relationships for every purpose
+    #[serde(default = "default_max_travel_time_seconds")]
+    pub max_travel_time_seconds: Cost,   // the floodfill time limit: defaults to an hour
+    #[serde(default)]
+    pub accessibility_metrics: Option<AccessibilityMetricsRequest>,   // extra measures computed in the same floodfill, if given
+    #[serde(default)]
+    pub return_subpurpose_scores: usize,   // 0 for false, 1 for true
+    #[serde(default)]
+    pub score_time_bands: Vec<Cost>,   // eg [600, 1200, 1800] to split purpose scores into 0-10, 10-20, 20-30 and 30+ minutes
 }
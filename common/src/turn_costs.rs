@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use typed_index_collections::TiVec;
+
+use crate::structs::{Angle, Cost, LinkID, NodeID, NodeWalkCyclingCar};
+
+pub const TURN_COST_PROFILES_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrafficSide {
+    #[default]
+    Left,
+    Right,
+}
+
+// Seconds added for turns whose angle is below up_to_degrees, and at or above the previous band's.
+// Turn angles are clockwise from straight on: 90 is a right turn, 180 a u-turn and 270 a left turn
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct TurnAngleBand {
+    pub up_to_degrees: u16,
+    pub seconds: Cost,
+}
+
+// Bands are written for left hand traffic, as in the UK, where right turns cross oncoming traffic.
+// With traffic_side of 'right' turn angles are mirrored, so left turns cost what right turns would
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct TurnCostProfile {
+    pub angle_bands: Vec<TurnAngleBand>,
+    #[serde(default)]
+    pub traffic_side: TrafficSide,
+    #[serde(default)]
+    pub major_road_crossing_seconds: Cost,   // added when going from one minor road to another across a node on a major road
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TurnCostProfiles {
+    pub version: u32,
+    pub profiles: HashMap<String, TurnCostProfile>,   // mode -> profile
+}
+
+impl TurnCostProfile {
+    // Bands must be in increasing order of up_to_degrees, with the last ending at 360
+    pub fn validate(&self) -> Result<(), String> {
+        let mut previous_up_to_degrees = 0;
+        for band in self.angle_bands.iter() {
+            if band.up_to_degrees <= previous_up_to_degrees {
+                return Err(format!(
+                    "Turn angle band up to {} degrees comes after one up to {}: bands must be in increasing order",
+                    band.up_to_degrees, previous_up_to_degrees
+                ));
+            }
+            previous_up_to_degrees = band.up_to_degrees;
+        }
+        if previous_up_to_degrees != 360 {
+            return Err(format!(
+                "The last turn angle band should end at 360 degrees, not {}",
+                previous_up_to_degrees
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn cost_of_turn(&self, angle_leaving_node_from: Angle, angle_arrived_from: Angle) -> Cost {
+        let mut turn_degrees = (angle_leaving_node_from.0 + 360 - angle_arrived_from.0) % 360;
+        if self.traffic_side == TrafficSide::Right {
+            turn_degrees = (360 - turn_degrees) % 360;
+        }
+        for band in self.angle_bands.iter() {
+            if turn_degrees < band.up_to_degrees {
+                return band.seconds;
+            }
+        }
+        Cost(0)
+    }
+
+    pub fn make_default_instance(mode: &str) -> Self {
+        // straight on, right turn, u-turn, left turn
+        let seconds = match mode {
+            "cycling" => [0, 15, 15, 5],
+            "car" => [0, 15, 17, 9],
+            _ => [0, 0, 0, 0],
+        };
+        let band = |up_to_degrees: u16, seconds: usize| TurnAngleBand {
+            up_to_degrees,
+            seconds: Cost(seconds),
+        };
+        TurnCostProfile {
+            angle_bands: vec![
+                band(45, seconds[0]),
+                band(135, seconds[1]),
+                band(225, seconds[2]),
+                band(315, seconds[3]),
+                band(360, seconds[0]),
+            ],
+            traffic_side: TrafficSide::Left,
+            major_road_crossing_seconds: Cost(0),
+        }
+    }
+}
+
+impl TurnCostProfiles {
+    // Modes without a profile get the default for that mode
+    pub fn get(&self, mode: &str) -> TurnCostProfile {
+        match self.profiles.get(mode) {
+            Some(profile) => profile.clone(),
+            None => TurnCostProfile::make_default_instance(mode),
+        }
+    }
+}
+
+// Which links are major roads, and which nodes are on one, for major_road_crossing_seconds
+pub struct MajorRoads {
+    link_is_major_road: Vec<bool>,   // indexed by LinkID
+    node_is_on_major_road: TiVec<NodeID, bool>,
+}
+
+impl MajorRoads {
+    pub fn new(link_is_major_road: Vec<bool>, graph: &TiVec<NodeID, NodeWalkCyclingCar>) -> Self {
+        let node_is_on_major_road = graph
+            .iter()
+            .map(|node| {
+                node.edges
+                    .iter()
+                    .any(|edge| link_is_major_road.get(edge.link_arrived_from.0 as usize) == Some(&true))
+            })
+            .collect();
+        MajorRoads {
+            link_is_major_road,
+            node_is_on_major_road,
+        }
+    }
+
+    // Links which aren't in link_is_major_road (eg: the start of a floodfill) are never crossings
+    pub fn is_crossing(&self, link_arrived_from: LinkID, node: NodeID, link_leaving_by: LinkID) -> bool {
+        let is_minor_road = |link: LinkID| self.link_is_major_road.get(link.0 as usize) == Some(&false);
+        self.node_is_on_major_road[node] && is_minor_road(link_arrived_from) && is_minor_road(link_leaving_by)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Arriving heading north, so the leaving bearing is the turn angle
+    fn cost_of_turn_from_north(profile: &TurnCostProfile, turn_degrees: u16) -> usize {
+        profile.cost_of_turn(Angle(turn_degrees), Angle(0)).0
+    }
+
+    #[test]
+    fn turns_cost_the_seconds_of_their_angle_band() {
+        let car = TurnCostProfile::make_default_instance("car");
+        let costs: Vec<usize> = [0, 44, 45, 90, 134, 135, 180, 224, 225, 270, 314, 315, 359]
+            .iter()
+            .map(|turn_degrees| cost_of_turn_from_north(&car, *turn_degrees))
+            .collect();
+        assert_eq!(costs, vec![0, 0, 15, 15, 15, 17, 17, 17, 9, 9, 9, 0, 0]);
+
+        // Angles wrap round north: heading 350 then 20 is 30 degrees right, and 20 then 300 is 80 left
+        assert_eq!(car.cost_of_turn(Angle(20), Angle(350)).0, 0);
+        assert_eq!(car.cost_of_turn(Angle(300), Angle(20)).0, 9);
+        assert_eq!(car.cost_of_turn(Angle(200), Angle(100)).0, 15);
+    }
+
+    #[test]
+    fn right_hand_traffic_mirrors_turns() {
+        let left_hand = TurnCostProfile::make_default_instance("car");
+        let right_hand = TurnCostProfile { traffic_side: TrafficSide::Right, ..left_hand.clone() };
+        for turn_degrees in [0, 30, 90, 180, 270, 330] {
+            assert_eq!(
+                cost_of_turn_from_north(&right_hand, turn_degrees),
+                cost_of_turn_from_north(&left_hand, (360 - turn_degrees) % 360),
+                "turn of {} degrees",
+                turn_degrees
+            );
+        }
+        // Left turns cross oncoming traffic, so cost what right turns do in the UK
+        assert_eq!(cost_of_turn_from_north(&right_hand, 270), 15);
+        assert_eq!(cost_of_turn_from_north(&right_hand, 90), 9);
+        assert_eq!(cost_of_turn_from_north(&right_hand, 180), 17);
+    }
+
+    #[test]
+    fn bands_must_increase_to_360() {
+        assert_eq!(TurnCostProfile::make_default_instance("cycling").validate(), Ok(()));
+        let band = |up_to_degrees: u16| TurnAngleBand { up_to_degrees, seconds: Cost(5) };
+        let profile = |angle_bands: Vec<TurnAngleBand>| TurnCostProfile {
+            angle_bands,
+            traffic_side: TrafficSide::Left,
+            major_road_crossing_seconds: Cost(0),
+        };
+        assert!(profile(vec![band(90), band(90), band(360)]).validate().is_err());
+        assert!(profile(vec![band(180), band(90), band(360)]).validate().is_err());
+        assert!(profile(vec![band(90), band(180)]).validate().is_err());
+        assert!(profile(vec![]).validate().is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use crate::structs::{Score, ZoneScores, ZoneStartNode};
+
+// Flattens the start nodes of each requested zone into one list, keeping the position of the zone in
+// zone_ids alongside each start node so results can be aggregated back to zones. Errs with every
+// zone_id not in zone_definitions, rather than scoring them as zero
+pub fn expand_zone_start_nodes(
+    zone_ids: &[String],
+    zone_definitions: &HashMap<String, Vec<ZoneStartNode>>,
+) -> Result<Vec<(usize, ZoneStartNode)>, String> {
+    let mut zone_start_nodes: Vec<(usize, ZoneStartNode)> = Vec::new();
+    let mut unknown_zone_ids: Vec<&str> = Vec::new();
+    for (zone_ix, zone_id) in zone_ids.iter().enumerate() {
+        match zone_definitions.get(zone_id) {
+            Some(start_nodes) => {
+                for start_node in start_nodes {
+                    zone_start_nodes.push((zone_ix, *start_node));
+                }
+            }
+            None => unknown_zone_ids.push(zone_id),
+        }
+    }
+    if !unknown_zone_ids.is_empty() {
+        return Err(format!("Unknown zone_ids: {}", unknown_zone_ids.join(", ")));
+    }
+    Ok(zone_start_nodes)
+}
+
+// Weighted mean of purpose scores across each zone's start nodes. purpose_scores must be in the same
+// order as zone_start_nodes
+pub fn aggregate_purpose_scores_by_zone(
+    zone_ids: &[String],
+    zone_start_nodes: &[(usize, ZoneStartNode)],
+    purpose_scores: &[Vec<Score>],
+    purposes_count: usize,
+) -> Vec<ZoneScores> {
+    let mut zone_scores: Vec<ZoneScores> = zone_ids
+        .iter()
+        .map(|zone_id| ZoneScores {
+            zone_id: zone_id.clone(),
+            total_weight: 0.0,
+            purpose_scores: vec![Score(0.0); purposes_count],
+            competition_adjusted_purpose_scores: None,
+        })
+        .collect();
+
+    for ((zone_ix, start_node), start_node_purpose_scores) in zone_start_nodes.iter().zip(purpose_scores) {
+        zone_scores[*zone_ix].total_weight += start_node.weight;
+        for (purpose_ix, score) in start_node_purpose_scores.iter().enumerate() {
+            zone_scores[*zone_ix].purpose_scores[purpose_ix] += Score(score.0 * start_node.weight);
+        }
+    }
+
+    for zone in zone_scores.iter_mut() {
+        if zone.total_weight > 0.0 {
+            for score in zone.purpose_scores.iter_mut() {
+                score.0 /= zone.total_weight;
+            }
+        }
+    }
+    zone_scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{Cost, NodeID};
+
+    #[test]
+    fn unknown_zone_ids_are_listed_in_the_error() {
+        let start_node = ZoneStartNode { node: NodeID(3), seconds_walk_to_start_node: Cost(60), weight: 2.0 };
+        let zone_definitions = HashMap::from([("E01000001".to_string(), vec![start_node])]);
+
+        let zone_start_nodes = expand_zone_start_nodes(&["E01000001".to_string()], &zone_definitions).unwrap();
+        assert_eq!(zone_start_nodes.len(), 1);
+        assert_eq!(zone_start_nodes[0].0, 0);
+
+        let zone_ids = ["E01000002".to_string(), "E01000001".to_string(), "E01000003".to_string()];
+        assert_eq!(
+            expand_zone_start_nodes(&zone_ids, &zone_definitions).err(),
+            Some("Unknown zone_ids: E01000002, E01000003".to_string())
+        );
+    }
+}
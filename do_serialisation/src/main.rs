@@ -1,20 +1,30 @@
 use fs_err::File;
 use smallvec::SmallVec;
 use std::collections::HashMap;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::path::Path;
+use serde::Serialize;
 use std::time::Instant;
 
-use common::read_file_funcs::{deserialize_bincoded_file, read_files_parallel_inc_node_values};
+use common::read_file_funcs::{
+    deserialize_bincoded_file, read_bincoded_file_header, read_files_parallel_excluding_node_values, read_graph_pt_routes,
+    read_graph_pt_walk, read_graph_walk_cycling_car, read_time_periods, write_bincoded_file,
+};
 use common::structs::{
-    Angle, Cost, EdgeRoute, EdgeWalk, EdgeWalkCyclingCar, LinkID, Multiplier, NodeID, NodeRoute,
+    Angle, Cost, CostUnits, EdgeRoute, EdgeWalk, EdgeWalkCyclingCar, LinkCostProfiles, LinkID, Multiplier, NodeID, NodeRoute,
     NodeWalk, NodeWalkCyclingCar, Score, SecondsPastMidnight, SubpurposeScore,
-    SubpurposeSmallMediumLargeCount,
+    SubpurposeSmallMediumLargeCount, ZoneStartNode, BINCODE_FORMAT_VERSION, TimePeriods,
 };
 
 // All serialisation you want to do should go here
+// Run with --migrate to rewrite existing files in serialised_data with a header and wider costs, without the JSON in data/
 fn main() {
-    serialise_files(2022);
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--migrate") {
+        migrate_files(2022);
+    } else {
+        serialise_files(2022);
+    }
 }
 
 pub fn serialise_files(year: i32) {
@@ -26,25 +36,26 @@ pub fn serialise_files(year: i32) {
     serialise_nodes_small_medium_large_count("small_medium_large_subpurpose_destinations_PT");
 
     serialise_car_nodes_is_closest_to_pt();
+    for mode in ["walk", "cycling", "car"] {
+        serialise_link_is_major_road(mode);
+        serialise_node_longlat_lookup_walk_cycling_car(mode);
+    }
     serialise_stop_rail_statuses(year);
 
-    serialise_graph_walk_cycling_car_vector("car_1");
-    serialise_graph_walk_cycling_car_vector("car_7");
-    serialise_graph_walk_cycling_car_vector("car_10");
-    serialise_graph_walk_cycling_car_vector("car_16");
-    serialise_graph_walk_cycling_car_vector("car_19");
-
-    serialise_sparse_node_values_2d("sparse_node_values_car_1");
-    serialise_sparse_node_values_2d("sparse_node_values_car_7");
-    serialise_sparse_node_values_2d("sparse_node_values_car_10");
-    serialise_sparse_node_values_2d("sparse_node_values_car_16");
-    serialise_sparse_node_values_2d("sparse_node_values_car_19");
-
-    serialise_list_multiplier("car_travel_time_relationships_1");
-    serialise_list_multiplier("car_travel_time_relationships_7");
-    serialise_list_multiplier("car_travel_time_relationships_10");
-    serialise_list_multiplier("car_travel_time_relationships_16");
-    serialise_list_multiplier("car_travel_time_relationships_19");
+    for zone_type in ["LSOA", "OA"] {
+        for mode in ["PT", "walk", "cycling", "car"] {
+            serialise_zone_start_nodes(zone_type, mode);
+        }
+    }
+
+    // Car files, and the travel time relationships of every mode, are per time period
+    let time_periods = read_time_periods();
+    for file_suffix in time_periods.unique_file_suffixes("car") {
+        serialise_graph_walk_cycling_car_vector(&format!("car_{}", file_suffix), CostUnits::Seconds);
+        serialise_sparse_node_values_2d(&format!("sparse_node_values_car_{}", file_suffix));
+        serialise_list_multiplier(&format!("car_travel_time_relationships_{}", file_suffix));
+    }
+    serialise_link_cost_profiles("car", &time_periods);
     println!("Serialised car files");
 
     serialise_graph_walk_and_len(year);
@@ -53,28 +64,81 @@ pub fn serialise_files(year: i32) {
     serialise_route_info(year);
 
     //serialise_list_immutable_array_usize("subpurpose_purpose_lookup"); // believe no longer needed. Adam June 19th 2023. Keeping just in case, can delete if code still working a week from now
-    serialise_list_multiplier("travel_time_relationships_7");
-    serialise_list_multiplier("travel_time_relationships_10");
-    serialise_list_multiplier("travel_time_relationships_16");
-    serialise_list_multiplier("travel_time_relationships_19");
+    for file_suffix in time_periods.unique_file_suffixes("PT") {
+        serialise_list_multiplier(&format!("travel_time_relationships_{}", file_suffix));
+    }
 
     serialise_sparse_node_values_2d(&*format!("sparse_node_values_6am_{year}_2d")); // &* converts String to &str
     serialise_rust_node_longlat_lookup(year);
 
-    serialise_graph_walk_cycling_car_vector("walk");
-    serialise_graph_walk_cycling_car_vector("cycling");
+    serialise_graph_walk_cycling_car_vector("walk", CostUnits::Seconds);
+    serialise_graph_walk_cycling_car_vector("cycling", CostUnits::Seconds);
 
     serialise_sparse_node_values_2d("sparse_node_values_walk");
     serialise_sparse_node_values_2d("sparse_node_values_cycling");
 
-    serialise_list_multiplier("walk_travel_time_relationships_7");
-    serialise_list_multiplier("cycling_travel_time_relationships_7");
+    for mode in ["walk", "cycling"] {
+        for file_suffix in time_periods.unique_file_suffixes(mode) {
+            serialise_list_multiplier(&format!("{}_travel_time_relationships_{}", mode, file_suffix));
+        }
+    }
 
     chunk_pt_graphs(year);
 
     println!("File serialisation year {}/tTook {:?}", year, now.elapsed());
 }
 
+// Only files with costs in, or whose layout has changed, need migrating: the rest read the same with or without a header
+pub fn migrate_files(year: i32) {
+    let now = Instant::now();
+
+    let time_periods = read_time_periods();
+    let mut graph_names: Vec<String> = time_periods.unique_file_suffixes("car")
+        .iter()
+        .map(|file_suffix| format!("car_{}", file_suffix))
+        .collect();
+    graph_names.push("walk".to_string());
+    graph_names.push("cycling".to_string());
+    for graph_name in graph_names {
+        migrate_file(&format!("graph_{}", graph_name), CostUnits::Seconds, read_graph_walk_cycling_car);
+    }
+
+    migrate_file(&format!("graph_pt_walk_6am_{}", year), CostUnits::Seconds, read_graph_pt_walk);
+    migrate_file(&format!("graph_pt_routes_6am_{}", year), CostUnits::Seconds, read_graph_pt_routes);
+    for i in 1..=3 {
+        migrate_file(&format!("graph_pt_walk_chunk_{}", i), CostUnits::Seconds, read_graph_pt_walk);
+        migrate_file(&format!("graph_pt_routes_chunk_{}", i), CostUnits::Seconds, read_graph_pt_routes);
+    }
+
+    for zone_type in ["LSOA", "OA"] {
+        for mode in ["PT", "walk", "cycling", "car"] {
+            migrate_file(
+                &format!("zone_start_nodes_{}_{}", zone_type, mode),
+                CostUnits::Seconds,
+                deserialize_bincoded_file::<HashMap<String, Vec<ZoneStartNode>>>,
+            );
+        }
+    }
+
+    println!("File migration year {}/tTook {:?}", year, now.elapsed());
+}
+
+// Skips files which are missing or already the current version, so is safe to rerun. read converts
+// the file from whichever version it is
+fn migrate_file<T: Serialize>(filename: &str, cost_units: CostUnits, read: fn(&str) -> T) {
+    if !Path::new(&format!("serialised_data/{}.bin", filename)).exists() {
+        println!("Skipping {} as it doesn't exist", filename);
+        return;
+    }
+    if read_bincoded_file_header(filename).format_version == BINCODE_FORMAT_VERSION {
+        println!("Skipping {} as it's already version {}", filename, BINCODE_FORMAT_VERSION);
+        return;
+    }
+    let value = read(filename);
+    write_bincoded_file(filename, &value, cost_units);
+    println!("Migrated {}", filename);
+}
+
 pub fn serialise_car_nodes_is_closest_to_pt() {
     let inpath = format!("data/car_nodes_is_closest_to_pt.json");
     let contents = fs_err::read_to_string(&inpath).unwrap();
@@ -83,9 +147,42 @@ pub fn serialise_car_nodes_is_closest_to_pt() {
 
     let output: Vec<bool> = input.iter().map(|&num| num != 0).collect();
 
-    let outpath = format!("serialised_data/car_nodes_is_closest_to_pt.bin");
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
+    let outpath = "car_nodes_is_closest_to_pt";
+    write_bincoded_file(outpath, &output, CostUnits::Seconds);
+    println!("Serialised to {}", outpath);
+}
+
+// Optional: only needed for turn cost profiles with a major road crossing penalty
+pub fn serialise_link_is_major_road(mode: &str) {
+    let inpath = format!("data/{}_link_is_major_road.json", mode);
+    if !Path::new(&inpath).exists() {
+        println!("Skipping {} as it doesn't exist", inpath);
+        return;
+    }
+    let contents = fs_err::read_to_string(&inpath).unwrap();
+    let input: Vec<i32> = serde_json::from_str(&contents).unwrap();
+    println!("Read from {}", inpath);
+
+    let output: Vec<bool> = input.iter().map(|&num| num != 0).collect();
+
+    let outpath = format!("{}_link_is_major_road", mode);
+    write_bincoded_file(&outpath, &output, CostUnits::Seconds);
+    println!("Serialised to {}", outpath);
+}
+
+// Optional: only needed for A* on the /route/ endpoint, and for route geometry
+pub fn serialise_node_longlat_lookup_walk_cycling_car(mode: &str) {
+    let inpath = format!("data/{}_nodes_long_lat.json", mode);
+    if !Path::new(&inpath).exists() {
+        println!("Skipping {} as it doesn't exist", inpath);
+        return;
+    }
+    let contents = fs_err::read_to_string(&inpath).unwrap();
+    let output: Vec<[f64; 2]> = serde_json::from_str(&contents).unwrap();
+    println!("Read from {}", inpath);
+
+    let outpath = format!("{}_nodes_long_lat", mode);
+    write_bincoded_file(&outpath, &output, CostUnits::Seconds);
     println!("Serialised to {}", outpath);
 }
 
@@ -97,14 +194,38 @@ pub fn serialise_stop_rail_statuses(year: i32) {
 
     let output: Vec<bool> = input.iter().map(|&num| num != 0).collect();
 
-    let outpath = format!("serialised_data/stop_rail_statuses_{}.bin", year);
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
+    let outpath = format!("stop_rail_statuses_{}", year);
+    write_bincoded_file(&outpath, &output, CostUnits::Seconds);
+    println!("Serialised to {}", outpath);
+}
+
+// Input is a dict of zone ID to a list of [node, seconds walk to node, weight] for each of the zone's start nodes
+pub fn serialise_zone_start_nodes(zone_type: &str, mode: &str) {
+    let inpath = format!("data/zone_start_nodes_{}_{}.json", zone_type, mode);
+    let contents = fs_err::read_to_string(&inpath).unwrap();
+    let input: HashMap<String, Vec<[f64; 3]>> = serde_json::from_str(&contents).unwrap();
+    println!("Read from {}", inpath);
+
+    let mut output: HashMap<String, Vec<ZoneStartNode>> = HashMap::new();
+    for (zone_id, start_nodes) in input {
+        let zone_start_nodes = start_nodes
+            .iter()
+            .map(|val| ZoneStartNode {
+                node: NodeID(val[0] as usize),
+                seconds_walk_to_start_node: Cost(val[1] as usize),
+                weight: val[2],
+            })
+            .collect();
+        output.insert(zone_id, zone_start_nodes);
+    }
+
+    let outpath = format!("zone_start_nodes_{}_{}", zone_type, mode);
+    write_bincoded_file(&outpath, &output, CostUnits::Seconds);
     println!("Serialised to {}", outpath);
 }
 
 pub fn chunk_pt_graphs(year: i32) {
-    let (_node_values_2d, graph_walk, graph_routes) = read_files_parallel_inc_node_values(year);
+    let (graph_walk, graph_routes) = read_files_parallel_excluding_node_values(year);
 
     let chunk_count = 3;
     let graph_walk_chunk_size = 1 + graph_walk.len() / chunk_count;
@@ -115,9 +236,8 @@ pub fn chunk_pt_graphs(year: i32) {
 
     for (i, chunk) in graph_walk_in_chunks.iter().enumerate() {
         println!("Chunk {} len: {}", i + 1, chunk.len());
-        let filename = format!("serialised_data/graph_pt_walk_chunk_{}.bin", i + 1);
-        let file = BufWriter::new(File::create(filename).unwrap());
-        bincode::serialize_into(file, &chunk).unwrap();
+        let filename = format!("graph_pt_walk_chunk_{}", i + 1);
+        write_bincoded_file(&filename, &chunk, CostUnits::Seconds);
         println!("Walk Chunk {} serialised", i + 1);
     }
 
@@ -129,19 +249,20 @@ pub fn chunk_pt_graphs(year: i32) {
 
     for (i, chunk) in graph_route_in_chunks.iter().enumerate() {
         println!("Chunk {} len: {}", i + 1, chunk.len());
-        let filename = format!("serialised_data/graph_pt_routes_chunk_{}.bin", i + 1);
-        let file = BufWriter::new(File::create(filename).unwrap());
-        bincode::serialize_into(file, &chunk).unwrap();
+        let filename = format!("graph_pt_routes_chunk_{}", i + 1);
+        write_bincoded_file(&filename, &chunk, CostUnits::Seconds);
         println!("Routes Chunk {} serialised", i + 1);
     }
 }
 
-fn serialise_graph_walk_cycling_car_vector(mode: &str) {
+fn serialise_graph_walk_cycling_car_vector(mode: &str, cost_units: CostUnits) {
     let contents_filename = format!("data/graph_{}.json", mode);
     println!("{:?}", contents_filename);
     let contents = fs_err::read_to_string(contents_filename).unwrap();
 
-    let input: Vec<Vec<[usize; 5]>> = serde_json::from_str(&contents).unwrap();
+    // Each edge is [cost, to, angle_leaving_node_from, angle_arrived_from, link_arrived_from], with an
+    // optional 6th value of the edge's distance in metres
+    let input: Vec<Vec<Vec<usize>>> = serde_json::from_str(&contents).unwrap();
 
     let mut graph_walk = Vec::new();
     for input_edges in input.iter() {
@@ -153,14 +274,47 @@ fn serialise_graph_walk_cycling_car_vector(mode: &str) {
                 angle_leaving_node_from: Angle(array[2] as u16),
                 angle_arrived_from: Angle(array[3] as u16),
                 link_arrived_from: LinkID(array[4] as u32),
+                distance_metres: array.get(5).map(|distance_metres| Cost(*distance_metres)),
             });
         }
         graph_walk.push(NodeWalkCyclingCar { edges });
     }
 
-    let filename = format!("serialised_data/graph_{}.bin", mode);
-    let file = BufWriter::new(File::create(filename).unwrap());
-    bincode::serialize_into(file, &graph_walk).unwrap();
+    let filename = format!("graph_{}", mode);
+    write_bincoded_file(&filename, &graph_walk, cost_units);
+}
+
+// Each link's cost in each period is taken from that period's graph; periods whose graph doesn't have
+// the link use the cost from the first graph which does
+fn serialise_link_cost_profiles(mode: &str, time_periods: &TimePeriods) {
+    let periods_count = time_periods.periods_count();
+    let mut seconds_by_link: Vec<Vec<Option<u32>>> = Vec::new();
+    for period_ix in 0..periods_count {
+        let graph = read_graph_walk_cycling_car(&format!("graph_{}_{}", mode, time_periods.file_suffix(period_ix, mode)));
+        for edge in graph.iter().flat_map(|node| node.edges.iter()) {
+            let link_ix = edge.link_arrived_from.0 as usize;
+            if link_ix >= seconds_by_link.len() {
+                seconds_by_link.resize(link_ix + 1, vec![None; periods_count]);
+            }
+            seconds_by_link[link_ix][period_ix] = Some(edge.cost.0 as u32);
+        }
+    }
+
+    let mut seconds_by_link_and_period = Vec::with_capacity(seconds_by_link.len() * periods_count);
+    for seconds in seconds_by_link.iter() {
+        let fallback = seconds.iter().flatten().next().copied().unwrap_or(0);
+        seconds_by_link_and_period.extend(seconds.iter().map(|seconds_this_period| seconds_this_period.unwrap_or(fallback)));
+    }
+    let filename = format!("{}_link_cost_profiles", mode);
+    write_bincoded_file(
+        &filename,
+        &LinkCostProfiles {
+            periods_count,
+            seconds_by_link_and_period,
+        },
+        CostUnits::Seconds,
+    );
+    println!("Serialised to {}", filename);
 }
 
 fn serialise_graph_routes(year: i32) {
@@ -193,16 +347,15 @@ fn serialise_graph_routes(year: i32) {
     }
 
     // Pad with empty values so length matches that of graph_walk
-    let graph_walk: Vec<NodeWalk> = deserialize_bincoded_file(&format!("graph_pt_walk_6am_{year}"));
+    let graph_walk: Vec<NodeWalk> = read_graph_pt_walk(&format!("graph_pt_walk_6am_{year}"));
     for _i in routes.len()..graph_walk.len() {
         graph_routes.push(NodeRoute::make_empty_instance());
     }
     assert!(graph_walk.len() == graph_routes.len());
 
     // Serialize the graph data into a binary file
-    let filename = format!("serialised_data/graph_pt_routes_6am_{}.bin", year);
-    let file = BufWriter::new(File::create(filename).unwrap());
-    bincode::serialize_into(file, &graph_routes).unwrap();
+    let filename = format!("graph_pt_routes_6am_{}", year);
+    write_bincoded_file(&filename, &graph_routes, CostUnits::Seconds);
 }
 
 // TODO consider if should make new object type for building counts, not Score
@@ -230,10 +383,8 @@ pub fn serialise_nodes_small_medium_large_count(input_str: &str) {
     }
     println!("Read and processed from from {}", inpath);
 
-    let outpath = format!("serialised_data/{}.bin", input_str);
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
-    println!("Serialised sparse_node_values to {}", outpath);
+    write_bincoded_file(input_str, &output, CostUnits::Seconds);
+    println!("Serialised sparse_node_values to {}", input_str);
 }
 
 pub fn serialise_sparse_node_values_2d(input_str: &str) {
@@ -258,10 +409,8 @@ pub fn serialise_sparse_node_values_2d(input_str: &str) {
     }
     println!("Read and processed from from {}", inpath);
 
-    let outpath = format!("serialised_data/{}.bin", input_str);
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
-    println!("Serialised sparse_node_values to {}", outpath);
+    write_bincoded_file(input_str, &output, CostUnits::Seconds);
+    println!("Serialised sparse_node_values to {}", input_str);
 }
 
 pub fn serialise_rust_node_longlat_lookup(year: i32) {
@@ -272,9 +421,8 @@ pub fn serialise_rust_node_longlat_lookup(year: i32) {
         .into();
     println!("Read from {}", inpath);
 
-    let outpath = format!("serialised_data/rust_nodes_long_lat.bin");
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
+    let outpath = "rust_nodes_long_lat";
+    write_bincoded_file(outpath, &output, CostUnits::Seconds);
     println!("Serialised rust_node_longlat_lookup to {}", outpath);
 }
 
@@ -282,12 +430,8 @@ fn serialise_node_values_padding_count(year: i32) {
     let contents_filename = format!("data/node_values_padding_row_count_6am_{}.json", year);
     let contents = fs_err::read_to_string(contents_filename).unwrap();
     let input_value: u32 = serde_json::from_str(&contents).unwrap();
-    let filename = format!(
-        "serialised_data/node_values_padding_row_count_6am_{}.bin",
-        year
-    );
-    let file = BufWriter::new(File::create(filename).unwrap());
-    bincode::serialize_into(file, &input_value).unwrap();
+    let filename = format!("node_values_padding_row_count_6am_{}", year);
+    write_bincoded_file(&filename, &input_value, CostUnits::Seconds);
 }
 
 fn serialise_graph_walk_and_len(year: i32) {
@@ -319,13 +463,11 @@ fn serialise_graph_walk_and_len(year: i32) {
         });
     }
 
-    let filename = format!("serialised_data/graph_pt_walk_6am_{}.bin", year);
-    let file = BufWriter::new(File::create(filename).unwrap());
-    bincode::serialize_into(file, &graph_walk_vec).unwrap();
+    let filename = format!("graph_pt_walk_6am_{}", year);
+    write_bincoded_file(&filename, &graph_walk_vec, CostUnits::Seconds);
 
-    let filename = format!("serialised_data/graph_pt_walk_len_{}.bin", year);
-    let file = BufWriter::new(File::create(filename).unwrap());
-    bincode::serialize_into(file, &graph_walk_vec.len()).unwrap();
+    let filename = format!("graph_pt_walk_len_{}", year);
+    write_bincoded_file(&filename, &graph_walk_vec.len(), CostUnits::Seconds);
 }
 
 pub fn serialise_route_info(year: i32) {
@@ -344,9 +486,8 @@ pub fn serialise_route_info(year: i32) {
         output.push(next_val_map);
     }
 
-    let outpath = format!("serialised_data/route_info_{}.bin", year);
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
+    let outpath = format!("route_info_{}", year);
+    write_bincoded_file(&outpath, &output, CostUnits::Seconds);
     println!("Serialised to {}", outpath);
 }
 
@@ -356,8 +497,6 @@ fn serialise_list_multiplier(filename: &str) {
     let output: Vec<Multiplier> = serde_json::from_str(&contents).unwrap();
     println!("Read from {}", inpath);
 
-    let outpath = format!("serialised_data/{}.bin", filename);
-    let file = BufWriter::new(File::create(&outpath).unwrap());
-    bincode::serialize_into(file, &output).unwrap();
-    println!("Serialised to {}", outpath);
+    write_bincoded_file(filename, &output, CostUnits::Seconds);
+    println!("Serialised to {}", filename);
 }
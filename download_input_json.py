@@ -69,6 +69,12 @@ for file in [
     blob = bucket.blob(file)
     blob.download_to_filename(f"data/{file}")
 
+for zone_type in ['LSOA', 'OA']:
+    for mode in ['PT', 'walk', 'cycling', 'car']:
+        file = f'zone_start_nodes_{zone_type}_{mode}.json'
+        blob = bucket.blob(file)
+        blob.download_to_filename(f"data/{file}")
+
 for mode in ['cycling', 'walk']:
     for file in [
         f'graph_{mode}.json',
@@ -82,19 +88,20 @@ for mode in ['cycling', 'walk']:
 ## These files we save directly to serialised_data, as Rust reads then directly without serialising them. 
 ## It does this because they are small files, and .dockerignore prevents files in 'data' folder
 ## from being used
-for mode_simpler in ['car', 'bus', 'walk', 'cycling']:
-    file = f'score_multipliers_{mode_simpler}.json'
-    blob = bucket.blob(file)
-    blob.download_to_filename(f"serialised_data/{file}")
-    
-    # checking all valueare above zero: ensures correct file has been moved across
-    multipliers = read_json_file(file)
+blob = bucket.blob('purpose_taxonomy.json')
+blob.download_to_filename("serialised_data/purpose_taxonomy.json")
+
+# Optional: the Rust code uses its default time periods if there's no time_periods.json
+blob = bucket.blob('time_periods.json')
+if blob.exists():
+    blob.download_to_filename("serialised_data/time_periods.json")
+
+# checking all multipliers are above zero: ensures correct file has been moved across
+purpose_taxonomy = read_json_file('purpose_taxonomy.json')
+for mode_simpler, multipliers in purpose_taxonomy['score_multipliers'].items():
     for multiplier in multipliers:
         if multiplier < 0.00000001:
-            print(f'multiplier: {multiplier} in {file}')
+            print(f'multiplier: {multiplier} for {mode_simpler} in purpose_taxonomy.json')
             raise ValueError("Multiplier is a zero: shouldnt be the case")
 
-
-blob = bucket.blob('subpurpose_to_purpose_integer.json')
-blob.download_to_filename("serialised_data/subpurpose_to_purpose_integer.json")
 print('All files read in')
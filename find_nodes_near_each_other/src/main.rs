@@ -4,6 +4,7 @@ use std::io::BufWriter;
 use typed_index_collections::TiVec;
 
 use common::floodfill_public_transport_no_scores::floodfill_public_transport_no_scores;
+use common::search_workspace::with_thread_workspace;
 use common::read_file_funcs::{read_files_parallel_excluding_node_values, read_stop_rail_statuses};
 use common::structs::{Cost, FloodfillOutput, NodeID, NodeRoute, NodeWalk, SecondsPastMidnight};
 
@@ -46,10 +47,11 @@ pub fn make_and_serialise_nodes_within_n_seconds(
 
     let results: Vec<FloodfillOutput> = indices
         .par_iter()
-        .map(|i| {
+        .map(|i| with_thread_workspace(graph_walk.len(), |workspace| {
             floodfill_public_transport_no_scores(
                 &graph_walk,
                 &graph_routes,
+                workspace,
                 NodeID(*i as usize),
                 SecondsPastMidnight(28800),
                 Cost(0),
@@ -57,7 +59,7 @@ pub fn make_and_serialise_nodes_within_n_seconds(
                 seconds_travel_time,
                 &stop_rail_statuses,
             )
-        })
+        }))
         .collect();
     println!("Floodfill done for all nodes in graph_walk");
 
@@ -1,14 +1,14 @@
+use common::floodfill_funcs::get_travel_time_multiplier;
 use common::structs::{
-    DestinationReached, FloodfillOutput, Multiplier, NodeID, NodeScore, PlanningToolOutput, Score,
-    SubpurposeScore, PURPOSES_COUNT, TOP_CLUSTERS_COUNT,
+    DestinationReached, FloodfillOutput, Multiplier, NodeID, NodeScore, PlanningToolOutput, PurposeTaxonomy,
+    Score, SubpurposeScore, TOP_CLUSTERS_COUNT,
 };
 use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
 use std::time::Instant;
 use typed_index_collections::TiVec;
 
-use common::floodfill_funcs::{initialise_score_multiplers, initialise_subpurpose_purpose_lookup};
-
+#[allow(clippy::too_many_arguments)]
 pub fn get_all_scores_links_and_key_destinations(
     floodfill_output: &FloodfillOutput,
     node_values_2d: &TiVec<NodeID, Vec<SubpurposeScore>>,
@@ -16,10 +16,13 @@ pub fn get_all_scores_links_and_key_destinations(
     nodes_to_neighbouring_nodes: &TiVec<NodeID, Vec<NodeID>>,
     rust_node_longlat_lookup: &TiVec<NodeID, [f64; 2]>,
     route_info: &TiVec<NodeID, HashMap<String, String>>,
-    mutex_sparse_node_values_contributed: &Mutex<TiVec<NodeID, [Score; PURPOSES_COUNT]>>,
+    purpose_taxonomy: &PurposeTaxonomy,
+    mutex_sparse_node_values_contributed: &Mutex<Vec<Score>>,
 ) -> PlanningToolOutput {
-    let subpurpose_purpose_lookup = initialise_subpurpose_purpose_lookup();
-    let score_multiplers = initialise_score_multiplers("bus");
+    let subpurpose_purpose_lookup = &purpose_taxonomy.subpurpose_purpose_lookup;
+    let score_multiplers = purpose_taxonomy.score_multipliers("bus");
+    let purposes_count = purpose_taxonomy.purposes_count();
+    let seconds_per_purpose = travel_time_relationships.len() / purposes_count;
 
     let start = floodfill_output.start_node_id;
     let seconds_walk_to_start_node = floodfill_output.seconds_walk_to_start_node;
@@ -27,15 +30,16 @@ pub fn get_all_scores_links_and_key_destinations(
     println!("{:?} destinations reached", destinations_reached.len());
 
     // get lock so can edit: we reset all changes at end of this func
+    // Holds purposes_count scores for each node, one after the other: the scores for node n start at n * purposes_count
     let mut sparse_node_values_contributed = mutex_sparse_node_values_contributed.lock().unwrap();
 
-    let mut node_values_contributed_each_purpose_vec: Vec<[Score; PURPOSES_COUNT]> = vec![];
+    let mut node_values_contributed_each_purpose_vec: Vec<Vec<Score>> = vec![];
 
     // ********* Get subpurpose level scores overall, and purpose level contribution of each individual node reached
     let now = Instant::now();
 
     for DestinationReached { node, cost, .. } in destinations_reached.iter() {
-        let mut purpose_scores_this_node = [Score(0.0); PURPOSES_COUNT];
+        let mut purpose_scores_this_node = vec![Score(0.0); purposes_count];
 
         for SubpurposeScore {
             subpurpose_ix,
@@ -43,8 +47,12 @@ pub fn get_all_scores_links_and_key_destinations(
         } in node_values_2d[*node].iter()
         {
             // store scores for each subpurpose's purpose, for this node
-            let vec_start_pos_this_purpose = subpurpose_purpose_lookup[*subpurpose_ix] * 3601;
-            let multiplier = travel_time_relationships[vec_start_pos_this_purpose + (cost.0)];
+            let multiplier = get_travel_time_multiplier(
+                travel_time_relationships,
+                seconds_per_purpose,
+                subpurpose_purpose_lookup[*subpurpose_ix],
+                cost.0,
+            );
             let score_to_add = subpurpose_score.multiply(multiplier);
             let purpose_ix = subpurpose_purpose_lookup[*subpurpose_ix];
 
@@ -55,7 +63,8 @@ pub fn get_all_scores_links_and_key_destinations(
             purpose_scores_this_node[purpose_ix] += purpose_score_to_add;
         }
 
-        sparse_node_values_contributed[*node] = purpose_scores_this_node;
+        sparse_node_values_contributed[node.0 * purposes_count..(node.0 + 1) * purposes_count]
+            .copy_from_slice(&purpose_scores_this_node);
         node_values_contributed_each_purpose_vec.push(purpose_scores_this_node);
     }
 
@@ -71,8 +80,8 @@ pub fn get_all_scores_links_and_key_destinations(
     let now = Instant::now();
 
     // initialise link data to populate
-    let mut link_score_contributions: Vec<[Score; PURPOSES_COUNT]> =
-        vec![[Score(0.0); PURPOSES_COUNT]; destinations_reached.len()];
+    let mut link_score_contributions: Vec<Vec<Score>> =
+        vec![vec![Score(0.0); purposes_count]; destinations_reached.len()];
     let mut link_start_end_nodes_string: Vec<Vec<String>> = vec![];
     let mut link_is_pt: Vec<u8> = vec![];
     let mut link_route_details: Vec<HashMap<String, String>> = Vec::new();
@@ -93,7 +102,7 @@ pub fn get_all_scores_links_and_key_destinations(
 
         // loop until all links taken to node reached have the score for this node added to their score contributions
         loop {
-            for k in 0..PURPOSES_COUNT {
+            for k in 0..purposes_count {
                 link_score_contributions[link_ix][k] +=
                     node_values_contributed_each_purpose_vec[node_reached_iteration][k];
             }
@@ -153,60 +162,40 @@ pub fn get_all_scores_links_and_key_destinations(
     let now = Instant::now();
 
     // Vec of list of NodeIDs, for where new node is close to 1+ top nodes
-    let mut near_nodes_to_top_node: [HashMap<NodeID, Vec<NodeID>>; PURPOSES_COUNT] = [
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-    ];
+    let mut near_nodes_to_top_node: Vec<HashMap<NodeID, Vec<NodeID>>> = vec![HashMap::new(); purposes_count];
 
     // sets of all nodes which are within N seconds of those in the top n (eg: if there are 24 nodes within 120s of the n top nodes for business, those 24 node ids will be in the set corresponding to business)
-    let mut all_near_nodes: [HashSet<NodeID>; PURPOSES_COUNT] = [
-        HashSet::new(),
-        HashSet::new(),
-        HashSet::new(),
-        HashSet::new(),
-        HashSet::new(),
-        HashSet::new(),
-    ];
+    let mut all_near_nodes: Vec<HashSet<NodeID>> = vec![HashSet::new(); purposes_count];
 
     // track minimum scores for each purpose: this is the threshold to exceed to get into the running top n
-    let mut thresholds_for_update = [NodeScore {
+    let mut thresholds_for_update = vec![NodeScore {
         node: NodeID(0),
         score: Score(0.0),
-    }; PURPOSES_COUNT];
+    }; purposes_count];
 
-    let mut top_nodes = [[NodeScore {
+    let mut top_nodes = vec![[NodeScore {
         node: NodeID(0),
         score: Score(0.0),
-    }; TOP_CLUSTERS_COUNT]; PURPOSES_COUNT];
+    }; TOP_CLUSTERS_COUNT]; purposes_count];
 
     // Dicts of nodeID to near nodes (each Dict will have n keys of node IDs, corresponding to vec of Node IDs in each cluster)
-    let mut top_nodes_to_near_nodes: [HashMap<NodeID, Vec<NodeID>>; PURPOSES_COUNT] = [
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-        HashMap::new(),
-    ];
+    let mut top_nodes_to_near_nodes: Vec<HashMap<NodeID, Vec<NodeID>>> = vec![HashMap::new(); purposes_count];
 
     for DestinationReached { node, .. } in destinations_reached.iter() {
         let near_nodes = &nodes_to_neighbouring_nodes[*node];
-        let mut purpose_scores_current_node = [Score(0.0); PURPOSES_COUNT];
+        let mut purpose_scores_current_node = vec![Score(0.0); purposes_count];
 
         // get total scores by purpose, of nodes within N seconds of this node
         for neighbouring_node in near_nodes {
-            let scores_one_node = sparse_node_values_contributed[*neighbouring_node];
-            for nth_purpose in 0..PURPOSES_COUNT {
+            let scores_one_node = &sparse_node_values_contributed
+                [neighbouring_node.0 * purposes_count..(neighbouring_node.0 + 1) * purposes_count];
+            for nth_purpose in 0..purposes_count {
                 purpose_scores_current_node[nth_purpose] += scores_one_node[nth_purpose];
             }
         }
 
         // Look through each of the purposes: does this node become a top node?
-        for nth_purpose in 0..PURPOSES_COUNT {
+        for nth_purpose in 0..purposes_count {
             if purpose_scores_current_node[nth_purpose] >= thresholds_for_update[nth_purpose].score
             {
                 let mut top_node_may_replace: NodeID = NodeID(0);
@@ -266,9 +255,9 @@ pub fn get_all_scores_links_and_key_destinations(
                     == top_nodes[nth_purpose][top_node_may_replace_ix].score
                 {
                     let purpose_value_node_to_replace =
-                        sparse_node_values_contributed[top_node_may_replace][nth_purpose];
+                        sparse_node_values_contributed[top_node_may_replace.0 * purposes_count + nth_purpose];
                     let purpose_value_node_reached =
-                        sparse_node_values_contributed[*node][nth_purpose];
+                        sparse_node_values_contributed[node.0 * purposes_count + nth_purpose];
                     do_nothing_as_existing_node_score_larger =
                         purpose_value_node_to_replace >= purpose_value_node_reached;
                 }
@@ -356,15 +345,15 @@ pub fn get_all_scores_links_and_key_destinations(
 
     // Nodes of 0s may have remained: this is fine if there are legitimately under TOP_CLUSTERS_COUNT clusters (plausible for rural start nodes);
     // if len if over TOP_CLUSTERS_COUNT it's because the NodeID of 0 is never removed in the above. This fixes that
-    for nth_purpose in 0..PURPOSES_COUNT {
+    for nth_purpose in 0..purposes_count {
         if top_nodes_to_near_nodes[nth_purpose].keys().len() > TOP_CLUSTERS_COUNT {
             top_nodes_to_near_nodes[nth_purpose].remove(&NodeID(0));
         }
     }
 
-    let mut most_important_nodes_longlat: [[[f64; 2]; TOP_CLUSTERS_COUNT]; PURPOSES_COUNT] =
-        [[[0.0; 2]; TOP_CLUSTERS_COUNT]; PURPOSES_COUNT];
-    for i in 0..PURPOSES_COUNT {
+    let mut most_important_nodes_longlat: Vec<[[f64; 2]; TOP_CLUSTERS_COUNT]> =
+        vec![[[0.0; 2]; TOP_CLUSTERS_COUNT]; purposes_count];
+    for i in 0..purposes_count {
         for (inner_iter, rust_node_id) in top_nodes_to_near_nodes[i].keys().enumerate() {
             let node_longlat = rust_node_longlat_lookup[*rust_node_id];
             most_important_nodes_longlat[i][inner_iter] = node_longlat;
@@ -373,7 +362,8 @@ pub fn get_all_scores_links_and_key_destinations(
 
     // ****** Reset sparse_node_values_contributed for next query
     for DestinationReached { node, .. } in destinations_reached.iter() {
-        sparse_node_values_contributed[*node] = [Score(0.0); PURPOSES_COUNT];
+        sparse_node_values_contributed[node.0 * purposes_count..(node.0 + 1) * purposes_count]
+            .fill(Score(0.0));
     }
 
     PlanningToolOutput {
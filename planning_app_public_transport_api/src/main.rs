@@ -1,20 +1,21 @@
-use actix_web::{get, post, web, App, HttpServer};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::sync::Mutex;
 use std::time::Instant;
 use typed_index_collections::TiVec;
 
-use common::floodfill_funcs::get_time_of_day_index;
+use common::columnar_output::{formatted_response, planning_tool_record_batch, text_response, ResponseFormat};
+use common::decay_functions::apply_decay_functions;
 use common::floodfill_public_transport_no_scores::floodfill_public_transport_no_scores;
+use common::search_workspace::SearchWorkspace;
 use common::read_file_funcs::{
     deserialize_bincoded_file, read_files_parallel_excluding_node_values,
-    read_rust_node_longlat_lookup_serial, read_small_files_serial,
+    read_purpose_taxonomy, read_rust_node_longlat_lookup_serial, read_time_periods, read_travel_time_relationships_by_period,
     read_sparse_node_values_2d_serial, read_stop_rail_statuses,
 };
 use common::structs::{
-    Cost, Multiplier, NodeID, NodeRoute, NodeWalk, Score, SubpurposeScore, UserInputJSON,
-    PURPOSES_COUNT,
+    Multiplier, NodeID, NodeRoute, NodeWalk, PurposeTaxonomy, TimePeriods, Score, SubpurposeScore, UserInputJSON,
 };
 use get_all_scores_links_and_key_destinations::get_all_scores_links_and_key_destinations;
 
@@ -23,14 +24,16 @@ mod get_all_scores_links_and_key_destinations;
 use std::env;
 
 struct AppState {
-    travel_time_relationships_all: Vec<Vec<Multiplier>>,
+    travel_time_relationships_all: Vec<Vec<Multiplier>>,   // one per time period
+    time_periods: TimePeriods,
+    purpose_taxonomy: PurposeTaxonomy,
     nodes_to_neighbouring_nodes: TiVec<NodeID, Vec<NodeID>>,
     graph_walk: TiVec<NodeID, NodeWalk>,
     graph_pt: TiVec<NodeID, NodeRoute>,
     node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>>,
     rust_node_longlat_lookup: TiVec<NodeID, [f64; 2]>,
     route_info: TiVec<NodeID, HashMap<String, String>>,
-    mutex_sparse_node_values_contributed: Mutex<TiVec<NodeID, [Score; PURPOSES_COUNT]>>,
+    mutex_sparse_node_values_contributed: Mutex<Vec<Score>>,   // purposes_count scores per node, one node after the other
     stop_rail_statuses: TiVec<NodeID, bool>,
 }
 
@@ -47,19 +50,33 @@ async fn get_node_id_count() -> String {
 }
 
 #[post("/floodfill_pt/")]
-async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<UserInputJSON>) -> String {
-    let time_of_day_ix = get_time_of_day_index(input.trip_start_seconds);
+async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<UserInputJSON>, request: HttpRequest) -> HttpResponse {
+    let format = ResponseFormat::from_request(&request);
+    let period_ix = match data.time_periods.period_index(&input.day_type, input.trip_start_seconds) {
+        Some(period_ix) => period_ix,
+        None => return text_response(format!("No time periods for day_type {}", input.day_type)),
+    };
+    let travel_time_relationships = match apply_decay_functions(
+        &input.decay_functions,
+        &data.travel_time_relationships_all[period_ix],
+        data.purpose_taxonomy.purposes_count(),
+        input.max_travel_time_seconds,
+    ) {
+        Ok(travel_time_relationships) => travel_time_relationships,
+        Err(message) => return text_response(message),
+    };
 
     let now = Instant::now();
     // Only looks at first input if the request has >1 start nodes
     let floodfill_output = floodfill_public_transport_no_scores(
         &data.graph_walk,
         &data.graph_pt,
+        &mut SearchWorkspace::new(data.graph_walk.len()),
         *&input.start_nodes_user_input[0],
         *&input.trip_start_seconds,
         *&input.init_travel_times_user_input[0],
         false,
-        Cost(3600),
+        input.max_travel_time_seconds,
         &data.stop_rail_statuses,
     );
     println!("Floodfill in {:?}", now.elapsed());
@@ -68,10 +85,11 @@ async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<UserInputJSON>
     let results = get_all_scores_links_and_key_destinations(
         &floodfill_output,
         &data.node_values_2d,
-        &data.travel_time_relationships_all[time_of_day_ix],
+        &travel_time_relationships,
         &data.nodes_to_neighbouring_nodes,
         &data.rust_node_longlat_lookup,
         &data.route_info,
+        &data.purpose_taxonomy,
         &data.mutex_sparse_node_values_contributed,
     );
 
@@ -80,7 +98,9 @@ async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<UserInputJSON>
         now.elapsed()
     );
 
-    serde_json::to_string(&results).unwrap()
+    formatted_response(&results, format, |results| {
+        planning_tool_record_batch(results, &data.purpose_taxonomy.purposes)
+    })
 }
 
 #[actix_web::main]
@@ -94,25 +114,14 @@ async fn main() -> std::io::Result<()> {
     let year: i32 = 2022;
     //let seconds_travel_for_destination_clustering = 120;
 
-    let (
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-        _subpurpose_purpose_lookup,
-    ) = read_small_files_serial();
-
-    let travel_time_relationships_all = vec![
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-    ];
+    let time_periods = read_time_periods();
+    let travel_time_relationships_all = read_travel_time_relationships_by_period(&time_periods);
+    let purpose_taxonomy = read_purpose_taxonomy();
 
     let route_info: Vec<HashMap<String, String>> =
         deserialize_bincoded_file(&format!("route_info_{year}"));
     let (graph_walk, graph_pt) = read_files_parallel_excluding_node_values(year);
-    let node_values_2d = read_sparse_node_values_2d_serial(year);
+    let node_values_2d = read_sparse_node_values_2d_serial(year, purpose_taxonomy.subpurposes_count());
     let rust_node_longlat_lookup = read_rust_node_longlat_lookup_serial();
 
     let nodes_to_neighbouring_nodes: Vec<Vec<NodeID>> =
@@ -140,13 +149,11 @@ async fn main() -> std::io::Result<()> {
 
     // create mutex of empty values that nodes contribute. Do this now to save 0.4seconds initialising whenever the API is called. It is reset after each API call
     let now = Instant::now();
-    let sparse_node_values_contributed: Vec<[Score; PURPOSES_COUNT]> = (0..graph_walk.len())
+    let sparse_node_values_contributed: Vec<Score> = (0..graph_walk.len() * purpose_taxonomy.purposes_count())
         .into_par_iter()
-        .map(|_| [Score::default(); PURPOSES_COUNT])
+        .map(|_| Score::default())
         .collect();
-    let non_mutex_sparse_node_values_contributed: TiVec<NodeID, [Score; PURPOSES_COUNT]> =
-        TiVec::from(sparse_node_values_contributed);
-    let mutex_sparse_node_values_contributed = Mutex::new(non_mutex_sparse_node_values_contributed);
+    let mutex_sparse_node_values_contributed = Mutex::new(sparse_node_values_contributed);
     println!("Making sparse node values took {:?}", now.elapsed());
 
     let stop_rail_statuses_input = read_stop_rail_statuses(year);
@@ -154,6 +161,8 @@ async fn main() -> std::io::Result<()> {
 
     let app_state = web::Data::new(AppState {
         travel_time_relationships_all,
+        time_periods,
+        purpose_taxonomy,
         nodes_to_neighbouring_nodes,
         graph_walk,
         graph_pt,
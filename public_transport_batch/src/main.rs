@@ -1,26 +1,51 @@
-use actix_web::{get, post, web, App, HttpServer};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
 use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::time::Instant;
 use typed_index_collections::TiVec;
 
-use common::floodfill_funcs::get_time_of_day_index;
+use common::batch_runner::{response_body, run_requests_command};
+use common::national_run::national_run_command;
+use common::columnar_output::{
+    floodfill_results_record_batch, formatted_response, od_matrix_record_batch, text_response, zone_scores_record_batch,
+    FloodfillResultRow, ResponseFormat,
+};
+use common::competition::{
+    calculate_competition_adjusted_scores, demand_from_one_origin, merge_demand, nodes_with_opportunities,
+    populations_by_start_node,
+};
+use common::decay_functions::apply_decay_functions;
+use common::floodfill_funcs::validate_score_time_bands;
 use common::floodfill_public_transport_purpose_scores::floodfill_public_transport_purpose_scores;
 use common::read_file_funcs::{
-    read_files_parallel_excluding_node_values, read_small_files_serial,
+    read_files_parallel_excluding_node_values, read_rust_node_longlat_lookup_serial, read_time_periods, read_travel_time_relationships_by_period,
     read_sparse_node_values_2d_serial, read_stop_rail_statuses, read_small_medium_large_subpurpose_destinations, 
+    read_purpose_taxonomy, read_zone_definitions,
 };
+use common::search_workspace::with_thread_workspace;
+use common::spatial_index::NodeGridIndex;
+use common::named_output::add_names_to_output;
+use common::ndjson_stream::{ndjson_channel, ndjson_response, ndjson_thread_pool};
+use common::od_matrix::{od_matrix_from_od_pairs, OdMatrixOutput};
+use common::zones::{aggregate_purpose_scores_by_zone, expand_zone_start_nodes};
 use common::structs::{
-    Cost, FloodfillOutputOriginDestinationPair, Multiplier, NodeID, NodeRoute, NodeWalk,
-    OriginDestinationUserInputJSON, SubpurposeScore, SubpurposeSmallMediumLargeCount,
+    Cost, FloodfillOutputOriginDestinationPair, Multiplier, NearestNode, NearestNodeUserInputJSON, NodeID,
+    NodeRoute, NodeWalk, OriginDestinationUserInputJSON, PurposeTaxonomy, TimePeriods, SubpurposeScore,
+    SubpurposeSmallMediumLargeCount, ZoneStartNode,
 };
 
 struct AppState {
-    travel_time_relationships_all: Vec<Vec<Multiplier>>,
+    travel_time_relationships_all: Vec<Vec<Multiplier>>,   // one per time period
+    time_periods: TimePeriods,
+    purpose_taxonomy: PurposeTaxonomy,
     graph_walk: TiVec<NodeID, NodeWalk>,
     graph_routes: TiVec<NodeID, NodeRoute>,
     node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>>,
     stop_rail_statuses: TiVec<NodeID, bool>,
     small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>>,
+    rust_node_longlat_lookup: TiVec<NodeID, [f64; 2]>,
+    node_grid_index: NodeGridIndex,
+    zone_definitions: HashMap<String, HashMap<String, Vec<ZoneStartNode>>>,   // zone type -> zone ID -> start nodes
 }
 
 #[get("/")]
@@ -28,16 +53,82 @@ async fn index() -> String {
     format!("App is listening")
 }
 
-#[post("/floodfill_pt/")]
-async fn floodfill_pt(
+// Returns the nodes nearest to a long/lat point, with estimated walk times to each: these can be
+// used as start_nodes and init_travel_times in requests to /floodfill_pt/
+#[post("/nearest_node/")]
+async fn nearest_node(
     data: web::Data<AppState>,
-    input: web::Json<OriginDestinationUserInputJSON>,
+    input: web::Json<NearestNodeUserInputJSON>,
 ) -> String {
-    let time_of_day_ix = get_time_of_day_index(input.trip_start_seconds);
+    let nearest_nodes: Vec<NearestNode> = data.node_grid_index.find_nearest_nodes(
+        &data.rust_node_longlat_lookup,
+        [input.longitude, input.latitude],
+        input.nodes_count,
+    );
+    serde_json::to_string(&nearest_nodes).unwrap()
+}
+
+// Everything the floodfills of one request need, read and checked once. Owned rather than borrowed from
+// the handler, so streamed floodfills can carry on after it returns
+struct PreparedFloodfills {
+    data: web::Data<AppState>,
+    input: OriginDestinationUserInputJSON,
+    travel_time_relationships: Vec<Multiplier>,
+    zone_start_nodes: Vec<(usize, ZoneStartNode)>,
+    start_nodes: Vec<NodeID>,
+    init_travel_times: Vec<Cost>,
+    count_destinations_at_intervals: bool,
+}
+
+impl PreparedFloodfills {
+    // Each rayon worker reuses its thread's workspace across the start nodes it takes
+    fn run_floodfill(&self, i: usize, destination_nodes: &Vec<NodeID>) -> FloodfillOutputOriginDestinationPair {
+        let data = &self.data;
+        let input = &self.input;
+        with_thread_workspace(data.graph_walk.len(), |workspace| floodfill_public_transport_purpose_scores(
+            &data.graph_walk,
+            &data.graph_routes,
+            workspace,
+            self.start_nodes[i],
+            input.trip_start_seconds,
+            self.init_travel_times[i],
+            false,
+            input.max_travel_time_seconds,
+            &data.node_values_2d,
+            &self.travel_time_relationships,
+            &data.purpose_taxonomy,
+            destination_nodes,
+            &data.stop_rail_statuses,
+            &data.small_medium_large_subpurpose_destinations,
+            self.count_destinations_at_intervals,
+            &input.original_time_intervals_to_store_destination_counts,
+            input.accessibility_metrics.as_ref(),
+            input.return_subpurpose_scores == 1,
+            &input.score_time_bands,
+        ))
+    }
+}
+
+// Errors are the message to send back
+fn prepare_floodfills(
+    data: web::Data<AppState>,
+    input: OriginDestinationUserInputJSON,
+) -> Result<PreparedFloodfills, String> {
+    let period_ix = match data.time_periods.period_index(&input.day_type, input.trip_start_seconds) {
+        Some(period_ix) => period_ix,
+        None => return Err(format!("No time periods for day_type {}", input.day_type)),
+    };
+    let travel_time_relationships = apply_decay_functions(
+        &input.decay_functions,
+        &data.travel_time_relationships_all[period_ix],
+        data.purpose_taxonomy.purposes_count(),
+        input.max_travel_time_seconds,
+    )?;
+    validate_score_time_bands(&input.score_time_bands)?;
 
     println!(
-        "Started running floodfill and node values files read\ttime_of_day_ix: {}\tNodes count: {}",
-        time_of_day_ix,
+        "Started running floodfill and node values files read\ttime period: {}\tNodes count: {}",
+        data.time_periods.periods[period_ix].name,
         input.start_nodes.len()
     );
     
@@ -47,34 +138,248 @@ async fn floodfill_pt(
     }
 
 
+    // When zones are requested, run from every start node of every zone
+    let mut zone_start_nodes = Vec::new();
+    let mut start_nodes = input.start_nodes.clone();
+    let mut init_travel_times = input.init_travel_times.clone();
+    if !input.zone_ids.is_empty() {
+        let zone_definitions = match data.zone_definitions.get(&input.zone_type) {
+            Some(zone_definitions) => zone_definitions,
+            None => return Err(format!("Unknown zone_type {}", input.zone_type)),
+        };
+        zone_start_nodes = expand_zone_start_nodes(&input.zone_ids, zone_definitions)?;
+        start_nodes = zone_start_nodes.iter().map(|(_, start_node)| start_node.node).collect();
+        init_travel_times = zone_start_nodes
+            .iter()
+            .map(|(_, start_node)| start_node.seconds_walk_to_start_node)
+            .collect();
+    }
+
+    Ok(PreparedFloodfills {
+        data,
+        input,
+        travel_time_relationships,
+        zone_start_nodes,
+        start_nodes,
+        init_travel_times,
+        count_destinations_at_intervals,
+    })
+}
+
+#[post("/floodfill_pt/")]
+async fn floodfill_pt(
+    data: web::Data<AppState>,
+    input: web::Json<OriginDestinationUserInputJSON>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = ResponseFormat::from_request(&request);
+    match floodfill_pt_response(data, input.into_inner(), format) {
+        Ok(response) => response,
+        Err(message) => text_response(message),
+    }
+}
+
+// The /floodfill_pt/ response, also used for offline runs. Errors are the message to send back
+fn floodfill_pt_response(
+    data: web::Data<AppState>,
+    input: OriginDestinationUserInputJSON,
+    format: ResponseFormat,
+) -> Result<HttpResponse, String> {
+    let prepared = prepare_floodfills(data.clone(), input)?;
+    let PreparedFloodfills {
+        input,
+        travel_time_relationships,
+        zone_start_nodes,
+        start_nodes,
+        ..
+    } = &prepared;
+
+    if input.od_matrix.is_some() && (!input.zone_ids.is_empty() || input.competition.is_some()) {
+        return Err("od_matrix can't be combined with zone_ids or competition".to_string());
+    }
+
     let now = Instant::now();
-    let indices = (0..input.start_nodes.len()).collect::<Vec<_>>();
+    let indices = (0..start_nodes.len()).collect::<Vec<_>>();
+
+    let run_floodfill = |i: usize, destination_nodes: &Vec<NodeID>| prepared.run_floodfill(i, destination_nodes);
+
+    // For competition adjusted scores, the first pass finds the demand at every destination. Both passes
+    // need OD pairs to every node with opportunities, on top of any destination_nodes requested
+    let mut destination_nodes = input.destination_nodes.clone();
+    let mut demand = None;
+    if let Some(competition) = &input.competition {
+        let populations = populations_by_start_node(
+            &competition.populations,
+            start_nodes.len(),
+            &input.zone_ids,
+            zone_start_nodes,
+        )?;
+        let opportunity_nodes = nodes_with_opportunities(&data.node_values_2d);
+        demand = Some(
+            indices
+                .par_iter()
+                .map(|i| {
+                    let result = run_floodfill(*i, &opportunity_nodes);
+                    demand_from_one_origin(
+                        &result.od_pairs_found_rail_adjusted,
+                        populations[*i],
+                        travel_time_relationships,
+                        data.purpose_taxonomy.purposes_count(),
+                    )
+                })
+                .reduce(HashMap::new, merge_demand),
+        );
+        destination_nodes.extend(opportunity_nodes);
+        println!("Competition demand found in {:?}", now.elapsed());
+    }
 
-    let results: Vec<FloodfillOutputOriginDestinationPair> = indices
+    let mut results: Vec<FloodfillOutputOriginDestinationPair> = indices
         .par_iter()
-        .map(|i| {
-            floodfill_public_transport_purpose_scores(
-                &data.graph_walk,
-                &data.graph_routes,
-                *&input.start_nodes[*i],
-                *&input.trip_start_seconds,
-                *&input.init_travel_times[*i],
-                false,
-                Cost(3600),
-                &data.node_values_2d,
-                &data.travel_time_relationships_all[time_of_day_ix],
-                &input.destination_nodes,
-                &data.stop_rail_statuses,
-                &data.small_medium_large_subpurpose_destinations,
-                count_destinations_at_intervals,
-                &input.original_time_intervals_to_store_destination_counts,
-            )
-        })
+        .map(|i| run_floodfill(*i, &destination_nodes))
         .collect();
 
+    if let Some(od_matrix_request) = &input.od_matrix {
+        let matrix = od_matrix_from_od_pairs(results.iter().map(|result| &result.od_pairs_found), &destination_nodes);
+        println!("OD matrix found in {:?}", now.elapsed());
+        return Ok(formatted_response(
+            &OdMatrixOutput::new(start_nodes, &destination_nodes, matrix, od_matrix_request.format),
+            format,
+            od_matrix_record_batch,
+        ));
+    }
+
+    if let Some(demand) = demand {
+        let requested_destination_nodes: HashSet<usize> =
+            input.destination_nodes.iter().map(|node| node.0).collect();
+        results.par_iter_mut().for_each(|result| {
+            result.competition_adjusted_scores = Some(calculate_competition_adjusted_scores(
+                &result.od_pairs_found_rail_adjusted,
+                &demand,
+                &data.node_values_2d,
+                travel_time_relationships,
+                &data.purpose_taxonomy,
+            ));
+            result
+                .od_pairs_found
+                .retain(|[_, node]| requested_destination_nodes.contains(node));
+        });
+    }
+
     println!("Floodfill in {:?}", now.elapsed());
     println!("results len {}", results.len());
-    serde_json::to_string(&results).unwrap()
+
+    if !input.zone_ids.is_empty() {
+        let purpose_scores: Vec<_> = results.iter().map(|result| result.purpose_scores.clone()).collect();
+        let mut zone_scores = aggregate_purpose_scores_by_zone(
+            &input.zone_ids,
+            zone_start_nodes,
+            &purpose_scores,
+            data.purpose_taxonomy.purposes_count(),
+        );
+        if input.competition.is_some() {
+            let competition_purpose_scores: Vec<_> = results
+                .iter()
+                .map(|result| result.competition_adjusted_scores.as_ref().unwrap().purpose_scores.clone())
+                .collect();
+            let competition_zone_scores = aggregate_purpose_scores_by_zone(
+                &input.zone_ids,
+                zone_start_nodes,
+                &competition_purpose_scores,
+                data.purpose_taxonomy.purposes_count(),
+            );
+            for (zone, competition_zone) in zone_scores.iter_mut().zip(competition_zone_scores) {
+                zone.competition_adjusted_purpose_scores = Some(competition_zone.purpose_scores);
+            }
+        }
+        // Columnar formats name scores by purpose whatever named_output is
+        if input.named_output == 1 && !format.is_columnar() {
+            let named_zone_scores: Vec<_> = zone_scores
+                .iter()
+                .map(|zone| add_names_to_output(zone, &data.purpose_taxonomy, &zone.purpose_scores, &[]))
+                .collect();
+            return Ok(text_response(serde_json::to_string(&named_zone_scores).unwrap()));
+        }
+        return Ok(formatted_response(&zone_scores, format, |zone_scores| {
+            zone_scores_record_batch(zone_scores, &data.purpose_taxonomy.purposes)
+        }));
+    }
+    if input.named_output == 1 && !format.is_columnar() {
+        let named_results: Vec<_> = results
+            .iter()
+            .map(|result| {
+                add_names_to_output(
+                    result,
+                    &data.purpose_taxonomy,
+                    &result.purpose_scores,
+                    &result.destinations_reached_at_time_intervals,
+                )
+            })
+            .collect();
+        return Ok(text_response(serde_json::to_string(&named_results).unwrap()));
+    }
+    Ok(formatted_response(&results, format, |results| {
+        let rows: Vec<FloodfillResultRow> = results.iter().map(FloodfillResultRow::from).collect();
+        floodfill_results_record_batch(&rows, &data.purpose_taxonomy.purposes)
+    }))
+}
+
+// Same payload as /floodfill_pt/, but returns one JSON line per start node as its floodfill finishes,
+// rather than every result at the end. zone_ids, competition and od_matrix need every result together,
+// so aren't available here
+#[post("/floodfill_pt_ndjson/")]
+async fn floodfill_pt_ndjson(
+    data: web::Data<AppState>,
+    input: web::Json<OriginDestinationUserInputJSON>,
+) -> HttpResponse {
+    let prepared = match prepare_floodfills(data.clone(), input.into_inner()) {
+        Ok(prepared) => prepared,
+        Err(message) => return HttpResponse::Ok().body(message),
+    };
+    if !prepared.input.zone_ids.is_empty() || prepared.input.competition.is_some() || prepared.input.od_matrix.is_some() {
+        return HttpResponse::Ok().body("zone_ids, competition and od_matrix aren't available when streaming");
+    }
+
+    let (sender, body) = ndjson_channel();
+    std::thread::spawn(move || {
+        let now = Instant::now();
+        let sent_all = ndjson_thread_pool().install(|| {
+            (0..prepared.start_nodes.len()).into_par_iter().try_for_each(|i| {
+                let result = prepared.run_floodfill(i, &prepared.input.destination_nodes);
+                let sent = if prepared.input.named_output == 1 {
+                    sender.send(
+                        i,
+                        &add_names_to_output(
+                            &result,
+                            &prepared.data.purpose_taxonomy,
+                            &result.purpose_scores,
+                            &result.destinations_reached_at_time_intervals,
+                        ),
+                    )
+                } else {
+                    sender.send(i, &result)
+                };
+                if sent { Ok(()) } else { Err(()) }
+            })
+        });
+        if sent_all.is_err() {
+            println!("Client went away: stopped streaming floodfills");
+        }
+        println!("Streamed floodfills took {:?}", now.elapsed());
+    });
+    ndjson_response(body)
+}
+
+fn run_command(data: web::Data<AppState>, args: &[String]) {
+    let run_request = |line: &str| {
+        let input: OriginDestinationUserInputJSON =
+            serde_json::from_str(line).map_err(|error| format!("Couldn't parse request: {}", error))?;
+        floodfill_pt_response(data.clone(), input, ResponseFormat::Json).map(response_body)
+    };
+    match args[0].as_str() {
+        "run_requests" => run_requests_command(&args[1..], run_request),
+        "national_run" => national_run_command(&args[1..], "start_nodes", "init_travel_times", run_request),
+        command => panic!("Unknown command {}: the commands are run_requests and national_run", command),
+    }
 }
 
 #[actix_web::main]
@@ -84,23 +389,12 @@ async fn main() -> std::io::Result<()> {
 
     let year: i32 = 2022;
 
-    let (
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-        _subpurpose_purpose_lookup,
-    ) = read_small_files_serial();
-
-    let travel_time_relationships_all = vec![
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-    ];
+    let time_periods = read_time_periods();
+    let travel_time_relationships_all = read_travel_time_relationships_by_period(&time_periods);
+    let purpose_taxonomy = read_purpose_taxonomy();
 
     let (graph_walk, graph_routes) = read_files_parallel_excluding_node_values(year);
-    let node_values_2d = read_sparse_node_values_2d_serial(year);
+    let node_values_2d = read_sparse_node_values_2d_serial(year, purpose_taxonomy.subpurposes_count());
 
     let graph_walk: TiVec<NodeID, NodeWalk> = TiVec::from(graph_walk);
     let graph_routes: TiVec<NodeID, NodeRoute> = TiVec::from(graph_routes);
@@ -112,14 +406,40 @@ async fn main() -> std::io::Result<()> {
     let small_medium_large_subpurpose_destinations_input = read_small_medium_large_subpurpose_destinations("PT");
     let small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>> = TiVec::from(small_medium_large_subpurpose_destinations_input);
     
+    let rust_node_longlat_lookup: TiVec<NodeID, [f64; 2]> = TiVec::from(read_rust_node_longlat_lookup_serial());
+    let now = Instant::now();
+    let node_grid_index = NodeGridIndex::new(&rust_node_longlat_lookup);
+    println!("Building node grid index took {:?}", now.elapsed());
+
+    let mut zone_definitions = HashMap::new();
+    for zone_type in ["LSOA", "OA"] {
+        zone_definitions.insert(zone_type.to_string(), read_zone_definitions(zone_type, "PT"));
+    }
+
     let app_state = web::Data::new(AppState {
         travel_time_relationships_all,
+        time_periods,
+        purpose_taxonomy,
         graph_walk,
         graph_routes,
         node_values_2d,
         stop_rail_statuses,
         small_medium_large_subpurpose_destinations,
+        rust_node_longlat_lookup,
+        node_grid_index,
+        zone_definitions,
     });
+
+    // `public_transport_batch run_requests <requests.jsonl> <output_dir> [requests_per_shard]` runs a
+    // file of /floodfill_pt/ requests without starting the server, for runs longer than HTTP timeouts allow.
+    // `public_transport_batch national_run <template_request.json> <output_dir> [origins_per_chunk]
+    // [max_attempts]` runs one request with very many origins in resumable chunks
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        run_command(app_state, &args);
+        return Ok(());
+    }
+
     println!("Starting server");
     // The 500MB warning is wrong, so we 'allow deprecated' to hide it
     #[allow(deprecated)]
@@ -131,6 +451,8 @@ async fn main() -> std::io::Result<()> {
             .data(web::JsonConfig::default().limit(1024 * 1024 * 500)) // allow POST'd JSON payloads up to 500mb
             .service(index)
             .service(floodfill_pt)
+            .service(floodfill_pt_ndjson)
+            .service(nearest_node)
     })
     .bind(("0.0.0.0", 7328))?
     .run()
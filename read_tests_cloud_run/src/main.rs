@@ -3,7 +3,7 @@ use fs_err::File;
 use std::io::BufWriter;
 use std::time::Instant;
 
-use common::read_file_funcs::{deserialize_bincoded_file, read_files_parallel_inc_node_values};
+use common::read_file_funcs::{deserialize_bincoded_file, read_files_parallel_inc_node_values, read_purpose_taxonomy};
 use common::structs::{NodeRoute, NodeWalk, SubpurposeScore};
 
 #[actix_web::main]
@@ -31,7 +31,7 @@ async fn run_tests() -> String {
     let year = 2022;
 
     let now = Instant::now();
-    let (_node_values_2d, graph_walk, graph_routes) = read_files_parallel_inc_node_values(year);
+    let (_node_values_2d, graph_walk, graph_routes) = read_files_parallel_inc_node_values(year, read_purpose_taxonomy().subpurposes_count());
     println!("Standard loading took {:?}", now.elapsed());
 
     // **** Creating for 3 chunks of each file
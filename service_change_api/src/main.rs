@@ -5,19 +5,25 @@ use smallvec::SmallVec;
 use std::time::Instant;
 use typed_index_collections::TiVec;
 
-use common::floodfill_funcs::get_time_of_day_index;
+use common::decay_functions::apply_decay_functions;
+use common::floodfill_funcs::validate_score_time_bands;
 use common::floodfill_public_transport_purpose_scores::floodfill_public_transport_purpose_scores;
+use common::named_output::add_names_to_output;
+use common::search_workspace::with_thread_workspace;
 use common::read_file_funcs::{
-    deserialize_bincoded_file, read_files_extra_parallel_inc_node_values, read_small_files_serial,
-    read_stop_rail_statuses, read_small_medium_large_subpurpose_destinations, 
+    deserialize_bincoded_file, read_files_extra_parallel_inc_node_values, read_purpose_taxonomy,
+    read_time_periods, read_travel_time_relationships_by_period, read_stop_rail_statuses, read_small_medium_large_subpurpose_destinations, 
 };
 use common::structs::{
     Cost, EdgeRoute, EdgeWalk, FloodfillOutputOriginDestinationPair, Multiplier, NodeID, NodeRoute,
-    NodeWalk, Score, SecondsPastMidnight, ServiceChangePayload, SubpurposeScore, SubpurposeSmallMediumLargeCount, 
+    NodeWalk, PurposeTaxonomy, TimePeriods, Score, SecondsPastMidnight, ServiceChangePayload, SubpurposeScore,
+    SubpurposeSmallMediumLargeCount, 
 };
 
 struct AppState {
-    travel_time_relationships_all: Vec<Vec<Multiplier>>,
+    travel_time_relationships_all: Vec<Vec<Multiplier>>,   // one per time period
+    time_periods: TimePeriods,
+    purpose_taxonomy: PurposeTaxonomy,
     small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>>,
 }
 
@@ -52,7 +58,7 @@ async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<ServiceChangeP
     // Tests originally showed read_files_extra_parallel_inc_node_values as faster in cloud run but slower on server; (adam 18th may)
     // Further tests show read_files_parallel_inc_node_values may be marginally faster in all cases (adam 19th May)
     let (node_values_2d, graph_walk, graph_routes) =
-        read_files_extra_parallel_inc_node_values(input.year);
+        read_files_extra_parallel_inc_node_values(input.year, data.purpose_taxonomy.subpurposes_count());
 
     let mut graph_walk: TiVec<NodeID, NodeWalk> = TiVec::from(graph_walk);
     let mut graph_routes: TiVec<NodeID, NodeRoute> = TiVec::from(graph_routes);
@@ -62,7 +68,22 @@ async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<ServiceChangeP
     let graph_routes_og_len = graph_routes.len();
     let node_values_2d_og_len = node_values_2d.len();
 
-    let time_of_day_ix = get_time_of_day_index(input.trip_start_seconds);
+    let period_ix = match data.time_periods.period_index(&input.day_type, input.trip_start_seconds) {
+        Some(period_ix) => period_ix,
+        None => return format!("No time periods for day_type {}", input.day_type),
+    };
+    let travel_time_relationships = match apply_decay_functions(
+        &input.decay_functions,
+        &data.travel_time_relationships_all[period_ix],
+        data.purpose_taxonomy.purposes_count(),
+        input.max_travel_time_seconds,
+    ) {
+        Ok(travel_time_relationships) => travel_time_relationships,
+        Err(message) => return message,
+    };
+    if let Err(message) = validate_score_time_bands(&input.score_time_bands) {
+        return message;
+    }
 
     // Make new routes nodes, and walking links from those nodes to new nodes
     for input_edges in input.graph_walk_additions.iter() {
@@ -177,26 +198,35 @@ async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<ServiceChangeP
     let now = Instant::now();
     let indices = (0..input.start_nodes.len()).collect::<Vec<_>>();
 
+    // Each rayon worker keeps its workspace after this request, sized for the largest graph_walk it has
+    // searched: the base graph plus any nodes a request added. That's about 24 bytes per node and 4 per link
+    // for each worker, so a few hundred MB each on the national graph, held until the server stops and
+    // reused by the next request rather than reallocated
     let results: Vec<FloodfillOutputOriginDestinationPair> = indices
         .par_iter()
-        .map(|i| {
+        .map(|i| with_thread_workspace(graph_walk.len(), |workspace| {
             floodfill_public_transport_purpose_scores(
                 &graph_walk,
                 &graph_routes,
+                workspace,
                 input.start_nodes[*i],
                 input.trip_start_seconds,
                 input.init_travel_times[*i],
                 false,
-                Cost(3600),
+                input.max_travel_time_seconds,
                 &node_values_2d,
-                &data.travel_time_relationships_all[time_of_day_ix],
+                &travel_time_relationships,
+                &data.purpose_taxonomy,
                 &input.target_destinations,
                 &stop_rail_statuses,
                 &data.small_medium_large_subpurpose_destinations,
                 count_destinations_at_intervals,
                 &input.original_time_intervals_to_store_destination_counts,
+                input.accessibility_metrics.as_ref(),
+                input.return_subpurpose_scores == 1,
+                &input.score_time_bands,
             )
-        })
+        }))
         .collect();
     println!("Getting destinations and scores took {:?}", now.elapsed());
 
@@ -258,6 +288,20 @@ async fn floodfill_pt(data: web::Data<AppState>, input: web::Json<ServiceChangeP
     assert!(graph_routes_og_len == graph_routes.len());
     assert!(node_values_2d_og_len == node_values_2d.len());
 
+    if input.named_output == 1 {
+        let named_results: Vec<_> = results
+            .iter()
+            .map(|result| {
+                add_names_to_output(
+                    result,
+                    &data.purpose_taxonomy,
+                    &result.purpose_scores,
+                    &result.destinations_reached_at_time_intervals,
+                )
+            })
+            .collect();
+        return serde_json::to_string(&named_results).unwrap();
+    }
     serde_json::to_string(&results).unwrap()
 }
 
@@ -267,25 +311,16 @@ async fn main() -> std::io::Result<()> {
         .filter_level(LevelFilter::Debug)
         .init();
 
-    let (
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-        _subpurpose_purpose_lookup,
-    ) = read_small_files_serial();
-
-    let travel_time_relationships_all = vec![
-        travel_time_relationships_7,
-        travel_time_relationships_10,
-        travel_time_relationships_16,
-        travel_time_relationships_19,
-    ];
+    let time_periods = read_time_periods();
+    let travel_time_relationships_all = read_travel_time_relationships_by_period(&time_periods);
+    let purpose_taxonomy = read_purpose_taxonomy();
     let small_medium_large_subpurpose_destinations_input = read_small_medium_large_subpurpose_destinations("PT");
     let small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>> = TiVec::from(small_medium_large_subpurpose_destinations_input);
     
     let app_state = web::Data::new(AppState {
         travel_time_relationships_all,
+        time_periods,
+        purpose_taxonomy,
         small_medium_large_subpurpose_destinations,
     });
 
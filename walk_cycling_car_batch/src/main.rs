@@ -1,27 +1,161 @@
-use actix_web::{get, post, web, App, HttpServer};
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
 use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 use std::time::Instant;
 use typed_index_collections::TiVec;
 
-use common::floodfill_funcs::get_time_of_day_index;
+use common::batch_runner::{response_body, run_requests_command};
+use common::national_run::national_run_command;
+use common::columnar_output::{
+    floodfill_results_record_batch, formatted_response, od_matrix_record_batch, text_response, zone_scores_record_batch,
+    FloodfillResultRow, ResponseFormat,
+};
+use common::competition::{
+    calculate_competition_adjusted_scores, demand_from_one_origin, merge_demand, nodes_with_opportunities,
+    populations_by_start_node,
+};
+use common::decay_functions::apply_decay_functions;
+use common::floodfill_funcs::validate_score_time_bands;
 use common::floodfill_walk_cycling_car::floodfill_walk_cycling_car;
 use common::read_file_funcs::{
-    read_car_nodes_is_closest_to_pt, read_files_serial_walk_cycling_car, 
-    read_small_medium_large_subpurpose_destinations,
+    read_car_nodes_is_closest_to_pt, read_files_serial_walk_cycling_car, read_graph_walk_cycling_car_for_period,
+    read_link_cost_profiles, read_link_is_major_road, read_node_longlat_lookup_walk_cycling_car, read_purpose_taxonomy,
+    read_contraction_hierarchy, read_small_medium_large_subpurpose_destinations, read_time_periods,
+    read_turn_cost_profiles, read_zone_definitions,
 };
+use common::named_output::add_names_to_output;
+use common::ndjson_stream::{ndjson_channel, ndjson_response, ndjson_thread_pool};
+use common::od_matrix::{od_matrix_from_od_pairs, OdMatrixOutput};
+use common::routing::{find_route_walk_cycling_car, min_cost_per_metre, RouteHeuristic};
+use common::search_workspace::{with_thread_workspace, SearchWorkspace};
+use common::spatial_index::NodeGridIndex;
+use common::turn_costs::{MajorRoads, TurnCostProfile, TurnCostProfiles};
+use common::zones::{aggregate_purpose_scores_by_zone, expand_zone_start_nodes};
 use common::structs::{
-    Cost, FloodfillOutputOriginDestinationPairWalkCyclingCar, NodeID, NodeWalkCyclingCar, SubpurposeScore,
-    WalkCyclingCarUserInputJSON, SubpurposeSmallMediumLargeCount,
+    Cost, FloodfillOutputOriginDestinationPairWalkCyclingCar, LinkCostProfiles, Multiplier, NearestNode,
+    NearestNodeUserInputJSON, NodeID, NodeWalkCyclingCar, PurposeTaxonomy, RouteUserInputJSON, SubpurposeScore, TimePeriods, WalkCyclingCarUserInputJSON, ZoneStartNode, SubpurposeSmallMediumLargeCount,
 };
 
+// Holds what is small enough to read for every mode up front: graphs are read per request
+struct AppState {
+    purpose_taxonomy: PurposeTaxonomy,
+    time_periods: TimePeriods,
+    turn_cost_profiles: TurnCostProfiles,
+    zone_definitions: HashMap<String, HashMap<String, HashMap<String, Vec<ZoneStartNode>>>>,   // mode -> zone type -> zone ID -> start nodes
+    route_cost_per_metre: Mutex<HashMap<(String, String, bool), f64>>,   // (mode, file suffix, optimise_on_distance) -> the /route/ heuristic's cost per metre, found on first use
+    node_grid_indexes: HashMap<String, (TiVec<NodeID, [f64; 2]>, NodeGridIndex)>,   // mode -> its nodes' long/lat and a grid over them, for modes with coordinates
+}
+
 #[get("/")]
 async fn index() -> String {
     println!("Ping received");
     format!("App is listening")
 }
 
-#[post("/floodfill_endpoint/")]
-async fn floodfill_endpoint(input: web::Json<WalkCyclingCarUserInputJSON>) -> String {
+// Returns the nodes of the mode's graph nearest to a long/lat point, with estimated walk times to each:
+// these can be used as start_nodes_user_input and init_travel_times_user_input in /floodfill_endpoint/
+#[post("/nearest_node/")]
+async fn nearest_node(
+    data: web::Data<AppState>,
+    input: web::Json<NearestNodeUserInputJSON>,
+) -> String {
+    let (node_longlat_lookup, node_grid_index) = match data.node_grid_indexes.get(&input.mode) {
+        Some(node_grid_index) => node_grid_index,
+        None => return format!("No coordinates for mode {}", input.mode),
+    };
+    let nearest_nodes: Vec<NearestNode> = node_grid_index.find_nearest_nodes(
+        node_longlat_lookup,
+        [input.longitude, input.latitude],
+        input.nodes_count,
+    );
+    serde_json::to_string(&nearest_nodes).unwrap()
+}
+
+// Everything the floodfills of one request need, read and checked once. Owned rather than borrowed from
+// the handler, so streamed floodfills can carry on after it returns
+struct PreparedFloodfills {
+    data: web::Data<AppState>,
+    input: WalkCyclingCarUserInputJSON,
+    file_suffix: String,
+    travel_time_relationships: Vec<Multiplier>,
+    node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>>,
+    graph: TiVec<NodeID, NodeWalkCyclingCar>,
+    turn_cost_profile: TurnCostProfile,
+    major_roads: Option<MajorRoads>,
+    link_cost_profiles: Option<LinkCostProfiles>,
+    small_medium_large_subpurpose_destinations: TiVec<NodeID, Vec<SubpurposeSmallMediumLargeCount>>,
+    car_nodes_is_closest_to_pt: TiVec<NodeID, bool>,
+    zone_start_nodes: Vec<(usize, ZoneStartNode)>,
+    start_nodes: Vec<NodeID>,
+    init_travel_times: Vec<Cost>,
+    track_pt_nodes_reached: bool,
+    count_destinations_at_intervals: bool,
+    optimise_on_distance: bool,
+    return_other_cost: bool,
+}
+
+impl PreparedFloodfills {
+    // Each rayon worker reuses its thread's workspace across the start nodes it takes
+    fn run_floodfill(&self, i: usize, destination_nodes: &[NodeID]) -> FloodfillOutputOriginDestinationPairWalkCyclingCar {
+        let input = &self.input;
+        with_thread_workspace(self.graph.len(), |workspace| floodfill_walk_cycling_car(
+            &self.travel_time_relationships,
+            &self.data.purpose_taxonomy,
+            &self.node_values_2d,
+            &self.graph,
+            workspace,
+            &self.turn_cost_profile,
+            self.major_roads.as_ref(),
+            self.start_nodes[i],
+            self.init_travel_times[i],
+            destination_nodes,
+            input.max_travel_time_seconds,
+            &input.mode,
+            self.track_pt_nodes_reached,
+            input.seconds_reclaimed_when_pt_stop_reached,
+            input.target_node,
+            &self.car_nodes_is_closest_to_pt,
+            &self.small_medium_large_subpurpose_destinations,
+            self.count_destinations_at_intervals,
+            &input.original_time_intervals_to_store_destination_counts,
+            input.accessibility_metrics.as_ref(),
+            input.return_subpurpose_scores == 1,
+            &input.score_time_bands,
+            self.optimise_on_distance,
+            self.return_other_cost,
+            self.link_cost_profiles.as_ref(),
+            input.trip_start_seconds,
+            &self.data.time_periods,
+            &input.day_type,
+        ))
+    }
+}
+
+// Major roads are only read when the turn cost profile penalises crossing them
+fn read_major_roads_if_needed(
+    mode: &str,
+    turn_cost_profile: &TurnCostProfile,
+    graph: &TiVec<NodeID, NodeWalkCyclingCar>,
+) -> Result<Option<MajorRoads>, String> {
+    if turn_cost_profile.major_road_crossing_seconds == Cost(0) {
+        return Ok(None);
+    }
+    match read_link_is_major_road(mode) {
+        Some(link_is_major_road) => Ok(Some(MajorRoads::new(link_is_major_road, graph))),
+        None => Err(format!(
+            "No {}_link_is_major_road.bin in serialised_data, so major_road_crossing_seconds can't be used for mode {}",
+            mode, mode
+        )),
+    }
+}
+
+// Reads the files for the request's mode and time period and checks them against it. Errors are the
+// message to send back
+fn prepare_floodfills(
+    data: web::Data<AppState>,
+    input: WalkCyclingCarUserInputJSON,
+) -> Result<PreparedFloodfills, String> {
     
     // convert 0/1 format to binary
     let mut track_pt_nodes_reached = false;
@@ -34,31 +168,35 @@ async fn floodfill_endpoint(input: web::Json<WalkCyclingCarUserInputJSON>) -> St
         count_destinations_at_intervals = true;
     }
 
-    let time_of_day_index = get_time_of_day_index(input.trip_start_seconds);
-    let mut start_time_group: usize = 7;
-    if time_of_day_index == 1 {
-        start_time_group = 10;
-    }
-    if time_of_day_index == 2 {
-        start_time_group = 16;
-    }
-    if time_of_day_index == 3 {
-        start_time_group = 19;
-    }
+    let period_ix = match data.time_periods.period_index(&input.day_type, input.trip_start_seconds) {
+        Some(period_ix) => period_ix,
+        None => return Err(format!("No time periods for day_type {}", input.day_type)),
+    };
+    let file_suffix = match data.time_periods.periods[period_ix].file_suffixes.get(&input.mode) {
+        Some(file_suffix) => file_suffix.clone(),
+        None => return Err(format!("No files for mode {} in time period {}", input.mode, data.time_periods.periods[period_ix].name)),
+    };
 
-    if input.time_or_distance == "distance" && input.mode == "car" {
+    let optimise_on_distance = input.time_or_distance == "distance";
+    if optimise_on_distance {
         println!("Distance request received");
-        start_time_group = 1; // start_time_group of 1 refers to the distance driving graph
     }
 
     println!(
-        "time_of_day_index {} and start_time_group {} for trip_start_seconds {}",
-        time_of_day_index, start_time_group, input.trip_start_seconds.0
+        "Time period {} and file suffix {} for trip_start_seconds {}",
+        data.time_periods.periods[period_ix].name, file_suffix, input.trip_start_seconds.0
     );
 
     // Read in files at endpoint rather than in advance as we don't know which mode the user will request
     let (travel_time_relationships, node_values_2d, graph) =
-        read_files_serial_walk_cycling_car(&input.mode, start_time_group);
+        read_files_serial_walk_cycling_car(&input.mode, &file_suffix, data.purpose_taxonomy.subpurposes_count());
+    let travel_time_relationships = apply_decay_functions(
+        &input.decay_functions,
+        &travel_time_relationships,
+        data.purpose_taxonomy.purposes_count(),
+        input.max_travel_time_seconds,
+    )?;
+    validate_score_time_bands(&input.score_time_bands)?;
     
     // Read and convert to TiVec, which allows you to index the vector with NodeID type, rather than usize type
     let small_medium_large_subpurpose_destinations = read_small_medium_large_subpurpose_destinations(&input.mode);
@@ -68,6 +206,32 @@ async fn floodfill_endpoint(input: web::Json<WalkCyclingCarUserInputJSON>) -> St
     let car_nodes_is_closest_to_pt: TiVec<NodeID, bool> = TiVec::from(car_nodes_is_closest_to_pt);
 
     let graph: TiVec<NodeID, NodeWalkCyclingCar> = TiVec::from(graph);
+    if (optimise_on_distance || input.return_other_cost == 1) && !graph.iter().all(|node| node.has_distances()) {
+        return Err(format!(
+            "The {} graph for file suffix {} has no edge distances: reserialise it with distances to use time_or_distance of 'distance' or return_other_cost",
+            input.mode, file_suffix
+        ));
+    }
+
+    // Only car travel times vary by time of day
+    let link_cost_profiles = if input.time_dependent == 1 {
+        if input.mode != "car" {
+            return Err(format!("time_dependent is only available for car, not {}", input.mode));
+        }
+        let link_cost_profiles = read_link_cost_profiles(&input.mode);
+        if link_cost_profiles.periods_count != data.time_periods.periods_count() {
+            return Err(format!(
+                "{}_link_cost_profiles has {} time periods but there are {}: reserialise it",
+                input.mode, link_cost_profiles.periods_count, data.time_periods.periods_count()
+            ));
+        }
+        if !link_cost_profiles.covers_graph(graph.as_ref()) {
+            return Err(format!("{}_link_cost_profiles doesn't cover every link in the graph: reserialise it", input.mode));
+        }
+        Some(link_cost_profiles)
+    } else {
+        None
+    };
     let mut node_values_2d: TiVec<NodeID, Vec<SubpurposeScore>> = TiVec::from(node_values_2d);
 
     // If any destinations are to be removed prior to running floodfill
@@ -89,23 +253,15 @@ async fn floodfill_endpoint(input: web::Json<WalkCyclingCarUserInputJSON>) -> St
         }
     }
 
-    // Extract costs of turning, in order of: straight, right turn, u-turn, left turn
-    let time_costs_turn: [Cost; 4];
-    if input.mode == "cycling" {
-        time_costs_turn = [Cost(0), Cost(15), Cost(15), Cost(5)];
-
-    // If using the distance driving graph (ie, each edge is in terms of distance in metres, divided by 20, rather than time to cross the edge in seconds)
-    } else if input.time_or_distance == "distance" && input.mode == "car" {
-        time_costs_turn = [Cost(0), Cost(0), Cost(0), Cost(0)];
-    } else if input.mode == "car" {
-        time_costs_turn = [Cost(0), Cost(15), Cost(17), Cost(9)];
-
-    // walking turn costs
-    } else {
-        time_costs_turn = [Cost(0), Cost(0), Cost(0), Cost(0)];
-    }
-
-    let now = Instant::now();
+    // Turn costs add to seconds only: when optimising on distance, turns add no distance
+    let turn_cost_profile = match &input.turn_cost_profile {
+        Some(turn_cost_profile) => {
+            turn_cost_profile.validate()?;
+            turn_cost_profile.clone()
+        }
+        None => data.turn_cost_profiles.get(&input.mode),
+    };
+    let major_roads = read_major_roads_if_needed(&input.mode, &turn_cost_profile, &graph)?;
     
     
     let mut original_time_intervals_to_store_destination_counts = Vec::new();
@@ -113,45 +269,463 @@ async fn floodfill_endpoint(input: web::Json<WalkCyclingCarUserInputJSON>) -> St
     original_time_intervals_to_store_destination_counts.push(Cost(1500));
     
 
-    let indices = (0..input.start_nodes_user_input.len()).collect::<Vec<_>>();
+    // When zones are requested, run from every start node of every zone
+    let mut zone_start_nodes = Vec::new();
+    let mut start_nodes = input.start_nodes_user_input.clone();
+    let mut init_travel_times = input.init_travel_times_user_input.clone();
+    if !input.zone_ids.is_empty() {
+        let zone_definitions = match data
+            .zone_definitions
+            .get(&input.mode)
+            .and_then(|zone_definitions_by_type| zone_definitions_by_type.get(&input.zone_type))
+        {
+            Some(zone_definitions) => zone_definitions,
+            None => return Err(format!("Unknown zone_type {} for mode {}", input.zone_type, input.mode)),
+        };
+        zone_start_nodes = expand_zone_start_nodes(&input.zone_ids, zone_definitions)?;
+        start_nodes = zone_start_nodes.iter().map(|(_, start_node)| start_node.node).collect();
+        init_travel_times = zone_start_nodes
+            .iter()
+            .map(|(_, start_node)| start_node.seconds_walk_to_start_node)
+            .collect();
+    }
+
+    // Competition and OD matrices need the seconds to each OD pair: when optimising on distance, these are the other cost
+    let return_other_cost = input.return_other_cost == 1
+        || (optimise_on_distance && (input.competition.is_some() || input.od_matrix.is_some()));
+
+    Ok(PreparedFloodfills {
+        data,
+        input,
+        file_suffix,
+        travel_time_relationships,
+        node_values_2d,
+        graph,
+        turn_cost_profile,
+        major_roads,
+        link_cost_profiles,
+        small_medium_large_subpurpose_destinations,
+        car_nodes_is_closest_to_pt,
+        zone_start_nodes,
+        start_nodes,
+        init_travel_times,
+        track_pt_nodes_reached,
+        count_destinations_at_intervals,
+        optimise_on_distance,
+        return_other_cost,
+    })
+}
+
+#[post("/floodfill_endpoint/")]
+async fn floodfill_endpoint(
+    data: web::Data<AppState>,
+    input: web::Json<WalkCyclingCarUserInputJSON>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let format = ResponseFormat::from_request(&request);
+    match floodfill_endpoint_response(data, input.into_inner(), format) {
+        Ok(response) => response,
+        Err(message) => text_response(message),
+    }
+}
+
+// The /floodfill_endpoint/ response, also used for offline runs. Errors are the message to send back
+fn floodfill_endpoint_response(
+    data: web::Data<AppState>,
+    input: WalkCyclingCarUserInputJSON,
+    format: ResponseFormat,
+) -> Result<HttpResponse, String> {
+    let prepared = prepare_floodfills(data.clone(), input)?;
+    let PreparedFloodfills {
+        input,
+        file_suffix,
+        travel_time_relationships,
+        node_values_2d,
+        graph,
+        turn_cost_profile,
+        zone_start_nodes,
+        start_nodes,
+        ..
+    } = &prepared;
+    let track_pt_nodes_reached = prepared.track_pt_nodes_reached;
+    let optimise_on_distance = prepared.optimise_on_distance;
+
+    let now = Instant::now();
+
+    // Contraction hierarchies hold seconds on fixed link travel times for one turn cost profile: other
+    // OD matrices are found with floodfills below
+    if let Some(od_matrix_request) = &input.od_matrix {
+        if !input.zone_ids.is_empty() || input.competition.is_some() {
+            return Err("od_matrix can't be combined with zone_ids or competition".to_string());
+        }
+        let contraction_hierarchy = (!optimise_on_distance && input.time_dependent == 0 && !track_pt_nodes_reached)
+            .then(|| read_contraction_hierarchy(&input.mode, file_suffix))
+            .flatten();
+        if let Some(contraction_hierarchy) = contraction_hierarchy {
+            if contraction_hierarchy.turn_cost_profile == *turn_cost_profile && contraction_hierarchy.nodes_count == graph.len() {
+                let mut matrix = contraction_hierarchy.many_to_many(
+                    &input.start_nodes_user_input,
+                    &input.destination_nodes,
+                    input.max_travel_time_seconds,
+                );
+                // Like the floodfill, costs start from the seconds to the start node and stop short of the time limit
+                for (row, init_travel_time) in matrix.iter_mut().zip(input.init_travel_times_user_input.iter()) {
+                    for cost in row.iter_mut() {
+                        *cost = cost
+                            .map(|cost| cost + *init_travel_time)
+                            .filter(|cost| *cost < input.max_travel_time_seconds);
+                    }
+                }
+                println!("OD matrix found from contraction hierarchy in {:?}", now.elapsed());
+                return Ok(formatted_response(
+                    &OdMatrixOutput::new(
+                        &input.start_nodes_user_input,
+                        &input.destination_nodes,
+                        matrix,
+                        od_matrix_request.format,
+                    ),
+                    format,
+                    od_matrix_record_batch,
+                ));
+            }
+            println!("Contraction hierarchy was built for another turn cost profile or graph: using floodfills");
+        }
+    }
+
+    let indices = (0..start_nodes.len()).collect::<Vec<_>>();
 
-    let results: Vec<FloodfillOutputOriginDestinationPairWalkCyclingCar> = indices
+    let od_pairs_seconds = |result: &FloodfillOutputOriginDestinationPairWalkCyclingCar| -> Vec<[usize; 2]> {
+        if optimise_on_distance {
+            result.od_pairs_found_other_cost.clone().unwrap()
+        } else {
+            result.od_pairs_found.clone()
+        }
+    };
+
+    let run_floodfill = |i: usize, destination_nodes: &Vec<NodeID>| prepared.run_floodfill(i, destination_nodes);
+
+    // For competition adjusted scores, the first pass finds the demand at every destination. Both passes
+    // need OD pairs to every node with opportunities, on top of any destination_nodes requested
+    let mut destination_nodes = input.destination_nodes.clone();
+    let mut demand = None;
+    if let Some(competition) = &input.competition {
+        let populations = populations_by_start_node(
+            &competition.populations,
+            start_nodes.len(),
+            &input.zone_ids,
+            zone_start_nodes,
+        )?;
+        let opportunity_nodes = nodes_with_opportunities(node_values_2d);
+        demand = Some(
+            indices
+                .par_iter()
+                .map(|i| {
+                    let result = run_floodfill(*i, &opportunity_nodes);
+                    demand_from_one_origin(
+                        &od_pairs_seconds(&result),
+                        populations[*i],
+                        travel_time_relationships,
+                        data.purpose_taxonomy.purposes_count(),
+                    )
+                })
+                .reduce(HashMap::new, merge_demand),
+        );
+        destination_nodes.extend(opportunity_nodes);
+        println!("Competition demand found in {:?}", now.elapsed());
+    }
+
+    let mut results: Vec<FloodfillOutputOriginDestinationPairWalkCyclingCar> = indices
         .par_iter()
-        .map(|i| {
-            floodfill_walk_cycling_car(
-                &travel_time_relationships,
-                &node_values_2d,
-                &graph,
-                &time_costs_turn,
-                *&input.start_nodes_user_input[*i],
-                *&input.init_travel_times_user_input[*i],
-                &input.destination_nodes,
-                Cost(3600),
-                &input.mode,
-                track_pt_nodes_reached,
-                *&input.seconds_reclaimed_when_pt_stop_reached,
-                *&input.target_node,
-                &car_nodes_is_closest_to_pt,
-                &small_medium_large_subpurpose_destinations,
-                count_destinations_at_intervals,
-                &input.original_time_intervals_to_store_destination_counts,
-            )
-        })
+        .map(|i| run_floodfill(*i, &destination_nodes))
         .collect();
 
+    if let Some(od_matrix_request) = &input.od_matrix {
+        let od_pairs_seconds_by_origin: Vec<Vec<[usize; 2]>> = results.iter().map(od_pairs_seconds).collect();
+        let matrix = od_matrix_from_od_pairs(od_pairs_seconds_by_origin.iter(), &destination_nodes);
+        println!("OD matrix found in {:?}", now.elapsed());
+        return Ok(formatted_response(
+            &OdMatrixOutput::new(start_nodes, &destination_nodes, matrix, od_matrix_request.format),
+            format,
+            od_matrix_record_batch,
+        ));
+    }
+
+    if let Some(demand) = demand {
+        let requested_destination_nodes: HashSet<usize> =
+            input.destination_nodes.iter().map(|node| node.0).collect();
+        results.par_iter_mut().for_each(|result| {
+            result.competition_adjusted_scores = Some(calculate_competition_adjusted_scores(
+                &od_pairs_seconds(result),
+                &demand,
+                node_values_2d,
+                travel_time_relationships,
+                &data.purpose_taxonomy,
+            ));
+            result
+                .od_pairs_found
+                .retain(|[_, node]| requested_destination_nodes.contains(node));
+            if input.return_other_cost == 1 {
+                if let Some(od_pairs_found_other_cost) = result.od_pairs_found_other_cost.as_mut() {
+                    od_pairs_found_other_cost.retain(|[_, node]| requested_destination_nodes.contains(node));
+                }
+            } else {
+                result.od_pairs_found_other_cost = None;
+            }
+        });
+    }
+
     println!("Getting destinations and scores took {:?}", now.elapsed());
-    serde_json::to_string(&results).unwrap()
+
+    if !input.zone_ids.is_empty() {
+        let purpose_scores: Vec<_> = results.iter().map(|result| result.purpose_scores.clone()).collect();
+        let mut zone_scores = aggregate_purpose_scores_by_zone(
+            &input.zone_ids,
+            zone_start_nodes,
+            &purpose_scores,
+            data.purpose_taxonomy.purposes_count(),
+        );
+        if input.competition.is_some() {
+            let competition_purpose_scores: Vec<_> = results
+                .iter()
+                .map(|result| result.competition_adjusted_scores.as_ref().unwrap().purpose_scores.clone())
+                .collect();
+            let competition_zone_scores = aggregate_purpose_scores_by_zone(
+                &input.zone_ids,
+                zone_start_nodes,
+                &competition_purpose_scores,
+                data.purpose_taxonomy.purposes_count(),
+            );
+            for (zone, competition_zone) in zone_scores.iter_mut().zip(competition_zone_scores) {
+                zone.competition_adjusted_purpose_scores = Some(competition_zone.purpose_scores);
+            }
+        }
+        // Columnar formats name scores by purpose whatever named_output is
+        if input.named_output == 1 && !format.is_columnar() {
+            let named_zone_scores: Vec<_> = zone_scores
+                .iter()
+                .map(|zone| add_names_to_output(zone, &data.purpose_taxonomy, &zone.purpose_scores, &[]))
+                .collect();
+            return Ok(text_response(serde_json::to_string(&named_zone_scores).unwrap()));
+        }
+        return Ok(formatted_response(&zone_scores, format, |zone_scores| {
+            zone_scores_record_batch(zone_scores, &data.purpose_taxonomy.purposes)
+        }));
+    }
+    if input.named_output == 1 && !format.is_columnar() {
+        let named_results: Vec<_> = results
+            .iter()
+            .map(|result| {
+                add_names_to_output(
+                    result,
+                    &data.purpose_taxonomy,
+                    &result.purpose_scores,
+                    &result.destinations_reached_at_time_intervals,
+                )
+            })
+            .collect();
+        return Ok(text_response(serde_json::to_string(&named_results).unwrap()));
+    }
+    Ok(formatted_response(&results, format, |results| {
+        let rows: Vec<FloodfillResultRow> = results.iter().map(FloodfillResultRow::from).collect();
+        floodfill_results_record_batch(&rows, &data.purpose_taxonomy.purposes)
+    }))
+}
+
+// Same payload as /floodfill_endpoint/, but returns one JSON line per start node as its floodfill
+// finishes, rather than every result at the end. zone_ids, competition and od_matrix need every result
+// together, so aren't available here
+#[post("/floodfill_endpoint_ndjson/")]
+async fn floodfill_endpoint_ndjson(
+    data: web::Data<AppState>,
+    input: web::Json<WalkCyclingCarUserInputJSON>,
+) -> HttpResponse {
+    let prepared = match prepare_floodfills(data.clone(), input.into_inner()) {
+        Ok(prepared) => prepared,
+        Err(message) => return HttpResponse::Ok().body(message),
+    };
+    if !prepared.input.zone_ids.is_empty() || prepared.input.competition.is_some() || prepared.input.od_matrix.is_some() {
+        return HttpResponse::Ok().body("zone_ids, competition and od_matrix aren't available when streaming");
+    }
+
+    let (sender, body) = ndjson_channel();
+    std::thread::spawn(move || {
+        let now = Instant::now();
+        let sent_all = ndjson_thread_pool().install(|| {
+            (0..prepared.start_nodes.len()).into_par_iter().try_for_each(|i| {
+                let result = prepared.run_floodfill(i, &prepared.input.destination_nodes);
+                let sent = if prepared.input.named_output == 1 {
+                    sender.send(
+                        i,
+                        &add_names_to_output(
+                            &result,
+                            &prepared.data.purpose_taxonomy,
+                            &result.purpose_scores,
+                            &result.destinations_reached_at_time_intervals,
+                        ),
+                    )
+                } else {
+                    sender.send(i, &result)
+                };
+                if sent { Ok(()) } else { Err(()) }
+            })
+        });
+        if sent_all.is_err() {
+            println!("Client went away: stopped streaming floodfills");
+        }
+        println!("Streamed floodfills took {:?}", now.elapsed());
+    });
+    ndjson_response(body)
+}
+
+// Shortest path between two nodes, for route optimisation. Car graphs are picked by time period as in
+// the floodfill, but link travel times don't change as the route goes on
+#[post("/route/")]
+async fn route_endpoint(
+    data: web::Data<AppState>,
+    input: web::Json<RouteUserInputJSON>,
+) -> String {
+    let period_ix = match data.time_periods.period_index(&input.day_type, input.trip_start_seconds) {
+        Some(period_ix) => period_ix,
+        None => return format!("No time periods for day_type {}", input.day_type),
+    };
+    let file_suffix = match data.time_periods.periods[period_ix].file_suffixes.get(&input.mode) {
+        Some(file_suffix) => file_suffix.as_str(),
+        None => return format!("No files for mode {} in time period {}", input.mode, data.time_periods.periods[period_ix].name),
+    };
+    let optimise_on_distance = input.time_or_distance == "distance";
+
+    let graph: TiVec<NodeID, NodeWalkCyclingCar> = TiVec::from(read_graph_walk_cycling_car_for_period(&input.mode, file_suffix));
+    for node in [input.start_node, input.target_node] {
+        if node.0 >= graph.len() {
+            return format!("Node {} isn't in the {} graph", node.0, input.mode);
+        }
+    }
+    if optimise_on_distance && !graph.iter().all(|node| node.has_distances()) {
+        return format!(
+            "The {} graph for file suffix {} has no edge distances: reserialise it with distances to use time_or_distance of 'distance'",
+            input.mode, file_suffix
+        );
+    }
+
+    let turn_cost_profile = match &input.turn_cost_profile {
+        Some(turn_cost_profile) => {
+            if let Err(message) = turn_cost_profile.validate() {
+                return message;
+            }
+            turn_cost_profile.clone()
+        }
+        None => data.turn_cost_profiles.get(&input.mode),
+    };
+    let major_roads = match read_major_roads_if_needed(&input.mode, &turn_cost_profile, &graph) {
+        Ok(major_roads) => major_roads,
+        Err(message) => return message,
+    };
+
+    // Without coordinates the search falls back to Dijkstra, and there's no geometry
+    let node_longlat_lookup: Option<TiVec<NodeID, [f64; 2]>> =
+        read_node_longlat_lookup_walk_cycling_car(&input.mode).map(TiVec::from);
+    if let Some(node_longlat_lookup) = &node_longlat_lookup {
+        if node_longlat_lookup.len() != graph.len() {
+            return format!(
+                "{}_nodes_long_lat has {} nodes but the graph has {}: reserialise it",
+                input.mode, node_longlat_lookup.len(), graph.len()
+            );
+        }
+    }
+
+    let now = Instant::now();
+    let heuristic = node_longlat_lookup.as_ref().map(|node_longlat_lookup| {
+        let cost_per_metre = *data
+            .route_cost_per_metre
+            .lock()
+            .unwrap()
+            .entry((input.mode.clone(), file_suffix.to_string(), optimise_on_distance))
+            .or_insert_with(|| min_cost_per_metre(node_longlat_lookup, &graph, optimise_on_distance));
+        RouteHeuristic::new(node_longlat_lookup, cost_per_metre)
+    });
+    let route = find_route_walk_cycling_car(
+        &graph,
+        &mut SearchWorkspace::new(graph.len()),
+        &turn_cost_profile,
+        major_roads.as_ref(),
+        heuristic.as_ref(),
+        input.start_node,
+        input.target_node,
+        optimise_on_distance,
+    );
+    println!("Route found in {:?}", now.elapsed());
+
+    match route {
+        Some(mut route) => {
+            route.geometry = node_longlat_lookup
+                .map(|node_longlat_lookup| route.nodes.iter().map(|node| node_longlat_lookup[*node]).collect());
+            serde_json::to_string(&route).unwrap()
+        }
+        None => format!("No route from node {} to node {}", input.start_node.0, input.target_node.0),
+    }
+}
+
+fn run_command(data: web::Data<AppState>, args: &[String]) {
+    let run_request = |line: &str| {
+        let input: WalkCyclingCarUserInputJSON =
+            serde_json::from_str(line).map_err(|error| format!("Couldn't parse request: {}", error))?;
+        floodfill_endpoint_response(data.clone(), input, ResponseFormat::Json).map(response_body)
+    };
+    match args[0].as_str() {
+        "run_requests" => run_requests_command(&args[1..], run_request),
+        "national_run" => national_run_command(&args[1..], "start_nodes_user_input", "init_travel_times_user_input", run_request),
+        command => panic!("Unknown command {}: the commands are run_requests and national_run", command),
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let mut zone_definitions = HashMap::new();
+    let mut node_grid_indexes = HashMap::new();
+    for mode in ["walk", "cycling", "car"] {
+        let mut zone_definitions_by_type = HashMap::new();
+        for zone_type in ["LSOA", "OA"] {
+            zone_definitions_by_type.insert(zone_type.to_string(), read_zone_definitions(zone_type, mode));
+        }
+        zone_definitions.insert(mode.to_string(), zone_definitions_by_type);
+
+        if let Some(node_longlat_lookup) = read_node_longlat_lookup_walk_cycling_car(mode) {
+            let node_longlat_lookup: TiVec<NodeID, [f64; 2]> = TiVec::from(node_longlat_lookup);
+            let node_grid_index = NodeGridIndex::new(&node_longlat_lookup);
+            node_grid_indexes.insert(mode.to_string(), (node_longlat_lookup, node_grid_index));
+        }
+    }
+
+    let app_state = web::Data::new(AppState {
+        purpose_taxonomy: read_purpose_taxonomy(),
+        time_periods: read_time_periods(),
+        turn_cost_profiles: read_turn_cost_profiles(),
+        zone_definitions,
+        route_cost_per_metre: Mutex::new(HashMap::new()),
+        node_grid_indexes,
+    });
+
+    // `walk_cycling_car_batch run_requests <requests.jsonl> <output_dir> [requests_per_shard]` runs a file
+    // of /floodfill_endpoint/ requests without starting the server, for runs longer than HTTP timeouts allow.
+    // `walk_cycling_car_batch national_run <template_request.json> <output_dir> [origins_per_chunk]
+    // [max_attempts]` runs one request with very many origins in resumable chunks
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        run_command(app_state, &args);
+        return Ok(());
+    }
+
     // The 500MB warning is wrong, the decorator on line below silences it
     #[allow(deprecated)]
     HttpServer::new(move || {
         App::new()
+            .app_data(app_state.clone())
             .data(web::JsonConfig::default().limit(1024 * 1024 * 500)) // allow POST'd JSON payloads up to 500mb
             .service(index)
+            .service(nearest_node)
             .service(floodfill_endpoint)
+            .service(floodfill_endpoint_ndjson)
+            .service(route_endpoint)
     })
     .bind(("0.0.0.0", 7328))?
     .run()